@@ -0,0 +1,825 @@
+//! Directories of zstd compressed segments of captured shreds, indexed by slot
+//!
+//! Every segment file starts with an 8 byte header, magic `SCAR`, the version
+//! and 3 reserved bytes, followed by blocks, each a zstd frame of records, the
+//! index, a zstd frame too, and a 16 byte footer. All integers are little endian.
+//!
+//! A record:
+//!
+//! | offset | size | field                                                |
+//! |--------|------|------------------------------------------------------|
+//! | 0      | 8    | capture time, unix nanoseconds                       |
+//! | 8      | 1    | flags, bit 0 egress, bit 1 destination, bit 2 origin |
+//! | 9      | 16   | source address, IPv4 mapped to IPv6                  |
+//! | 25     | 2    | source port                                          |
+//! | 27     | 16   | destination address, zero if unknown                 |
+//! | 43     | 2    | destination port                                     |
+//! | 45     | 2    | shred length                                         |
+//! | 47     |      | origin host id and interface, if flagged             |
+//! |        |      | shred                                                |
+//!
+//! The origin is an 8 byte host id, a 1 byte interface name length and the name.
+//!
+//! The index lists every block as its offset (8), compressed length (4),
+//! number of records (4) and lowest and highest slot (8 each), prefixed with
+//! their count (4). Then it lists the slots the segment holds shreds of,
+//! prefixed with their count (4), each as the slot (8), the index of its last
+//! data shred (4, `u32::MAX` if not seen), and the indices (4 each) of its data
+//! and of its code shreds, each prefixed with their count (4).
+//!
+//! The footer is the offset (8) and length (4) of the index and the magic again.
+//! Segments still being written have no footer yet and are skipped by readers.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    net::{Ipv6Addr, SocketAddr},
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
+};
+
+pub use crate::shred::ShredKind;
+use crate::{
+    envelope::Direction,
+    net::ipv6_mapped,
+    shred::{self, LAST_SHRED_IN_SLOT},
+};
+
+pub const MAGIC: [u8; 4] = *b"SCAR";
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 8;
+pub const FOOTER_LEN: usize = 16;
+/// Segment files are named `<unix time in ns>.<EXTENSION>`
+pub const EXTENSION: &str = "scar";
+/// Records are compressed together until they take this many bytes
+pub const BLOCK_LEN: usize = 1 << 20;
+
+const RECORD_HEADER_LEN: usize = 47;
+const FLAG_EGRESS: u8 = 1;
+const FLAG_DST: u8 = 2;
+const FLAG_ORIGIN: u8 = 4;
+const NO_LAST_INDEX: u32 = u32::MAX;
+
+/// What the index knows about a shred
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ShredKey {
+    pub slot: u64,
+    pub index: u32,
+    pub kind: ShredKind,
+    /// Set for the data shred that ends its slot
+    pub last_in_slot: bool,
+}
+
+impl ShredKey {
+    /// Reads the key from the headers of a merkle shred
+    pub fn parse(shred: &[u8]) -> Option<Self> {
+        let kind = shred::merkle_kind(shred)?;
+        let last_in_slot = kind == ShredKind::Data
+            && shred::data_flags(shred)? & LAST_SHRED_IN_SLOT == LAST_SHRED_IN_SLOT;
+        Some(ShredKey {
+            slot: shred::slot(shred)?,
+            index: shred::index(shred)?,
+            kind,
+            last_in_slot,
+        })
+    }
+}
+
+/// The host and interface an upstream shredcaster captured a shred on
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Origin {
+    pub host_id: u64,
+    pub iface: String,
+}
+
+/// A shred and its capture metadata
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub capture_ts_ns: u64,
+    pub direction: Direction,
+    pub src: SocketAddr,
+    pub dst: Option<SocketAddr>,
+    /// `None` if the archiving shredcaster captured it
+    pub origin: Option<Origin>,
+    pub shred: Vec<u8>,
+}
+
+impl Record {
+    /// The key of the shred, archived records always have one
+    pub fn key(&self) -> Option<ShredKey> {
+        ShredKey::parse(&self.shred)
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        let mut flags = 0;
+        if self.direction == Direction::Egress {
+            flags |= FLAG_EGRESS;
+        }
+        if self.dst.is_some() {
+            flags |= FLAG_DST;
+        }
+        if self.origin.is_some() {
+            flags |= FLAG_ORIGIN;
+        }
+        out.extend_from_slice(&self.capture_ts_ns.to_le_bytes());
+        out.push(flags);
+        for addr in [Some(self.src), self.dst] {
+            let (ip, port) = addr.map_or((Ipv6Addr::UNSPECIFIED, 0), |addr| {
+                (ipv6_mapped(addr.ip()), addr.port())
+            });
+            out.extend_from_slice(&ip.octets());
+            out.extend_from_slice(&port.to_le_bytes());
+        }
+        out.extend_from_slice(&(self.shred.len() as u16).to_le_bytes());
+        if let Some(origin) = &self.origin {
+            let iface = &origin.iface.as_bytes()[..origin.iface.len().min(u8::MAX.into())];
+            out.extend_from_slice(&origin.host_id.to_le_bytes());
+            out.push(iface.len() as u8);
+            out.extend_from_slice(iface);
+        }
+        out.extend_from_slice(&self.shred);
+    }
+
+    /// Decodes the record at the start of `buf`, returning it and its length
+    fn decode(buf: &[u8]) -> io::Result<(Record, usize)> {
+        let header = buf.get(..RECORD_HEADER_LEN).ok_or_else(truncated)?;
+        let flags = header[8];
+        let addr = |at: usize| {
+            SocketAddr::new(
+                Ipv6Addr::from(read::<16>(header, at)).to_canonical(),
+                u16::from_le_bytes(read(header, at + 16)),
+            )
+        };
+        let shred_len = usize::from(u16::from_le_bytes(read(header, 45)));
+        let mut len = RECORD_HEADER_LEN;
+        let origin = if flags & FLAG_ORIGIN != 0 {
+            let host_id = buf.get(len..len + 8).ok_or_else(truncated)?;
+            let iface_len = usize::from(*buf.get(len + 8).ok_or_else(truncated)?);
+            let iface = buf
+                .get(len + 9..len + 9 + iface_len)
+                .ok_or_else(truncated)?;
+            len += 9 + iface_len;
+            Some(Origin {
+                host_id: u64::from_le_bytes(host_id.try_into().unwrap()),
+                iface: String::from_utf8_lossy(iface).into_owned(),
+            })
+        } else {
+            None
+        };
+        let shred = buf.get(len..len + shred_len).ok_or_else(truncated)?;
+        let record = Record {
+            capture_ts_ns: u64::from_le_bytes(read(header, 0)),
+            direction: if flags & FLAG_EGRESS != 0 {
+                Direction::Egress
+            } else {
+                Direction::Ingress
+            },
+            src: addr(9),
+            dst: (flags & FLAG_DST != 0).then(|| addr(27)),
+            origin,
+            shred: shred.to_vec(),
+        };
+        Ok((record, len + shred_len))
+    }
+}
+
+/// Which shreds of a slot were archived
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SlotShreds {
+    pub data: BTreeSet<u32>,
+    pub code: BTreeSet<u32>,
+    /// The index of the data shred ending the slot, if it was archived
+    pub last_index: Option<u32>,
+}
+
+impl SlotShreds {
+    fn insert(&mut self, key: &ShredKey) {
+        match key.kind {
+            ShredKind::Data => self.data.insert(key.index),
+            ShredKind::Code => self.code.insert(key.index),
+        };
+        if key.last_in_slot {
+            self.last_index = Some(key.index);
+        }
+    }
+
+    fn merge(&mut self, other: &SlotShreds) {
+        self.data.extend(other.data.iter().copied());
+        self.code.extend(other.code.iter().copied());
+        self.last_index = self.last_index.or(other.last_index);
+    }
+
+    /// Whether every data shred up to the last one of the slot was archived
+    pub fn is_complete(&self) -> bool {
+        self.last_index
+            .is_some_and(|last| self.data.len() as u64 == u64::from(last) + 1)
+    }
+
+    /// The data shreds missing before the highest one archived, or the last one if known
+    pub fn missing_data(&self) -> Vec<u32> {
+        let Some(end) = self.last_index.or(self.data.last().copied()) else {
+            return Vec::new();
+        };
+        (0..=end)
+            .filter(|index| !self.data.contains(index))
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct BlockIndex {
+    offset: u64,
+    len: u32,
+    records: u32,
+    min_slot: u64,
+    max_slot: u64,
+}
+
+impl BlockIndex {
+    fn overlaps(&self, slots: &(Bound<u64>, Bound<u64>)) -> bool {
+        let above_start = match slots.0 {
+            Bound::Included(start) => self.max_slot >= start,
+            Bound::Excluded(start) => self.max_slot > start,
+            Bound::Unbounded => true,
+        };
+        let below_end = match slots.1 {
+            Bound::Included(end) => self.min_slot <= end,
+            Bound::Excluded(end) => self.min_slot < end,
+            Bound::Unbounded => true,
+        };
+        above_start && below_end
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct SegmentIndex {
+    blocks: Vec<BlockIndex>,
+    slots: BTreeMap<u64, SlotShreds>,
+}
+
+impl SegmentIndex {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.blocks.len() as u32).to_le_bytes());
+        for block in self.blocks.iter() {
+            out.extend_from_slice(&block.offset.to_le_bytes());
+            out.extend_from_slice(&block.len.to_le_bytes());
+            out.extend_from_slice(&block.records.to_le_bytes());
+            out.extend_from_slice(&block.min_slot.to_le_bytes());
+            out.extend_from_slice(&block.max_slot.to_le_bytes());
+        }
+        out.extend_from_slice(&(self.slots.len() as u32).to_le_bytes());
+        for (slot, shreds) in self.slots.iter() {
+            out.extend_from_slice(&slot.to_le_bytes());
+            out.extend_from_slice(&shreds.last_index.unwrap_or(NO_LAST_INDEX).to_le_bytes());
+            for indices in [&shreds.data, &shreds.code] {
+                out.extend_from_slice(&(indices.len() as u32).to_le_bytes());
+                for index in indices {
+                    out.extend_from_slice(&index.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    fn decode(mut buf: &[u8]) -> io::Result<Self> {
+        let mut take = |len: usize| -> io::Result<&[u8]> {
+            if buf.len() < len {
+                return Err(truncated());
+            }
+            let (head, rest) = buf.split_at(len);
+            buf = rest;
+            Ok(head)
+        };
+        let mut index = SegmentIndex::default();
+        let blocks = u32::from_le_bytes(read(take(4)?, 0));
+        for _ in 0..blocks {
+            let block = take(32)?;
+            index.blocks.push(BlockIndex {
+                offset: u64::from_le_bytes(read(block, 0)),
+                len: u32::from_le_bytes(read(block, 8)),
+                records: u32::from_le_bytes(read(block, 12)),
+                min_slot: u64::from_le_bytes(read(block, 16)),
+                max_slot: u64::from_le_bytes(read(block, 24)),
+            });
+        }
+        let slots = u32::from_le_bytes(read(take(4)?, 0));
+        for _ in 0..slots {
+            let slot = take(12)?;
+            let last_index = u32::from_le_bytes(read(slot, 8));
+            let mut shreds = SlotShreds {
+                last_index: (last_index != NO_LAST_INDEX).then_some(last_index),
+                ..SlotShreds::default()
+            };
+            for indices in [&mut shreds.data, &mut shreds.code] {
+                let count = u32::from_le_bytes(read(take(4)?, 0)) as usize;
+                let bytes = take(count.checked_mul(4).ok_or_else(truncated)?)?;
+                indices.extend(
+                    bytes
+                        .chunks_exact(4)
+                        .map(|index| u32::from_le_bytes(read(index, 0))),
+                );
+            }
+            index
+                .slots
+                .insert(u64::from_le_bytes(read(slot, 0)), shreds);
+        }
+        Ok(index)
+    }
+}
+
+/// Writes one segment, which readers only see once it is finished
+pub struct SegmentWriter {
+    file: BufWriter<File>,
+    level: i32,
+    /// Bytes written to the file so far
+    len: u64,
+    block: Vec<u8>,
+    block_index: Option<BlockIndex>,
+    index: SegmentIndex,
+}
+
+impl SegmentWriter {
+    /// Creates the segment at `path`, compressing with zstd `level`
+    pub fn create(path: impl AsRef<Path>, level: i32) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create_new(path)?);
+        file.write_all(&MAGIC)?;
+        file.write_all(&[VERSION, 0, 0, 0])?;
+        Ok(Self {
+            file,
+            level,
+            len: HEADER_LEN as u64,
+            block: Vec::with_capacity(BLOCK_LEN),
+            block_index: None,
+            index: SegmentIndex::default(),
+        })
+    }
+
+    /// Adds `record`, returning false if it doesn't hold a shred and was skipped
+    pub fn append(&mut self, record: &Record) -> io::Result<bool> {
+        let Some(key) = record.key() else {
+            return Ok(false);
+        };
+        record.encode(&mut self.block);
+        let block = self.block_index.get_or_insert(BlockIndex {
+            offset: self.len,
+            len: 0,
+            records: 0,
+            min_slot: key.slot,
+            max_slot: key.slot,
+        });
+        block.records += 1;
+        block.min_slot = block.min_slot.min(key.slot);
+        block.max_slot = block.max_slot.max(key.slot);
+        self.index.slots.entry(key.slot).or_default().insert(&key);
+        if self.block.len() >= BLOCK_LEN {
+            self.finish_block()?;
+        }
+        Ok(true)
+    }
+
+    /// Roughly how large the segment is, counting records not yet compressed
+    pub fn len(&self) -> u64 {
+        self.len + self.block.len() as u64
+    }
+
+    /// Whether no shred was appended yet
+    pub fn is_empty(&self) -> bool {
+        self.index.slots.is_empty()
+    }
+
+    fn finish_block(&mut self) -> io::Result<()> {
+        let Some(mut block) = self.block_index.take() else {
+            return Ok(());
+        };
+        let compressed = zstd::bulk::compress(&self.block, self.level)?;
+        self.file.write_all(&compressed)?;
+        block.len = compressed.len() as u32;
+        self.len += compressed.len() as u64;
+        self.index.blocks.push(block);
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Writes the last block and the index, which makes the segment readable
+    pub fn finish(mut self) -> io::Result<()> {
+        self.finish_block()?;
+        let mut index = Vec::new();
+        self.index.encode(&mut index);
+        let compressed = zstd::bulk::compress(&index, self.level)?;
+        self.file.write_all(&compressed)?;
+        self.file.write_all(&self.len.to_le_bytes())?;
+        self.file
+            .write_all(&(compressed.len() as u32).to_le_bytes())?;
+        self.file.write_all(&MAGIC)?;
+        self.file.flush()?;
+        self.file.get_ref().sync_data()
+    }
+}
+
+/// A finished segment of an archive
+pub struct Segment {
+    pub path: PathBuf,
+    index: SegmentIndex,
+}
+
+impl Segment {
+    /// Reads the index of the segment at `path`, `None` while it is being written
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Option<Self>> {
+        let path = path.as_ref();
+        let mut file = File::open(path)?;
+        if file.metadata()?.len() < (HEADER_LEN + FOOTER_LEN) as u64 {
+            return Ok(None);
+        }
+        let mut header = [0; HEADER_LEN];
+        file.read_exact(&mut header)?;
+        if header[..4] != MAGIC {
+            return Err(invalid("not an archive segment"));
+        }
+        if header[4] != VERSION {
+            return Err(invalid("unsupported archive segment version"));
+        }
+        let mut footer = [0; FOOTER_LEN];
+        file.seek(SeekFrom::End(-(FOOTER_LEN as i64)))?;
+        file.read_exact(&mut footer)?;
+        if footer[12..] != MAGIC {
+            return Ok(None);
+        }
+        let offset = u64::from_le_bytes(read(&footer, 0));
+        let len = u32::from_le_bytes(read(&footer, 8));
+        let mut compressed = vec![0; len as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut compressed)?;
+        Ok(Some(Self {
+            path: path.to_owned(),
+            index: SegmentIndex::decode(&zstd::stream::decode_all(compressed.as_slice())?)?,
+        }))
+    }
+
+    /// Which shreds of every slot the segment holds
+    pub fn slots(&self) -> &BTreeMap<u64, SlotShreds> {
+        &self.index.slots
+    }
+
+    /// How many blocks of records the segment holds
+    pub fn blocks(&self) -> usize {
+        self.index.blocks.len()
+    }
+
+    /// The records of block `block`, in the order they were captured
+    pub fn read_block(&self, block: usize) -> io::Result<Vec<Record>> {
+        let block = self
+            .index
+            .blocks
+            .get(block)
+            .ok_or_else(|| invalid("no such archive block"))?;
+        let mut file = File::open(&self.path)?;
+        let mut compressed = vec![0; block.len as usize];
+        file.seek(SeekFrom::Start(block.offset))?;
+        file.read_exact(&mut compressed)?;
+        let decoded = zstd::stream::decode_all(compressed.as_slice())?;
+        let mut records = Vec::with_capacity(block.records as usize);
+        let mut buf = decoded.as_slice();
+        while !buf.is_empty() {
+            let (record, len) = Record::decode(buf)?;
+            records.push(record);
+            buf = &buf[len..];
+        }
+        Ok(records)
+    }
+
+    /// How many shreds the segment holds
+    pub fn records(&self) -> usize {
+        self.index
+            .blocks
+            .iter()
+            .map(|block| block.records as usize)
+            .sum()
+    }
+}
+
+/// Paths of the segment files in `dir`, oldest first
+pub fn segment_paths(dir: impl AsRef<Path>) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(ts) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix(EXTENSION))
+            .and_then(|name| name.strip_suffix('.'))
+            .and_then(|ts| ts.parse::<u64>().ok())
+        else {
+            continue;
+        };
+        paths.push((ts, path));
+    }
+    paths.sort_unstable();
+    Ok(paths.into_iter().map(|(_, path)| path).collect())
+}
+
+/// The finished segments of an archive directory
+pub struct Archive {
+    pub segments: Vec<Segment>,
+}
+
+impl Archive {
+    /// Reads the indexes of the segments in `dir`, skipping the one being written
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let mut segments = Vec::new();
+        for path in segment_paths(dir)? {
+            match Segment::open(&path) {
+                Ok(segment) => segments.extend(segment),
+                // deleted by retention since it was listed
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Self { segments })
+    }
+
+    /// Which shreds of the slots in `slots` were archived, across segments
+    pub fn completeness(&self, slots: impl RangeBounds<u64>) -> BTreeMap<u64, SlotShreds> {
+        let slots = (slots.start_bound().cloned(), slots.end_bound().cloned());
+        let mut merged = BTreeMap::<u64, SlotShreds>::new();
+        for segment in self.segments.iter() {
+            for (slot, shreds) in segment.index.slots.range(slots) {
+                merged.entry(*slot).or_default().merge(shreds);
+            }
+        }
+        merged
+    }
+
+    /// The records of the shreds of `slots`, in the order they were captured
+    pub fn records(&self, slots: impl RangeBounds<u64>) -> Records<'_> {
+        Records {
+            archive: self,
+            slots: (slots.start_bound().cloned(), slots.end_bound().cloned()),
+            segment: 0,
+            block: 0,
+            records: Vec::new().into_iter(),
+        }
+    }
+
+    /// Every archived shred of `slot`
+    pub fn slot(&self, slot: u64) -> io::Result<Vec<Record>> {
+        self.records(slot..=slot).collect()
+    }
+}
+
+/// Reads the records of a slot range block by block
+pub struct Records<'a> {
+    archive: &'a Archive,
+    slots: (Bound<u64>, Bound<u64>),
+    segment: usize,
+    block: usize,
+    records: std::vec::IntoIter<Record>,
+}
+
+impl Iterator for Records<'_> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.records.next() {
+                if record
+                    .key()
+                    .is_some_and(|key| self.slots.contains(&key.slot))
+                {
+                    return Some(Ok(record));
+                }
+                continue;
+            }
+            let segment = self.archive.segments.get(self.segment)?;
+            let Some(block) = segment.index.blocks.get(self.block) else {
+                self.segment += 1;
+                self.block = 0;
+                continue;
+            };
+            self.block += 1;
+            if !block.overlaps(&self.slots) {
+                continue;
+            }
+            match segment.read_block(self.block - 1) {
+                Ok(records) => self.records = records.into_iter(),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+fn read<const N: usize>(buf: &[u8], offset: usize) -> [u8; N] {
+    buf[offset..offset + N].try_into().unwrap()
+}
+
+fn invalid(error: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn truncated() -> io::Error {
+    invalid("archive record is truncated")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A merkle shred with just enough of its headers set
+    fn shred(slot: u64, index: u32, kind: ShredKind, last_in_slot: bool) -> Vec<u8> {
+        let mut shred = vec![0; 128];
+        shred[64] = match kind {
+            ShredKind::Data => 0x96,
+            ShredKind::Code => 0x46,
+        };
+        shred[65..73].copy_from_slice(&slot.to_le_bytes());
+        shred[73..77].copy_from_slice(&index.to_le_bytes());
+        if last_in_slot {
+            shred[85] = LAST_SHRED_IN_SLOT;
+        }
+        shred
+    }
+
+    fn record(ts: u64, shred: Vec<u8>) -> Record {
+        Record {
+            capture_ts_ns: ts,
+            direction: Direction::Ingress,
+            src: "10.0.0.1:8001".parse().unwrap(),
+            dst: None,
+            origin: None,
+            shred,
+        }
+    }
+
+    /// An empty directory of its own for every test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("shredcaster-proto-{name}-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write_segment(dir: &Path, name: u64, records: &[Record]) {
+        let path = dir.join(format!("{name}.{EXTENSION}"));
+        let mut segment = SegmentWriter::create(path, 3).unwrap();
+        for record in records {
+            assert!(segment.append(record).unwrap());
+        }
+        segment.finish().unwrap();
+    }
+
+    #[test]
+    fn parses_shred_keys() {
+        assert_eq!(
+            ShredKey::parse(&shred(7, 3, ShredKind::Data, true)),
+            Some(ShredKey {
+                slot: 7,
+                index: 3,
+                kind: ShredKind::Data,
+                last_in_slot: true,
+            })
+        );
+        let code = ShredKey::parse(&shred(7, 3, ShredKind::Code, true)).unwrap();
+        assert_eq!(code.kind, ShredKind::Code);
+        assert!(!code.last_in_slot);
+        assert_eq!(
+            ShredKey::parse(&shred(7, 3, ShredKind::Data, false)[..80]),
+            None
+        );
+        assert_eq!(ShredKey::parse(&[0; 128]), None);
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let records = [
+            record(1, shred(1, 0, ShredKind::Data, false)),
+            Record {
+                capture_ts_ns: u64::MAX,
+                direction: Direction::Egress,
+                src: "[2001:db8::1]:8001".parse().unwrap(),
+                dst: Some("10.0.0.2:8002".parse().unwrap()),
+                origin: Some(Origin {
+                    host_id: 42,
+                    iface: "eth0".to_owned(),
+                }),
+                shred: shred(2, 5, ShredKind::Code, false),
+            },
+        ];
+        let mut buf = Vec::new();
+        for record in records.iter() {
+            record.encode(&mut buf);
+        }
+        let mut rest = buf.as_slice();
+        for record in records.iter() {
+            let (decoded, len) = Record::decode(rest).unwrap();
+            assert_eq!(&decoded, record);
+            rest = &rest[len..];
+        }
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn rejects_truncated_records() {
+        let mut record = record(1, shred(1, 0, ShredKind::Data, false));
+        record.origin = Some(Origin {
+            host_id: 42,
+            iface: "eth0".to_owned(),
+        });
+        let mut buf = Vec::new();
+        record.encode(&mut buf);
+        for len in 0..buf.len() {
+            assert!(Record::decode(&buf[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn index_round_trips() {
+        let mut index = SegmentIndex::default();
+        index.blocks.push(BlockIndex {
+            offset: 8,
+            len: 100,
+            records: 3,
+            min_slot: 5,
+            max_slot: 6,
+        });
+        for key in [
+            ShredKey::parse(&shred(5, 0, ShredKind::Data, false)).unwrap(),
+            ShredKey::parse(&shred(5, 1, ShredKind::Data, true)).unwrap(),
+            ShredKey::parse(&shred(6, 9, ShredKind::Code, false)).unwrap(),
+        ] {
+            index.slots.entry(key.slot).or_default().insert(&key);
+        }
+        let mut buf = Vec::new();
+        index.encode(&mut buf);
+        assert_eq!(SegmentIndex::decode(&buf).unwrap(), index);
+
+        for len in 0..buf.len() {
+            assert!(SegmentIndex::decode(&buf[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn archive_round_trips() {
+        let dir = test_dir("archive-round-trip");
+        let first = [
+            record(1, shred(10, 0, ShredKind::Data, false)),
+            record(2, shred(10, 0, ShredKind::Code, false)),
+            record(3, shred(11, 0, ShredKind::Data, false)),
+        ];
+        let second = [
+            record(4, shred(10, 1, ShredKind::Data, true)),
+            record(5, shred(11, 2, ShredKind::Data, false)),
+        ];
+        write_segment(&dir, 1, &first);
+        write_segment(&dir, 2, &second);
+        // still being written, readers skip it
+        let mut partial = SegmentWriter::create(dir.join(format!("3.{EXTENSION}")), 3).unwrap();
+        partial
+            .append(&record(6, shred(10, 2, ShredKind::Data, false)))
+            .unwrap();
+
+        let archive = Archive::open(&dir).unwrap();
+        assert_eq!(archive.segments.len(), 2);
+        assert_eq!(archive.segments[0].records(), 3);
+
+        let all: Vec<_> = archive.records(..).map(Result::unwrap).collect();
+        assert_eq!(all, [first.as_slice(), second.as_slice()].concat());
+        let slot = archive.slot(10).unwrap();
+        assert_eq!(
+            slot,
+            [first[0].clone(), first[1].clone(), second[0].clone()]
+        );
+        assert!(archive.records(12..).next().is_none());
+
+        let completeness = archive.completeness(..);
+        let ten = &completeness[&10];
+        assert_eq!(ten.data, BTreeSet::from([0, 1]));
+        assert_eq!(ten.code, BTreeSet::from([0]));
+        assert_eq!(ten.last_index, Some(1));
+        assert!(ten.is_complete());
+        let eleven = &completeness[&11];
+        assert!(!eleven.is_complete());
+        assert_eq!(eleven.missing_data(), [1]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skips_records_without_shreds() {
+        let dir = test_dir("archive-skip");
+        let mut segment = SegmentWriter::create(dir.join(format!("1.{EXTENSION}")), 3).unwrap();
+        assert!(!segment.append(&record(1, vec![0; 128])).unwrap());
+        assert!(segment.is_empty());
+        drop(segment);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_files_that_are_no_segments() {
+        let dir = test_dir("archive-invalid");
+        let path = dir.join(format!("1.{EXTENSION}"));
+        std::fs::write(&path, [0; HEADER_LEN + FOOTER_LEN]).unwrap();
+        assert!(Segment::open(&path).is_err());
+        assert!(Archive::open(&dir).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+//! Several packets packed into one datagram
+//!
+//! All integers are little endian:
+//!
+//! | offset | size | field                          |
+//! |--------|------|--------------------------------|
+//! | 0      | 4    | magic `SCBN`                   |
+//! | 4      | 1    | version                        |
+//! | 5      | 1    | reserved                       |
+//! | 6      | 2    | number of frames               |
+//! | 8      |      | frames                         |
+//!
+//! Every frame is a 2 byte length followed by that many bytes of payload.
+
+use std::fmt;
+
+pub const MAGIC: [u8; 4] = *b"SCBN";
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 8;
+/// Bytes each frame adds on top of its payload
+pub const FRAME_OVERHEAD: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    TooShort,
+    BadMagic,
+    UnsupportedVersion(u8),
+    BadLength,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TooShort => write!(f, "bundle is truncated"),
+            DecodeError::BadMagic => write!(f, "not a bundle"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported bundle version {version}")
+            }
+            DecodeError::BadLength => write!(f, "frames do not match the bundle length"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Whether `buf` looks like a bundle rather than a single packet
+pub fn is_bundle(buf: &[u8]) -> bool {
+    buf.starts_with(&MAGIC)
+}
+
+/// Builds a bundle frame by frame
+#[derive(Default)]
+pub struct BundleBuilder {
+    buf: Vec<u8>,
+    count: u16,
+}
+
+impl BundleBuilder {
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Size of the bundle once a payload of `payload_len` bytes is added
+    pub fn len_with(&self, payload_len: usize) -> usize {
+        self.buf.len().max(HEADER_LEN) + FRAME_OVERHEAD + payload_len
+    }
+
+    pub fn push(&mut self, payload: &[u8]) {
+        if self.buf.is_empty() {
+            self.buf.extend_from_slice(&MAGIC);
+            self.buf.extend_from_slice(&[VERSION, 0, 0, 0]);
+        }
+        self.buf
+            .extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        self.buf.extend_from_slice(payload);
+        self.count += 1;
+    }
+
+    /// Returns the bundle and starts a new one
+    pub fn finish(&mut self) -> Vec<u8> {
+        if let Some(count) = self.buf.get_mut(6..8) {
+            count.copy_from_slice(&self.count.to_le_bytes());
+        }
+        self.count = 0;
+        std::mem::take(&mut self.buf)
+    }
+}
+
+/// The payloads of a bundle
+pub struct Frames<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Iterator for Frames<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (len, rest) = self.rest.split_first_chunk::<FRAME_OVERHEAD>()?;
+        let (frame, rest) = rest.split_at(u16::from_le_bytes(*len) as usize);
+        self.rest = rest;
+        Some(frame)
+    }
+}
+
+/// Checks that `buf` is a well formed bundle and returns its payloads
+pub fn decode(buf: &[u8]) -> Result<Frames<'_>, DecodeError> {
+    if buf.len() < HEADER_LEN {
+        return Err(DecodeError::TooShort);
+    }
+    if !is_bundle(buf) {
+        return Err(DecodeError::BadMagic);
+    }
+    if buf[4] != VERSION {
+        return Err(DecodeError::UnsupportedVersion(buf[4]));
+    }
+    let count = u16::from_le_bytes([buf[6], buf[7]]);
+    let mut rest = &buf[HEADER_LEN..];
+    for _ in 0..count {
+        let Some((len, frames)) = rest.split_first_chunk::<FRAME_OVERHEAD>() else {
+            return Err(DecodeError::BadLength);
+        };
+        let len = u16::from_le_bytes(*len) as usize;
+        if frames.len() < len {
+            return Err(DecodeError::BadLength);
+        }
+        rest = &frames[len..];
+    }
+    if !rest.is_empty() {
+        return Err(DecodeError::BadLength);
+    }
+
+    Ok(Frames {
+        rest: &buf[HEADER_LEN..],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundle(payloads: &[&[u8]]) -> Vec<u8> {
+        let mut builder = BundleBuilder::default();
+        for payload in payloads {
+            builder.push(payload);
+        }
+        builder.finish()
+    }
+
+    #[test]
+    fn round_trips() {
+        let payloads: [&[u8]; 3] = [b"first", b"", &[7; 1200]];
+        let mut builder = BundleBuilder::default();
+        assert!(builder.is_empty());
+        for payload in payloads {
+            let len = builder.len_with(payload.len());
+            builder.push(payload);
+            assert_eq!(builder.buf.len(), len);
+        }
+        let buf = builder.finish();
+        assert!(builder.is_empty());
+        assert!(is_bundle(&buf));
+        assert_eq!(decode(&buf).unwrap().collect::<Vec<_>>(), payloads);
+    }
+
+    #[test]
+    fn builder_starts_over_once_finished() {
+        let mut builder = BundleBuilder::default();
+        builder.push(b"first");
+        builder.finish();
+        builder.push(b"second");
+        let buf = builder.finish();
+        assert_eq!(decode(&buf).unwrap().collect::<Vec<_>>(), [b"second"]);
+    }
+
+    #[test]
+    fn rejects_truncated_bundles() {
+        let buf = bundle(&[b"first", b"second"]);
+        assert_eq!(
+            decode(&buf[..HEADER_LEN - 1]).err(),
+            Some(DecodeError::TooShort)
+        );
+        for len in HEADER_LEN..buf.len() {
+            assert_eq!(decode(&buf[..len]).err(), Some(DecodeError::BadLength));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_bundles() {
+        let buf = bundle(&[b"first", b"second"]);
+
+        let mut trailing = buf.clone();
+        trailing.push(0);
+        assert_eq!(decode(&trailing).err(), Some(DecodeError::BadLength));
+
+        let mut count = buf.clone();
+        count[6] = 3;
+        assert_eq!(decode(&count).err(), Some(DecodeError::BadLength));
+
+        let mut version = buf.clone();
+        version[4] = VERSION + 1;
+        assert_eq!(
+            decode(&version).err(),
+            Some(DecodeError::UnsupportedVersion(VERSION + 1))
+        );
+
+        assert_eq!(decode(b"not a bundle").err(), Some(DecodeError::BadMagic));
+    }
+}
@@ -0,0 +1,255 @@
+//! A fixed size header prepended to forwarded shreds
+//!
+//! All integers are little endian:
+//!
+//! | offset | size | field                                         |
+//! |--------|------|-----------------------------------------------|
+//! | 0      | 4    | magic `SCEV`                                  |
+//! | 4      | 1    | version                                       |
+//! | 5      | 1    | flags, bit 0 set for egress                   |
+//! | 6      | 2    | header length                                 |
+//! | 8      | 8    | per listener sequence number                  |
+//! | 16     | 8    | capture time, unix nanoseconds                |
+//! | 24     | 8    | capture host id                               |
+//! | 32     | 16   | original source address, IPv4 mapped to IPv6  |
+//! | 48     | 2    | original source port                          |
+//! | 50     | 2    | payload length                                |
+//! | 52     | 16   | capture interface name, nul padded            |
+//!
+//! Decoders skip `header length` bytes, so later versions may append fields.
+
+use std::{
+    fmt,
+    net::{Ipv6Addr, SocketAddr},
+};
+
+use crate::net::ipv6_mapped;
+
+pub const MAGIC: [u8; 4] = *b"SCEV";
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 68;
+/// Interface names longer than this are truncated
+pub const IFACE_LEN: usize = 16;
+
+const FLAG_EGRESS: u8 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Ingress,
+    Egress,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Envelope {
+    pub seq: u64,
+    pub capture_ts_ns: u64,
+    pub host_id: u64,
+    pub direction: Direction,
+    /// The address the shred was originally sent from
+    pub src: SocketAddr,
+    pub iface: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    TooShort,
+    BadMagic,
+    UnsupportedVersion(u8),
+    BadLength,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TooShort => write!(f, "envelope is truncated"),
+            DecodeError::BadMagic => write!(f, "not an envelope"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported envelope version {version}")
+            }
+            DecodeError::BadLength => write!(f, "payload length does not match"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+fn read<const N: usize>(buf: &[u8], offset: usize) -> [u8; N] {
+    buf[offset..offset + N].try_into().unwrap()
+}
+
+impl Envelope {
+    /// Appends the header and `payload` to `out`
+    pub fn encode(&self, payload: &[u8], out: &mut Vec<u8>) {
+        let mut header = [0; HEADER_LEN];
+        header[0..4].copy_from_slice(&MAGIC);
+        header[4] = VERSION;
+        header[5] = match self.direction {
+            Direction::Ingress => 0,
+            Direction::Egress => FLAG_EGRESS,
+        };
+        header[6..8].copy_from_slice(&(HEADER_LEN as u16).to_le_bytes());
+        header[8..16].copy_from_slice(&self.seq.to_le_bytes());
+        header[16..24].copy_from_slice(&self.capture_ts_ns.to_le_bytes());
+        header[24..32].copy_from_slice(&self.host_id.to_le_bytes());
+        let src = ipv6_mapped(self.src.ip());
+        header[32..48].copy_from_slice(&src.octets());
+        header[48..50].copy_from_slice(&self.src.port().to_le_bytes());
+        header[50..52].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+        let iface = self.iface.as_bytes();
+        let iface_len = iface.len().min(IFACE_LEN);
+        header[52..52 + iface_len].copy_from_slice(&iface[..iface_len]);
+
+        out.reserve(HEADER_LEN + payload.len());
+        out.extend_from_slice(&header);
+        out.extend_from_slice(payload);
+    }
+
+    /// Splits `buf` into the envelope and its payload
+    pub fn decode(buf: &[u8]) -> Result<(Envelope, &[u8]), DecodeError> {
+        if buf.len() < 8 {
+            return Err(DecodeError::TooShort);
+        }
+        if read::<4>(buf, 0) != MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+        if buf[4] != VERSION {
+            return Err(DecodeError::UnsupportedVersion(buf[4]));
+        }
+        let header_len = u16::from_le_bytes(read(buf, 6)) as usize;
+        if header_len < HEADER_LEN || buf.len() < header_len {
+            return Err(DecodeError::TooShort);
+        }
+        let payload_len = u16::from_le_bytes(read(buf, 50)) as usize;
+        let payload = &buf[header_len..];
+        if payload.len() != payload_len {
+            return Err(DecodeError::BadLength);
+        }
+        let iface = &buf[52..52 + IFACE_LEN];
+        let iface_len = iface.iter().position(|b| *b == 0).unwrap_or(IFACE_LEN);
+
+        let envelope = Envelope {
+            seq: u64::from_le_bytes(read(buf, 8)),
+            capture_ts_ns: u64::from_le_bytes(read(buf, 16)),
+            host_id: u64::from_le_bytes(read(buf, 24)),
+            direction: if buf[5] & FLAG_EGRESS != 0 {
+                Direction::Egress
+            } else {
+                Direction::Ingress
+            },
+            src: SocketAddr::new(
+                Ipv6Addr::from(read::<16>(buf, 32)).to_canonical(),
+                u16::from_le_bytes(read(buf, 48)),
+            ),
+            iface: String::from_utf8_lossy(&iface[..iface_len]).into_owned(),
+        };
+        Ok((envelope, payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn egress_from(src: SocketAddr, iface: &str) -> Envelope {
+        Envelope {
+            seq: 42,
+            capture_ts_ns: 1_700_000_000_000_000_000,
+            host_id: 0xfeed,
+            direction: Direction::Egress,
+            src,
+            iface: iface.to_string(),
+        }
+    }
+
+    #[test]
+    fn header_matches_the_documented_layout() {
+        let src = SocketAddr::new(Ipv4Addr::new(192, 0, 2, 7).into(), 8001);
+        let mut buf = Vec::new();
+        egress_from(src, "eth0").encode(b"shred", &mut buf);
+
+        assert_eq!(buf.len(), HEADER_LEN + 5);
+        assert_eq!(&buf[0..6], b"SCEV\x01\x01");
+        assert_eq!(buf[6..8], 68u16.to_le_bytes());
+        assert_eq!(buf[8..16], 42u64.to_le_bytes());
+        assert_eq!(buf[24..32], 0xfeedu64.to_le_bytes());
+        assert_eq!(
+            buf[32..48],
+            Ipv4Addr::new(192, 0, 2, 7).to_ipv6_mapped().octets()
+        );
+        assert_eq!(buf[48..52], [0x41, 0x1f, 5, 0]);
+        assert_eq!(&buf[52..68], b"eth0\0\0\0\0\0\0\0\0\0\0\0\0");
+        assert_eq!(&buf[68..], b"shred");
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let sources = [
+            "10.1.2.3:8000".parse().unwrap(),
+            "[2001:db8::1]:8001".parse().unwrap(),
+        ];
+        for src in sources {
+            let envelope = egress_from(src, "bond0");
+            let mut buf = b"left alone".to_vec();
+            envelope.encode(&[7; 1232], &mut buf);
+
+            let (decoded, payload) = Envelope::decode(&buf[10..]).unwrap();
+            assert_eq!(decoded, envelope);
+            assert_eq!(payload, [7; 1232]);
+            // IPv4 sources come back as IPv4, not mapped
+            assert_eq!(decoded.src.is_ipv4(), src.is_ipv4());
+        }
+    }
+
+    #[test]
+    fn long_interface_names_are_truncated() {
+        let src = "10.0.0.1:1".parse().unwrap();
+        let mut buf = Vec::new();
+        let mut envelope = egress_from(src, "a-very-long-interface-name");
+        envelope.direction = Direction::Ingress;
+        envelope.encode(&[], &mut buf);
+
+        let (decoded, payload) = Envelope::decode(&buf).unwrap();
+        assert_eq!(decoded.iface, "a-very-long-inte");
+        assert_eq!(decoded.direction, Direction::Ingress);
+        assert!(payload.is_empty());
+    }
+
+    #[test]
+    fn skips_fields_of_later_versions() {
+        let mut buf = Vec::new();
+        egress_from("10.0.0.1:1".parse().unwrap(), "eth0").encode(b"data", &mut buf);
+        buf[6..8].copy_from_slice(&72u16.to_le_bytes());
+        buf.splice(HEADER_LEN..HEADER_LEN, [0xee; 4]);
+
+        let (envelope, payload) = Envelope::decode(&buf).unwrap();
+        assert_eq!(envelope.seq, 42);
+        assert_eq!(payload, b"data");
+    }
+
+    #[test]
+    fn rejects_what_isnt_an_envelope() {
+        let mut valid = Vec::new();
+        egress_from("10.0.0.1:1".parse().unwrap(), "eth0").encode(b"data", &mut valid);
+
+        let mut other_version = valid.clone();
+        other_version[4] = 2;
+        let mut short_header = valid.clone();
+        short_header[6..8].copy_from_slice(&40u16.to_le_bytes());
+        let mut long_payload = valid.clone();
+        long_payload.push(0);
+
+        let cases: [(&[u8], DecodeError); 6] = [
+            (&valid[..7], DecodeError::TooShort),
+            (&valid[..HEADER_LEN - 1], DecodeError::TooShort),
+            (b"SCBN\x01\x00\x44\x00", DecodeError::BadMagic),
+            (&other_version, DecodeError::UnsupportedVersion(2)),
+            (&short_header, DecodeError::TooShort),
+            (&long_payload, DecodeError::BadLength),
+        ];
+        for (buf, error) in cases {
+            assert_eq!(Envelope::decode(buf).unwrap_err(), error);
+        }
+    }
+}
@@ -0,0 +1,380 @@
+//! Reed-Solomon parity over blocks of datagrams
+//!
+//! Every datagram of a block is sent with this header, integers little endian:
+//!
+//! | offset | size | field                                                    |
+//! |--------|------|----------------------------------------------------------|
+//! | 0      | 4    | magic `SCFC`                                             |
+//! | 4      | 1    | version                                                  |
+//! | 5      | 1    | index in the block, data shards first                    |
+//! | 6      | 1    | data shards, final on parity packets                     |
+//! | 7      | 1    | parity shards                                            |
+//! | 8      | 8    | block number                                             |
+//! | 16     |      | shard                                                    |
+//!
+//! Data shards are the datagram prefixed with its 2 byte length. Parity shards
+//! are computed over the data shards zero padded to the longest one, which is
+//! also the length of every parity shard. A block sent before it filled up is
+//! closed early, its parity packets carry the number of data shards it holds.
+
+use std::{collections::BTreeMap, fmt};
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+pub use reed_solomon_erasure::Error;
+
+pub const MAGIC: [u8; 4] = *b"SCFC";
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 16;
+/// Bytes a data packet is larger than the datagram it carries
+pub const OVERHEAD: usize = HEADER_LEN + 2;
+/// Blocks a decoder keeps around for late packets
+const MAX_PENDING_BLOCKS: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    TooShort,
+    BadMagic,
+    UnsupportedVersion(u8),
+    BadShard,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TooShort => write!(f, "fec packet is truncated"),
+            DecodeError::BadMagic => write!(f, "not a fec packet"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported fec version {version}")
+            }
+            DecodeError::BadShard => write!(f, "shard does not match its block"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Whether `buf` is a FEC packet rather than a bare datagram
+pub fn is_fec(buf: &[u8]) -> bool {
+    buf.starts_with(&MAGIC)
+}
+
+fn header(index: usize, data_shards: usize, parity_shards: usize, block: u64) -> [u8; HEADER_LEN] {
+    let mut header = [0; HEADER_LEN];
+    header[0..4].copy_from_slice(&MAGIC);
+    header[4] = VERSION;
+    header[5] = index as u8;
+    header[6] = data_shards as u8;
+    header[7] = parity_shards as u8;
+    header[8..16].copy_from_slice(&block.to_le_bytes());
+    header
+}
+
+/// Adds parity packets to a stream of datagrams
+pub struct FecEncoder {
+    data_shards: usize,
+    parity_shards: usize,
+    /// Codec of full blocks, early closed blocks build their own
+    codec: ReedSolomon,
+    block: u64,
+    /// Data shards of the current block
+    shards: Vec<Vec<u8>>,
+}
+
+impl FecEncoder {
+    /// At most 256 shards per block are supported
+    pub fn new(data_shards: usize, parity_shards: usize) -> Result<Self, Error> {
+        Ok(Self {
+            data_shards,
+            parity_shards,
+            codec: ReedSolomon::new(data_shards, parity_shards)?,
+            block: 0,
+            shards: Vec::with_capacity(data_shards),
+        })
+    }
+
+    /// Whether a block is waiting for more datagrams
+    pub fn is_pending(&self) -> bool {
+        !self.shards.is_empty()
+    }
+
+    /// Returns `datagram` as a data packet, followed by
+    /// the parity packets of its block if it completed it
+    pub fn encode(&mut self, datagram: &[u8]) -> Vec<Vec<u8>> {
+        let mut shard = Vec::with_capacity(2 + datagram.len());
+        shard.extend_from_slice(&(datagram.len() as u16).to_le_bytes());
+        shard.extend_from_slice(datagram);
+
+        let mut packet = Vec::with_capacity(HEADER_LEN + shard.len());
+        packet.extend_from_slice(&header(
+            self.shards.len(),
+            self.data_shards,
+            self.parity_shards,
+            self.block,
+        ));
+        packet.extend_from_slice(&shard);
+        self.shards.push(shard);
+
+        let mut packets = vec![packet];
+        if self.shards.len() == self.data_shards {
+            packets.extend(self.finish());
+        }
+        packets
+    }
+
+    /// Closes the current block, returning its parity packets
+    pub fn finish(&mut self) -> Vec<Vec<u8>> {
+        if self.shards.is_empty() {
+            return Vec::new();
+        }
+        let data_shards = self.shards.len();
+        let shard_len = self.shards.iter().map(Vec::len).max().unwrap_or_default();
+        for shard in self.shards.iter_mut() {
+            shard.resize(shard_len, 0);
+        }
+        let mut parity = vec![vec![0; shard_len]; self.parity_shards];
+        let encoded = if data_shards == self.data_shards {
+            self.codec.encode_sep(&self.shards, &mut parity)
+        } else {
+            ReedSolomon::new(data_shards, self.parity_shards)
+                .and_then(|codec| codec.encode_sep(&self.shards, &mut parity))
+        };
+        let block = self.block;
+        self.block += 1;
+        self.shards.clear();
+        if encoded.is_err() {
+            return Vec::new();
+        }
+
+        parity
+            .into_iter()
+            .enumerate()
+            .map(|(i, shard)| {
+                let mut packet = Vec::with_capacity(HEADER_LEN + shard.len());
+                packet.extend_from_slice(&header(
+                    data_shards + i,
+                    data_shards,
+                    self.parity_shards,
+                    block,
+                ));
+                packet.extend_from_slice(&shard);
+                packet
+            })
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct Block {
+    /// Known once a parity packet arrived
+    data_shards: Option<usize>,
+    parity_shards: usize,
+    shards: BTreeMap<usize, Vec<u8>>,
+    done: bool,
+}
+
+/// Recovers lost datagrams from the packets of a `FecEncoder`
+#[derive(Default)]
+pub struct FecDecoder {
+    blocks: BTreeMap<u64, Block>,
+    /// Datagrams recovered from parity
+    pub recovered: u64,
+}
+
+fn datagram(shard: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let (len, rest) = shard
+        .split_first_chunk::<2>()
+        .ok_or(DecodeError::BadShard)?;
+    rest.get(..u16::from_le_bytes(*len) as usize)
+        .map(<[u8]>::to_vec)
+        .ok_or(DecodeError::BadShard)
+}
+
+impl FecDecoder {
+    /// Returns the datagrams `packet` makes available: the one it carries if
+    /// it is a data packet, and those recovered once its block can be rebuilt
+    pub fn decode(&mut self, packet: &[u8]) -> Result<Vec<Vec<u8>>, DecodeError> {
+        if packet.len() < 5 {
+            return Err(DecodeError::TooShort);
+        }
+        if !is_fec(packet) {
+            return Err(DecodeError::BadMagic);
+        }
+        if packet[4] != VERSION {
+            return Err(DecodeError::UnsupportedVersion(packet[4]));
+        }
+        if packet.len() < HEADER_LEN {
+            return Err(DecodeError::TooShort);
+        }
+        let index = packet[5] as usize;
+        let data_shards = packet[6] as usize;
+        let parity_shards = packet[7] as usize;
+        let number = u64::from_le_bytes(packet[8..16].try_into().unwrap());
+        let shard = &packet[HEADER_LEN..];
+        if data_shards == 0 || parity_shards == 0 {
+            return Err(DecodeError::BadShard);
+        }
+        // blocks older than every pending one were already given up on
+        if self.blocks.len() >= MAX_PENDING_BLOCKS
+            && self
+                .blocks
+                .first_key_value()
+                .is_some_and(|(first, _)| number < *first)
+        {
+            return Ok(Vec::new());
+        }
+
+        let block = self.blocks.entry(number).or_default();
+        block.parity_shards = parity_shards;
+        let mut datagrams = Vec::new();
+        if index >= data_shards {
+            block.data_shards = Some(data_shards);
+        } else if !block.shards.contains_key(&index) {
+            datagrams.push(datagram(shard)?);
+        }
+        block.shards.entry(index).or_insert_with(|| shard.to_vec());
+
+        if let Some(data_shards) = block.data_shards
+            && !block.done
+            && block.shards.len() >= data_shards
+        {
+            block.done = true;
+            let missing: Vec<_> = (0..data_shards)
+                .filter(|index| !block.shards.contains_key(index))
+                .collect();
+            if !missing.is_empty() {
+                let shard_len = (data_shards..data_shards + block.parity_shards)
+                    .find_map(|index| block.shards.get(&index))
+                    .map_or(0, Vec::len);
+                let mut shards: Vec<Option<Vec<u8>>> = (0..data_shards + block.parity_shards)
+                    .map(|index| {
+                        let mut shard = block.shards.get(&index)?.clone();
+                        shard.resize(shard_len, 0);
+                        Some(shard)
+                    })
+                    .collect();
+                ReedSolomon::new(data_shards, block.parity_shards)
+                    .and_then(|codec| codec.reconstruct_data(&mut shards))
+                    .map_err(|_| DecodeError::BadShard)?;
+                for index in missing {
+                    if let Some(shard) = &shards[index] {
+                        datagrams.push(datagram(shard)?);
+                        self.recovered += 1;
+                    }
+                }
+            }
+        }
+
+        while self.blocks.len() > MAX_PENDING_BLOCKS {
+            self.blocks.pop_first();
+        }
+        Ok(datagrams)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagrams(count: usize) -> Vec<Vec<u8>> {
+        (0..count).map(|i| vec![i as u8; 100 + i * 37]).collect()
+    }
+
+    /// Feeds `packets` but those at `lost` to a decoder, returning what it hands out sorted
+    fn decode(packets: &[Vec<u8>], lost: &[usize]) -> (Vec<Vec<u8>>, u64) {
+        let mut decoder = FecDecoder::default();
+        let mut decoded = Vec::new();
+        for (i, packet) in packets.iter().enumerate() {
+            if !lost.contains(&i) {
+                decoded.extend(decoder.decode(packet).unwrap());
+            }
+        }
+        decoded.sort();
+        (decoded, decoder.recovered)
+    }
+
+    #[test]
+    fn recovers_lost_datagrams_of_full_block() {
+        let datagrams = datagrams(4);
+        let mut encoder = FecEncoder::new(4, 2).unwrap();
+        let packets: Vec<_> = datagrams.iter().flat_map(|d| encoder.encode(d)).collect();
+        assert_eq!(packets.len(), 6);
+        assert!(!encoder.is_pending());
+
+        let (decoded, recovered) = decode(&packets, &[1, 3]);
+        assert_eq!(decoded, datagrams);
+        assert_eq!(recovered, 2);
+    }
+
+    #[test]
+    fn recovers_lost_datagrams_of_early_closed_block() {
+        let datagrams = datagrams(2);
+        let mut encoder = FecEncoder::new(4, 2).unwrap();
+        let mut packets: Vec<_> = datagrams.iter().flat_map(|d| encoder.encode(d)).collect();
+        assert!(encoder.is_pending());
+        packets.extend(encoder.finish());
+        assert_eq!(packets.len(), 4);
+
+        let (decoded, recovered) = decode(&packets, &[0, 2]);
+        assert_eq!(decoded, datagrams);
+        assert_eq!(recovered, 1);
+    }
+
+    #[test]
+    fn blocks_are_decoded_independently() {
+        let datagrams = datagrams(6);
+        let mut encoder = FecEncoder::new(3, 1).unwrap();
+        let packets: Vec<_> = datagrams.iter().flat_map(|d| encoder.encode(d)).collect();
+        assert_eq!(packets.len(), 8);
+
+        // one datagram of each block
+        let (decoded, recovered) = decode(&packets, &[0, 5]);
+        assert_eq!(decoded, datagrams);
+        assert_eq!(recovered, 2);
+    }
+
+    #[test]
+    fn too_many_losses_recover_nothing() {
+        let datagrams = datagrams(4);
+        let mut encoder = FecEncoder::new(4, 1).unwrap();
+        let packets: Vec<_> = datagrams.iter().flat_map(|d| encoder.encode(d)).collect();
+
+        let (decoded, recovered) = decode(&packets, &[0, 1]);
+        assert_eq!(decoded, datagrams[2..]);
+        assert_eq!(recovered, 0);
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        let mut encoder = FecEncoder::new(2, 1).unwrap();
+        let packet = encoder.encode(b"datagram").remove(0);
+        let mut decoder = FecDecoder::default();
+
+        assert_eq!(decoder.decode(&packet[..4]), Err(DecodeError::TooShort));
+        assert_eq!(
+            decoder.decode(&packet[..HEADER_LEN - 1]),
+            Err(DecodeError::TooShort)
+        );
+        assert_eq!(
+            decoder.decode(b"not a fec packet"),
+            Err(DecodeError::BadMagic)
+        );
+
+        let mut version = packet.clone();
+        version[4] = VERSION + 1;
+        assert_eq!(
+            decoder.decode(&version),
+            Err(DecodeError::UnsupportedVersion(VERSION + 1))
+        );
+
+        let mut no_parity = packet.clone();
+        no_parity[7] = 0;
+        assert_eq!(decoder.decode(&no_parity), Err(DecodeError::BadShard));
+
+        // the length prefix claims more than the shard holds
+        assert_eq!(
+            decoder.decode(&packet[..packet.len() - 1]),
+            Err(DecodeError::BadShard)
+        );
+    }
+}
@@ -0,0 +1,21 @@
+//! Hashing that has to agree between shredcaster instances and tools,
+//! so that they pick the same shreds and reproduce the same runs
+
+const GOLDEN_GAMMA: u64 = 0x9e3779b97f4a7c15;
+
+/// The splitmix64 finalizer, which spreads any input uniformly over `u64`
+pub fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// The splitmix64 generator, the same seed yields the same sequence
+pub struct SplitMix64(pub u64);
+
+impl SplitMix64 {
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(GOLDEN_GAMMA);
+        mix(self.0)
+    }
+}
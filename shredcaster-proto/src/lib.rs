@@ -0,0 +1,12 @@
+//! Wire formats shared by shredcaster and the tools consuming its output
+
+pub mod archive;
+pub mod bundle;
+pub mod envelope;
+pub mod fec;
+pub mod hash;
+pub mod nack;
+pub mod net;
+pub mod pcap;
+pub mod secure;
+pub mod shred;
@@ -0,0 +1,165 @@
+//! Retransmission requests sent by listeners
+//!
+//! Listeners send NACKs to shredcaster's control address from the address they
+//! receive on, naming envelope sequence numbers they missed. All integers are
+//! little endian:
+//!
+//! | offset | size | field                                              |
+//! |--------|------|----------------------------------------------------|
+//! | 0      | 4    | magic `SCNK`                                       |
+//! | 4      | 1    | version                                            |
+//! | 5      | 1    | reserved                                           |
+//! | 6      | 2    | number of ranges                                   |
+//! | 8      | 16   | first and last missing sequence number, repeated   |
+//!
+//! Listeners configured with a `psk` seal their NACKs with
+//! `shredcaster_proto::secure`, shredcaster ignores unsealed ones.
+
+use std::{fmt, ops::RangeInclusive};
+
+pub const MAGIC: [u8; 4] = *b"SCNK";
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 8;
+/// Ranges that fit in a NACK well below any MTU
+pub const MAX_RANGES: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    TooShort,
+    BadMagic,
+    UnsupportedVersion(u8),
+    BadLength,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TooShort => write!(f, "nack is truncated"),
+            DecodeError::BadMagic => write!(f, "not a nack"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported nack version {version}")
+            }
+            DecodeError::BadLength => write!(f, "ranges do not match the nack length"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Nack {
+    /// Missing sequence numbers, at most `MAX_RANGES` are encoded
+    pub ranges: Vec<RangeInclusive<u64>>,
+}
+
+impl Nack {
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let ranges = &self.ranges[..self.ranges.len().min(MAX_RANGES)];
+        out.extend_from_slice(&MAGIC);
+        out.extend_from_slice(&[VERSION, 0]);
+        out.extend_from_slice(&(ranges.len() as u16).to_le_bytes());
+        for range in ranges {
+            out.extend_from_slice(&range.start().to_le_bytes());
+            out.extend_from_slice(&range.end().to_le_bytes());
+        }
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Nack, DecodeError> {
+        if buf.len() < HEADER_LEN {
+            return Err(DecodeError::TooShort);
+        }
+        if !buf.starts_with(&MAGIC) {
+            return Err(DecodeError::BadMagic);
+        }
+        if buf[4] != VERSION {
+            return Err(DecodeError::UnsupportedVersion(buf[4]));
+        }
+        let count = u16::from_le_bytes([buf[6], buf[7]]) as usize;
+        let body = &buf[HEADER_LEN..];
+        if count > MAX_RANGES || body.len() != count * 16 {
+            return Err(DecodeError::BadLength);
+        }
+        let ranges = body
+            .chunks_exact(16)
+            .map(|range| {
+                let first = u64::from_le_bytes(range[..8].try_into().unwrap());
+                let last = u64::from_le_bytes(range[8..].try_into().unwrap());
+                first..=last
+            })
+            .collect();
+
+        Ok(Nack { ranges })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(nack: &Nack) -> Vec<u8> {
+        let mut buf = Vec::new();
+        nack.encode(&mut buf);
+        buf
+    }
+
+    #[test]
+    fn round_trips() {
+        for nack in [
+            Nack::default(),
+            Nack {
+                ranges: vec![1..=1, 5..=9, 0..=u64::MAX],
+            },
+        ] {
+            assert_eq!(Nack::decode(&encoded(&nack)), Ok(nack));
+        }
+    }
+
+    #[test]
+    fn encodes_at_most_max_ranges() {
+        let nack = Nack {
+            ranges: (0..MAX_RANGES as u64 + 10).map(|seq| seq..=seq).collect(),
+        };
+        let decoded = Nack::decode(&encoded(&nack)).unwrap();
+        assert_eq!(decoded.ranges, nack.ranges[..MAX_RANGES]);
+    }
+
+    #[test]
+    fn rejects_truncated_nacks() {
+        let buf = encoded(&Nack {
+            ranges: vec![1..=2, 4..=8],
+        });
+        assert_eq!(
+            Nack::decode(&buf[..HEADER_LEN - 1]),
+            Err(DecodeError::TooShort)
+        );
+        for len in HEADER_LEN..buf.len() {
+            assert_eq!(Nack::decode(&buf[..len]), Err(DecodeError::BadLength));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_nacks() {
+        let buf = encoded(&Nack {
+            ranges: vec![1..=2],
+        });
+
+        let mut trailing = buf.clone();
+        trailing.push(0);
+        assert_eq!(Nack::decode(&trailing), Err(DecodeError::BadLength));
+
+        // more ranges than a NACK may hold, even if they are all there
+        let mut too_many = encoded(&Nack::default());
+        too_many[6..8].copy_from_slice(&(MAX_RANGES as u16 + 1).to_le_bytes());
+        too_many.resize(HEADER_LEN + (MAX_RANGES + 1) * 16, 0);
+        assert_eq!(Nack::decode(&too_many), Err(DecodeError::BadLength));
+
+        let mut version = buf.clone();
+        version[4] = VERSION + 1;
+        assert_eq!(
+            Nack::decode(&version),
+            Err(DecodeError::UnsupportedVersion(VERSION + 1))
+        );
+
+        assert_eq!(Nack::decode(b"not a nack"), Err(DecodeError::BadMagic));
+    }
+}
@@ -0,0 +1,31 @@
+//! Helpers for the IP and UDP headers shredcaster writes and reads
+
+use std::net::{IpAddr, Ipv6Addr};
+
+/// The ones' complement sum of `data` as big endian 16 bit words, added to `sum`
+pub fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut words = data.chunks_exact(2);
+    for word in words.by_ref() {
+        sum += u32::from(u16::from_be_bytes([word[0], word[1]]));
+    }
+    if let [last] = words.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+    sum
+}
+
+/// Folds a sum from `checksum_add` into the checksum written to a header
+pub fn checksum_finish(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// `ip` as an IPv6 address, IPv4 addresses are mapped into `::ffff:0:0/96`
+pub fn ipv6_mapped(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
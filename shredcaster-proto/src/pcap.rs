@@ -0,0 +1,607 @@
+//! Reading pcap and pcapng files, e.g. recorded with tcpdump
+
+use std::{
+    fs::File,
+    io::{self, BufReader, Read},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub const PCAPNG_SECTION_HEADER: u32 = 0x0a0d0d0a;
+pub const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+pub const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_SIMPLE_PACKET: u32 = 3;
+pub const PCAPNG_ENHANCED_PACKET: u32 = 6;
+/// Larger blocks are taken as a corrupt file rather than allocated
+const PCAPNG_MAX_BLOCK_LEN: usize = 16 << 20;
+
+const LINKTYPE_NULL: u16 = 0;
+pub const LINKTYPE_ETHERNET: u16 = 1;
+const LINKTYPE_RAW: u16 = 101;
+const LINKTYPE_LINUX_SLL: u16 = 113;
+const LINKTYPE_IPV4: u16 = 228;
+const LINKTYPE_IPV6: u16 = 229;
+const LINKTYPE_LINUX_SLL2: u16 = 276;
+const PACKET_OUTGOING: u8 = 4;
+const IPPROTO_UDP: u8 = 17;
+
+fn u16_at(buf: &[u8], at: usize, big_endian: bool) -> Option<u16> {
+    let bytes = buf.get(at..at + 2)?.try_into().ok()?;
+    Some(if big_endian {
+        u16::from_be_bytes(bytes)
+    } else {
+        u16::from_le_bytes(bytes)
+    })
+}
+
+fn u32_at(buf: &[u8], at: usize, big_endian: bool) -> Option<u32> {
+    let bytes = buf.get(at..at + 4)?.try_into().ok()?;
+    Some(if big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    })
+}
+
+/// Fills `buf`, returning false if the file ended before the first byte
+fn read_exact_or_eof(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..])? {
+            0 if read == 0 => return Ok(false),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => read += n,
+        }
+    }
+    Ok(true)
+}
+
+/// The IP packet a frame of `linktype` carries, and whether the frame was
+/// outgoing if the link type records that
+fn ip_packet(linktype: u16, frame: &[u8]) -> Option<(&[u8], bool)> {
+    match linktype {
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            // skip VLAN tags
+            while matches!(u16_at(frame, offset, true)?, 0x8100 | 0x88a8) {
+                offset += 4;
+            }
+            Some((frame.get(offset + 2..)?, false))
+        }
+        // the address family is in the capturing host's byte order, the IP version tells
+        LINKTYPE_NULL => Some((frame.get(4..)?, false)),
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => Some((frame, false)),
+        LINKTYPE_LINUX_SLL => {
+            let outgoing = u16_at(frame, 0, true)? == u16::from(PACKET_OUTGOING);
+            Some((frame.get(16..)?, outgoing))
+        }
+        LINKTYPE_LINUX_SLL2 => Some((frame.get(20..)?, *frame.get(10)? == PACKET_OUTGOING)),
+        _ => None,
+    }
+}
+
+struct Interface {
+    linktype: u16,
+    /// Timestamp units per second
+    ts_resolution: u64,
+}
+
+enum Format {
+    Pcap {
+        big_endian: bool,
+        nanos: bool,
+        linktype: u16,
+    },
+    Pcapng {
+        big_endian: bool,
+        interfaces: Vec<Interface>,
+    },
+}
+
+/// A captured frame, held in the reader's buffer
+struct Frame {
+    ts_ns: u64,
+    linktype: u16,
+    data: (usize, usize),
+}
+
+/// A UDP datagram of a captured frame
+pub struct Datagram<'a> {
+    /// Unix time the frame was captured at, in nanoseconds
+    pub ts_ns: u64,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    /// Set if the frame was outgoing and the link type records that
+    pub is_egress: bool,
+    pub payload: &'a [u8],
+}
+
+/// Reads the UDP datagrams of a pcap or pcapng file, e.g. one recorded with tcpdump
+pub struct PcapReader {
+    reader: BufReader<File>,
+    format: Format,
+    buf: Vec<u8>,
+}
+
+impl PcapReader {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        let format = match u32::from_le_bytes(magic) {
+            PCAPNG_SECTION_HEADER => Format::Pcapng {
+                // set by the section header, which is read as a block
+                big_endian: false,
+                interfaces: Vec::new(),
+            },
+            magic => {
+                let (big_endian, nanos) = match (magic, magic.swap_bytes()) {
+                    (0xa1b2c3d4, _) => (false, false),
+                    (0xa1b23c4d, _) => (false, true),
+                    (_, 0xa1b2c3d4) => (true, false),
+                    (_, 0xa1b23c4d) => (true, true),
+                    _ => {
+                        return Err(invalid(format!(
+                            "{} is not a pcap or pcapng file",
+                            path.display()
+                        )));
+                    }
+                };
+                let mut header = [0; 20];
+                reader.read_exact(&mut header)?;
+                Format::Pcap {
+                    big_endian,
+                    nanos,
+                    // the upper bits hold FCS information
+                    linktype: u32_at(&header, 16, big_endian).unwrap_or_default() as u16,
+                }
+            }
+        };
+        let mut reader = Self {
+            reader,
+            format,
+            buf: Vec::new(),
+        };
+        if let Format::Pcapng { .. } = reader.format {
+            reader.read_section_header()?;
+        }
+        Ok(reader)
+    }
+
+    /// Reads the next UDP datagram, skipping other frames, `None` at the end of the file
+    pub fn next_datagram(&mut self) -> io::Result<Option<Datagram<'_>>> {
+        loop {
+            let Some(frame) = self.next_frame()? else {
+                return Ok(None);
+            };
+            if self.datagram(&frame).is_some() {
+                return Ok(self.datagram(&frame));
+            }
+        }
+    }
+
+    fn datagram(&self, frame: &Frame) -> Option<Datagram<'_>> {
+        let (packet, is_egress) = ip_packet(frame.linktype, &self.buf[frame.data.0..frame.data.1])?;
+        let datagram = parse_udp(packet)?;
+        Some(Datagram {
+            ts_ns: frame.ts_ns,
+            src: datagram.src,
+            dst: datagram.dst,
+            is_egress,
+            payload: datagram.payload,
+        })
+    }
+
+    /// Reads the rest of a pcapng section header, whose block type was already read
+    fn read_section_header(&mut self) -> io::Result<()> {
+        let mut header = [0; 8];
+        self.reader.read_exact(&mut header)?;
+        let big_endian = match u32::from_le_bytes(header[4..8].try_into().unwrap()) {
+            PCAPNG_BYTE_ORDER_MAGIC => false,
+            magic if magic.swap_bytes() == PCAPNG_BYTE_ORDER_MAGIC => true,
+            _ => return Err(invalid("invalid pcapng byte order magic".to_owned())),
+        };
+        let block_len = u32_at(&header, 0, big_endian).unwrap_or_default() as usize;
+        if !(28..=PCAPNG_MAX_BLOCK_LEN).contains(&block_len) {
+            return Err(invalid(format!(
+                "invalid pcapng section length {block_len}"
+            )));
+        }
+        self.buf.resize(block_len - 12, 0);
+        self.reader.read_exact(&mut self.buf)?;
+        self.format = Format::Pcapng {
+            big_endian,
+            interfaces: Vec::new(),
+        };
+        Ok(())
+    }
+
+    /// Reads the next frame into the buffer, `None` at the end of the file
+    fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        loop {
+            match &mut self.format {
+                Format::Pcap {
+                    big_endian,
+                    nanos,
+                    linktype,
+                } => {
+                    let mut header = [0; 16];
+                    if !read_exact_or_eof(&mut self.reader, &mut header)? {
+                        return Ok(None);
+                    }
+                    let field =
+                        |at| u64::from(u32_at(&header, at, *big_endian).unwrap_or_default());
+                    let (secs, frac, len) = (field(0), field(4), field(8) as usize);
+                    if len > PCAPNG_MAX_BLOCK_LEN {
+                        return Err(invalid(format!("invalid pcap record length {len}")));
+                    }
+                    self.buf.resize(len, 0);
+                    self.reader.read_exact(&mut self.buf)?;
+                    return Ok(Some(Frame {
+                        ts_ns: secs * 1_000_000_000 + if *nanos { frac } else { frac * 1000 },
+                        linktype: *linktype,
+                        data: (0, len),
+                    }));
+                }
+                Format::Pcapng {
+                    big_endian,
+                    interfaces,
+                } => {
+                    let mut header = [0; 8];
+                    if !read_exact_or_eof(&mut self.reader, &mut header)? {
+                        return Ok(None);
+                    }
+                    let block_type = u32_at(&header, 0, *big_endian).unwrap_or_default();
+                    if block_type == PCAPNG_SECTION_HEADER {
+                        self.read_section_header()?;
+                        continue;
+                    }
+                    let block_len = u32_at(&header, 4, *big_endian).unwrap_or_default() as usize;
+                    if !(12..=PCAPNG_MAX_BLOCK_LEN).contains(&block_len) {
+                        return Err(invalid(format!("invalid pcapng block length {block_len}")));
+                    }
+                    self.buf.resize(block_len - 8, 0);
+                    self.reader.read_exact(&mut self.buf)?;
+                    // without the trailing copy of the block length
+                    let body = &self.buf[..block_len - 12];
+                    let big_endian = *big_endian;
+                    match block_type {
+                        PCAPNG_INTERFACE_DESCRIPTION => {
+                            interfaces.push(Interface {
+                                linktype: u16_at(body, 0, big_endian).unwrap_or_default(),
+                                ts_resolution: ts_resolution(body, big_endian),
+                            });
+                        }
+                        PCAPNG_ENHANCED_PACKET => {
+                            let field = |at| u32_at(body, at, big_endian).unwrap_or_default();
+                            let Some(interface) = interfaces.get(field(0) as usize) else {
+                                continue;
+                            };
+                            let ts = u64::from(field(4)) << 32 | u64::from(field(8));
+                            let len = (field(12) as usize).min(body.len().saturating_sub(20));
+                            return Ok(Some(Frame {
+                                ts_ns: (u128::from(ts) * 1_000_000_000
+                                    / u128::from(interface.ts_resolution))
+                                    as u64,
+                                linktype: interface.linktype,
+                                data: (20, 20 + len),
+                            }));
+                        }
+                        PCAPNG_SIMPLE_PACKET => {
+                            let Some(interface) = interfaces.first() else {
+                                continue;
+                            };
+                            let len = u32_at(body, 0, big_endian).unwrap_or_default() as usize;
+                            return Ok(Some(Frame {
+                                // simple packets carry no timestamp
+                                ts_ns: SystemTime::now()
+                                    .duration_since(UNIX_EPOCH)
+                                    .unwrap_or_default()
+                                    .as_nanos() as u64,
+                                linktype: interface.linktype,
+                                data: (4, 4 + len.min(body.len().saturating_sub(4))),
+                            }));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The `if_tsresol` option of an interface description block,
+/// microseconds if it is absent
+fn ts_resolution(body: &[u8], big_endian: bool) -> u64 {
+    let mut offset = 8;
+    while let (Some(code), Some(len)) = (
+        u16_at(body, offset, big_endian),
+        u16_at(body, offset + 2, big_endian),
+    ) {
+        let len = usize::from(len);
+        match code {
+            0 => break,
+            9 => {
+                return match body.get(offset + 4) {
+                    Some(resolution) if resolution & 0x80 != 0 => {
+                        1u64.checked_shl(u32::from(resolution & 0x7f))
+                    }
+                    Some(resolution) => 10u64.checked_pow(u32::from(*resolution)),
+                    None => None,
+                }
+                .unwrap_or(1_000_000);
+            }
+            _ => {}
+        }
+        offset += 4 + len.next_multiple_of(4);
+    }
+    1_000_000
+}
+
+/// A UDP datagram of an IP packet
+pub struct UdpDatagram<'a> {
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub payload: &'a [u8],
+}
+
+/// Parses an IPv4 or IPv6 packet carrying a UDP datagram, `None` for anything
+/// else, for fragments and for datagrams cut short by the capture length
+pub fn parse_udp(packet: &[u8]) -> Option<UdpDatagram<'_>> {
+    let be16 = |buf: &[u8], at: usize| Some(u16::from_be_bytes([*buf.get(at)?, *buf.get(at + 1)?]));
+    let (src, dst, udp) = match packet.first()? >> 4 {
+        4 => {
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            // later fragments don't start with a UDP header
+            if header_len < 20 || *packet.get(9)? != IPPROTO_UDP || be16(packet, 6)? & 0x1fff != 0 {
+                return None;
+            }
+            let total_len = usize::from(be16(packet, 2)?).min(packet.len());
+            let src: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+            (
+                IpAddr::V4(Ipv4Addr::from(src)),
+                IpAddr::V4(Ipv4Addr::from(dst)),
+                packet.get(header_len..total_len)?,
+            )
+        }
+        // like the BPF programs, UDP has to directly follow the fixed header
+        6 => {
+            if *packet.get(6)? != IPPROTO_UDP {
+                return None;
+            }
+            let end = (40 + usize::from(be16(packet, 4)?)).min(packet.len());
+            let src: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+            (
+                IpAddr::V6(Ipv6Addr::from(src)),
+                IpAddr::V6(Ipv6Addr::from(dst)),
+                packet.get(40..end)?,
+            )
+        }
+        _ => return None,
+    };
+    let udp_len = usize::from(be16(udp, 4)?);
+    Some(UdpDatagram {
+        src: SocketAddr::new(src.to_canonical(), be16(udp, 0)?),
+        dst: SocketAddr::new(dst.to_canonical(), be16(udp, 2)?),
+        payload: udp.get(8..udp_len)?,
+    })
+}
+
+fn invalid(error: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    const PAYLOAD: &[u8] = b"shred";
+
+    fn ipv4_udp(payload: &[u8]) -> Vec<u8> {
+        let total_len = (20 + 8 + payload.len()) as u16;
+        let mut packet = vec![0x45, 0];
+        packet.extend_from_slice(&total_len.to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0x40, 0, 64, IPPROTO_UDP, 0, 0]);
+        packet.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        packet.extend_from_slice(&8001u16.to_be_bytes());
+        packet.extend_from_slice(&8002u16.to_be_bytes());
+        packet.extend_from_slice(&(8 + payload.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn ipv6_udp(payload: &[u8]) -> Vec<u8> {
+        let udp_len = (8 + payload.len()) as u16;
+        let mut packet = vec![0x60, 0, 0, 0];
+        packet.extend_from_slice(&udp_len.to_be_bytes());
+        packet.extend_from_slice(&[IPPROTO_UDP, 64]);
+        packet.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        packet.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        packet.extend_from_slice(&8001u16.to_be_bytes());
+        packet.extend_from_slice(&8002u16.to_be_bytes());
+        packet.extend_from_slice(&udp_len.to_be_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn ethernet(ip: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        // a VLAN tag in front of IPv4
+        frame.extend_from_slice(&[0x81, 0x00, 0, 1, 0x08, 0x00]);
+        frame.extend_from_slice(ip);
+        frame
+    }
+
+    fn block(out: &mut Vec<u8>, block_type: u32, body: &[u8]) {
+        let len = (12 + body.len().next_multiple_of(4)) as u32;
+        out.extend_from_slice(&block_type.to_le_bytes());
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(body);
+        out.resize(out.len().next_multiple_of(4), 0);
+        out.extend_from_slice(&len.to_le_bytes());
+    }
+
+    fn pcapng(frames: &[(u64, Vec<u8>)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut shb = PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes().to_vec();
+        shb.extend_from_slice(&[1, 0, 0, 0]);
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        block(&mut out, PCAPNG_SECTION_HEADER, &shb);
+        let mut idb = LINKTYPE_ETHERNET.to_le_bytes().to_vec();
+        idb.extend_from_slice(&[0; 6]);
+        // if_tsresol of nanoseconds
+        idb.extend_from_slice(&[9, 0, 1, 0, 9, 0, 0, 0, 0, 0, 0, 0]);
+        block(&mut out, PCAPNG_INTERFACE_DESCRIPTION, &idb);
+        for (ts_ns, frame) in frames {
+            let mut epb = 0u32.to_le_bytes().to_vec();
+            epb.extend_from_slice(&((ts_ns >> 32) as u32).to_le_bytes());
+            epb.extend_from_slice(&(*ts_ns as u32).to_le_bytes());
+            epb.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            epb.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            epb.extend_from_slice(frame);
+            block(&mut out, PCAPNG_ENHANCED_PACKET, &epb);
+        }
+        out
+    }
+
+    /// A classic pcap file of raw IP packets with microsecond timestamps
+    fn pcap(packets: &[(u64, Vec<u8>)]) -> Vec<u8> {
+        let mut out = 0xa1b2c3d4u32.to_le_bytes().to_vec();
+        out.extend_from_slice(&[2, 0, 4, 0]);
+        out.extend_from_slice(&[0; 8]);
+        out.extend_from_slice(&65535u32.to_le_bytes());
+        out.extend_from_slice(&u32::from(LINKTYPE_RAW).to_le_bytes());
+        for (ts_us, packet) in packets {
+            out.extend_from_slice(&((ts_us / 1_000_000) as u32).to_le_bytes());
+            out.extend_from_slice(&((ts_us % 1_000_000) as u32).to_le_bytes());
+            out.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            out.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            out.extend_from_slice(packet);
+        }
+        out
+    }
+
+    fn test_file(name: &str, contents: &[u8]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("shredcaster-proto-{name}-{}", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// The timestamps, addresses and payloads of every datagram in the file at `path`
+    fn datagrams(path: &Path) -> Vec<(u64, SocketAddr, SocketAddr, Vec<u8>)> {
+        let mut reader = PcapReader::open(path).unwrap();
+        let mut datagrams = Vec::new();
+        while let Some(datagram) = reader.next_datagram().unwrap() {
+            datagrams.push((
+                datagram.ts_ns,
+                datagram.src,
+                datagram.dst,
+                datagram.payload.to_vec(),
+            ));
+        }
+        std::fs::remove_file(path).unwrap();
+        datagrams
+    }
+
+    #[test]
+    fn reads_pcapng() {
+        let ts_ns = 1_700_000_000_123_456_789;
+        let path = test_file(
+            "reads-pcapng",
+            &pcapng(&[
+                (ts_ns, ethernet(&ipv4_udp(PAYLOAD))),
+                // not IP, skipped
+                (ts_ns + 1, vec![0; 60]),
+                (ts_ns + 2, ethernet(&ipv4_udp(b""))),
+            ]),
+        );
+        let src = "10.0.0.1:8001".parse().unwrap();
+        let dst = "10.0.0.2:8002".parse().unwrap();
+        assert_eq!(
+            datagrams(&path),
+            [
+                (ts_ns, src, dst, PAYLOAD.to_vec()),
+                (ts_ns + 2, src, dst, Vec::new())
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_pcap() {
+        let ts_us = 1_700_000_000_123_456;
+        let path = test_file("reads-pcap", &pcap(&[(ts_us, ipv6_udp(PAYLOAD))]));
+        assert_eq!(
+            datagrams(&path),
+            [(
+                ts_us * 1000,
+                "[2001:db8::1]:8001".parse().unwrap(),
+                "[2001:db8::2]:8002".parse().unwrap(),
+                PAYLOAD.to_vec()
+            )]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        let path = test_file("not-pcap", b"not a capture file at all");
+        assert!(PcapReader::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+
+        // a packet block cut short
+        let mut truncated = pcapng(&[(0, ethernet(&ipv4_udp(PAYLOAD)))]);
+        truncated.truncate(truncated.len() - 8);
+        let path = test_file("truncated-pcapng", &truncated);
+        let mut reader = PcapReader::open(&path).unwrap();
+        assert!(reader.next_datagram().is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn parses_udp() {
+        let packet = ipv4_udp(PAYLOAD);
+        let datagram = parse_udp(&packet).unwrap();
+        assert_eq!(datagram.src, "10.0.0.1:8001".parse().unwrap());
+        assert_eq!(datagram.dst, "10.0.0.2:8002".parse().unwrap());
+        assert_eq!(datagram.payload, PAYLOAD);
+
+        let packet = ipv6_udp(PAYLOAD);
+        let datagram = parse_udp(&packet).unwrap();
+        assert_eq!(datagram.src, "[2001:db8::1]:8001".parse().unwrap());
+        assert_eq!(datagram.payload, PAYLOAD);
+    }
+
+    #[test]
+    fn rejects_malformed_udp() {
+        let packet = ipv4_udp(PAYLOAD);
+        for len in 0..packet.len() {
+            assert!(parse_udp(&packet[..len]).is_none());
+        }
+        let packet = ipv6_udp(PAYLOAD);
+        for len in 0..packet.len() {
+            assert!(parse_udp(&packet[..len]).is_none());
+        }
+
+        let mut tcp = ipv4_udp(PAYLOAD);
+        tcp[9] = 6;
+        assert!(parse_udp(&tcp).is_none());
+
+        let mut fragment = ipv4_udp(PAYLOAD);
+        fragment[6..8].copy_from_slice(&1u16.to_be_bytes());
+        assert!(parse_udp(&fragment).is_none());
+
+        let mut short_header = ipv4_udp(PAYLOAD);
+        short_header[0] = 0x44;
+        assert!(parse_udp(&short_header).is_none());
+
+        assert!(parse_udp(&[0x20; 40]).is_none());
+    }
+}
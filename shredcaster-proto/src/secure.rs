@@ -0,0 +1,475 @@
+//! Authenticated encryption of datagrams with a pre-shared key
+//!
+//! Every datagram is sealed with XChaCha20-Poly1305 under a 32 byte key shared
+//! by the sender and the listener:
+//!
+//! | offset | size | field                                              |
+//! |--------|------|----------------------------------------------------|
+//! | 0      | 4    | magic `SCSE`                                       |
+//! | 4      | 1    | version                                            |
+//! | 5      | 3    | reserved                                           |
+//! | 8      | 8    | session start, unix nanoseconds, little endian     |
+//! | 16     | 8    | session id, random                                 |
+//! | 24     | 8    | sequence number, little endian                     |
+//! | 32     |      | ciphertext followed by a 16 byte tag               |
+//!
+//! The nonce is bytes 8..32 and the whole header is authenticated. Senders pick
+//! a fresh session on start and every `SESSION_ROTATION`, so nonces are never
+//! reused, and receivers reject sequence numbers they already accepted or that
+//! fell out of the replay window, as well as sessions older than the two newest
+//! ones they have seen.
+//!
+//! Receivers only remember sessions in memory, so they also reject sessions that
+//! started more than `MAX_SESSION_AGE` before their own clock. A restarted
+//! receiver still accepts datagrams recorded from sessions younger than that,
+//! once each, which bounds what an attacker can replay to it. Clocks of senders
+//! and receivers have to agree to within `MAX_SESSION_AGE - SESSION_ROTATION`.
+
+use std::{
+    fmt,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use chacha20poly1305::{
+    KeyInit, Tag, XChaCha20Poly1305, XNonce,
+    aead::{AeadInPlace, Error as AeadError},
+};
+
+pub const MAGIC: [u8; 4] = *b"SCSE";
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 32;
+pub const TAG_LEN: usize = 16;
+/// Bytes a sealed datagram is larger than its payload
+pub const OVERHEAD: usize = HEADER_LEN + TAG_LEN;
+/// How far behind the newest sequence number a datagram may arrive,
+/// generous enough for retransmissions
+pub const REPLAY_WINDOW: u64 = 1 << 16;
+/// How often senders start a new session
+pub const SESSION_ROTATION: Duration = Duration::from_secs(60);
+/// Oldest session start, relative to the receiver's clock, it accepts
+pub const MAX_SESSION_AGE: Duration = Duration::from_secs(300);
+
+/// A 32 byte pre-shared key, written as 64 hex characters
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key([u8; 32]);
+
+impl Key {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl FromStr for Key {
+    type Err = hex::FromHexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut key = [0; 32];
+        hex::decode_to_slice(s.trim(), &mut key)?;
+        Ok(Self(key))
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpenError {
+    TooShort,
+    BadMagic,
+    UnsupportedVersion(u8),
+    /// The datagram was not sealed with our key or was tampered with
+    BadTag,
+    /// The sequence number was already accepted or is too old
+    Replayed,
+    /// The datagram belongs to a session older than the current ones
+    /// or one that started more than `MAX_SESSION_AGE` ago
+    StaleSession,
+}
+
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OpenError::TooShort => write!(f, "datagram is truncated"),
+            OpenError::BadMagic => write!(f, "not a sealed datagram"),
+            OpenError::UnsupportedVersion(version) => {
+                write!(f, "unsupported sealed datagram version {version}")
+            }
+            OpenError::BadTag => write!(f, "authentication failed"),
+            OpenError::Replayed => write!(f, "replayed datagram"),
+            OpenError::StaleSession => write!(f, "datagram from a stale session"),
+        }
+    }
+}
+
+impl std::error::Error for OpenError {}
+
+impl From<AeadError> for OpenError {
+    fn from(_: AeadError) -> Self {
+        OpenError::BadTag
+    }
+}
+
+fn unix_now_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// Session start and id, the first 16 bytes of every nonce
+fn new_session() -> Result<[u8; 16], getrandom::Error> {
+    let mut session = [0; 16];
+    session[..8].copy_from_slice(&unix_now_ns().to_le_bytes());
+    getrandom::getrandom(&mut session[8..])?;
+    Ok(session)
+}
+
+fn session_start(session: &[u8; 16]) -> u64 {
+    u64::from_le_bytes(session[..8].try_into().unwrap())
+}
+
+/// Seals datagrams for one listener
+pub struct Sealer {
+    cipher: XChaCha20Poly1305,
+    /// The current session and when it started
+    session: Mutex<([u8; 16], Instant)>,
+}
+
+impl Sealer {
+    /// Starts a new session, failing only if the OS has no randomness to offer
+    pub fn new(key: &Key) -> Result<Self, getrandom::Error> {
+        Ok(Self {
+            cipher: XChaCha20Poly1305::new(&key.0.into()),
+            session: Mutex::new((new_session()?, Instant::now())),
+        })
+    }
+
+    /// The current session, replaced once it is `SESSION_ROTATION` old
+    fn session(&self) -> [u8; 16] {
+        let mut session = self.session.lock().unwrap();
+        if session.1.elapsed() >= SESSION_ROTATION {
+            // without randomness the old session is kept, receivers reject it once it is too old
+            if let Ok(next) = new_session() {
+                *session = (next, Instant::now());
+            }
+        }
+        session.0
+    }
+
+    /// Appends `payload` sealed with sequence number `seq` to `out`,
+    /// `seq` must never repeat within a session
+    pub fn seal(&self, seq: u64, payload: &[u8], out: &mut Vec<u8>) {
+        let start = out.len();
+        out.reserve(OVERHEAD + payload.len());
+        out.extend_from_slice(&MAGIC);
+        out.push(VERSION);
+        out.extend_from_slice(&[0; 3]);
+        out.extend_from_slice(&self.session());
+        out.extend_from_slice(&seq.to_le_bytes());
+        out.extend_from_slice(payload);
+
+        let (header, body) = out[start..].split_at_mut(HEADER_LEN);
+        let nonce = XNonce::clone_from_slice(&header[8..]);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(&nonce, header, body)
+            .expect("payload fits in a datagram");
+        out.extend_from_slice(&tag);
+    }
+}
+
+/// Words of the replay bitmap, one more than the window needs so that
+/// advancing never clears bits still inside the window
+const WINDOW_WORDS: usize = REPLAY_WINDOW as usize / 64 + 1;
+
+/// Tracks which sequence numbers of the current session were accepted
+///
+/// The bitmap is a ring indexed by sequence number, so advancing only
+/// clears the words skipped over instead of shifting the whole window
+struct ReplayWindow {
+    session: [u8; 16],
+    highest: u64,
+    seen: Box<[u64; WINDOW_WORDS]>,
+}
+
+fn bit(seq: u64) -> (usize, u64) {
+    ((seq / 64) as usize % WINDOW_WORDS, 1 << (seq % 64))
+}
+
+impl ReplayWindow {
+    fn new(session: [u8; 16], seq: u64) -> Self {
+        let mut window = Self {
+            session,
+            highest: seq,
+            seen: Box::new([0; WINDOW_WORDS]),
+        };
+        window.accept(seq);
+        window
+    }
+
+    fn check(&self, seq: u64) -> Result<(), OpenError> {
+        if seq > self.highest {
+            return Ok(());
+        }
+        let (word, mask) = bit(seq);
+        if self.highest - seq >= REPLAY_WINDOW || self.seen[word] & mask != 0 {
+            return Err(OpenError::Replayed);
+        }
+        Ok(())
+    }
+
+    fn accept(&mut self, seq: u64) {
+        if seq > self.highest {
+            let skipped = (seq / 64 - self.highest / 64).min(WINDOW_WORDS as u64);
+            for i in 1..=skipped {
+                self.seen[bit(self.highest + i * 64).0] = 0;
+            }
+            self.highest = seq;
+        }
+        let (word, mask) = bit(seq);
+        self.seen[word] |= mask;
+    }
+}
+
+/// Opens datagrams sealed by a `Sealer` with the same key
+pub struct Opener {
+    cipher: XChaCha20Poly1305,
+    window: Option<ReplayWindow>,
+    /// The session before the current one, which late and resent datagrams still belong to
+    previous: Option<ReplayWindow>,
+}
+
+impl Opener {
+    pub fn new(key: &Key) -> Self {
+        Self {
+            cipher: XChaCha20Poly1305::new(&key.0.into()),
+            window: None,
+            previous: None,
+        }
+    }
+
+    /// Authenticates and decrypts `datagram` in place, returning the payload
+    pub fn open<'a>(&mut self, datagram: &'a mut [u8]) -> Result<&'a [u8], OpenError> {
+        if datagram.len() < 5 {
+            return Err(OpenError::TooShort);
+        }
+        if datagram[..4] != MAGIC {
+            return Err(OpenError::BadMagic);
+        }
+        if datagram[4] != VERSION {
+            return Err(OpenError::UnsupportedVersion(datagram[4]));
+        }
+        if datagram.len() < OVERHEAD {
+            return Err(OpenError::TooShort);
+        }
+
+        let (header, rest) = datagram.split_at_mut(HEADER_LEN);
+        let session: [u8; 16] = header[8..24].try_into().unwrap();
+        let seq = u64::from_le_bytes(header[24..32].try_into().unwrap());
+        let oldest = unix_now_ns().saturating_sub(MAX_SESSION_AGE.as_nanos() as u64);
+        if session_start(&session) < oldest {
+            return Err(OpenError::StaleSession);
+        }
+        let target = match (&self.window, &self.previous) {
+            (Some(window), _) if window.session == session => {
+                window.check(seq)?;
+                Window::Current
+            }
+            (_, Some(previous)) if previous.session == session => {
+                previous.check(seq)?;
+                Window::Previous
+            }
+            (Some(window), _) if session_start(&session) <= session_start(&window.session) => {
+                return Err(OpenError::StaleSession);
+            }
+            _ => Window::New,
+        };
+
+        let (body, tag) = rest.split_at_mut(rest.len() - TAG_LEN);
+        let nonce = XNonce::clone_from_slice(&header[8..]);
+        self.cipher
+            .decrypt_in_place_detached(&nonce, header, body, Tag::from_slice(tag))?;
+
+        // only authenticated datagrams may move the windows
+        match target {
+            Window::Current => self.window.as_mut().unwrap().accept(seq),
+            Window::Previous => self.previous.as_mut().unwrap().accept(seq),
+            Window::New => {
+                self.previous = self.window.replace(ReplayWindow::new(session, seq));
+            }
+        }
+        Ok(body)
+    }
+}
+
+/// Which replay window an authenticated datagram is accepted into
+enum Window {
+    Current,
+    Previous,
+    New,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> Key {
+        Key::new([byte; 32])
+    }
+
+    fn sealed(sealer: &Sealer, seq: u64, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        sealer.seal(seq, payload, &mut out);
+        out
+    }
+
+    #[test]
+    fn round_trips() {
+        let sealer = Sealer::new(&key(1)).unwrap();
+        let mut opener = Opener::new(&key(1));
+        for seq in [0, 1, 5, 3] {
+            let payload = format!("shred {seq}");
+            let mut datagram = sealed(&sealer, seq, payload.as_bytes());
+            assert_eq!(datagram.len(), payload.len() + OVERHEAD);
+            assert_eq!(opener.open(&mut datagram), Ok(payload.as_bytes()));
+        }
+    }
+
+    #[test]
+    fn key_round_trips_as_hex() {
+        let key = key(0xab);
+        assert_eq!(key.to_string().parse::<Key>().unwrap(), key);
+        assert!("abcd".parse::<Key>().is_err());
+    }
+
+    #[test]
+    fn rejects_replays() {
+        let sealer = Sealer::new(&key(1)).unwrap();
+        let mut opener = Opener::new(&key(1));
+        let datagram = sealed(&sealer, 7, b"shred");
+        assert!(opener.open(&mut datagram.clone()).is_ok());
+        assert_eq!(opener.open(&mut datagram.clone()), Err(OpenError::Replayed));
+    }
+
+    #[test]
+    fn rejects_sequence_numbers_behind_the_window() {
+        let sealer = Sealer::new(&key(1)).unwrap();
+        let mut opener = Opener::new(&key(1));
+        let late = sealed(&sealer, 1, b"shred");
+        assert!(
+            opener
+                .open(&mut sealed(&sealer, REPLAY_WINDOW + 1, b"shred"))
+                .is_ok()
+        );
+        assert_eq!(opener.open(&mut late.clone()), Err(OpenError::Replayed));
+    }
+
+    #[test]
+    fn rejects_sessions_older_than_the_current_ones() {
+        // sessions are ordered by when they started
+        let mut sealers = (0..3).map(|_| {
+            std::thread::sleep(Duration::from_millis(1));
+            Sealer::new(&key(1)).unwrap()
+        });
+        let (first, second, third) = (
+            sealers.next().unwrap(),
+            sealers.next().unwrap(),
+            sealers.next().unwrap(),
+        );
+
+        // the previous session is still accepted after a new one started
+        let mut opener = Opener::new(&key(1));
+        assert!(opener.open(&mut sealed(&first, 1, b"shred")).is_ok());
+        assert!(opener.open(&mut sealed(&second, 1, b"shred")).is_ok());
+        assert!(opener.open(&mut sealed(&first, 2, b"shred")).is_ok());
+
+        assert!(opener.open(&mut sealed(&third, 1, b"shred")).is_ok());
+        assert_eq!(
+            opener.open(&mut sealed(&first, 3, b"shred")),
+            Err(OpenError::StaleSession)
+        );
+
+        // a session that started before the current one is never new
+        let mut opener = Opener::new(&key(1));
+        assert!(opener.open(&mut sealed(&second, 1, b"shred")).is_ok());
+        assert_eq!(
+            opener.open(&mut sealed(&first, 1, b"shred")),
+            Err(OpenError::StaleSession)
+        );
+    }
+
+    #[test]
+    fn rejects_sessions_past_their_age() {
+        let sealer = Sealer::new(&key(1)).unwrap();
+        let mut datagram = sealed(&sealer, 1, b"shred");
+        let start = unix_now_ns() - MAX_SESSION_AGE.as_nanos() as u64 - 1_000_000_000;
+        datagram[8..16].copy_from_slice(&start.to_le_bytes());
+        assert_eq!(
+            Opener::new(&key(1)).open(&mut datagram),
+            Err(OpenError::StaleSession)
+        );
+    }
+
+    #[test]
+    fn rejects_forged_datagrams() {
+        let sealer = Sealer::new(&key(1)).unwrap();
+        let datagram = sealed(&sealer, 1, b"shred");
+
+        assert_eq!(
+            Opener::new(&key(2)).open(&mut datagram.clone()),
+            Err(OpenError::BadTag)
+        );
+        for at in [HEADER_LEN - 1, HEADER_LEN, datagram.len() - 1] {
+            let mut tampered = datagram.clone();
+            tampered[at] ^= 1;
+            assert_eq!(
+                Opener::new(&key(1)).open(&mut tampered),
+                Err(OpenError::BadTag)
+            );
+        }
+
+        // forgeries don't move the replay window
+        let mut opener = Opener::new(&key(1));
+        let mut forged = sealed(&sealer, 2, b"shred");
+        *forged.last_mut().unwrap() ^= 1;
+        assert_eq!(opener.open(&mut forged), Err(OpenError::BadTag));
+        assert!(opener.open(&mut sealed(&sealer, 2, b"shred")).is_ok());
+    }
+
+    #[test]
+    fn rejects_malformed_datagrams() {
+        let sealer = Sealer::new(&key(1)).unwrap();
+        let datagram = sealed(&sealer, 1, b"shred");
+        let mut opener = Opener::new(&key(1));
+
+        assert_eq!(
+            opener.open(&mut datagram[..4].to_vec()),
+            Err(OpenError::TooShort)
+        );
+        assert_eq!(
+            opener.open(&mut datagram[..OVERHEAD - 1].to_vec()),
+            Err(OpenError::TooShort)
+        );
+        assert_eq!(
+            opener.open(&mut b"not a sealed datagram".to_vec()),
+            Err(OpenError::BadMagic)
+        );
+        let mut version = datagram.clone();
+        version[4] = VERSION + 1;
+        assert_eq!(
+            opener.open(&mut version),
+            Err(OpenError::UnsupportedVersion(VERSION + 1))
+        );
+    }
+}
@@ -0,0 +1,101 @@
+//! Reads shred headers in place, without deserializing the shred
+//!
+//! Based on https://github.com/anza-xyz/agave/blob/v3.0.9/ledger/src/shred/wire.rs
+
+const VARIANT_OFFSET: usize = 64;
+const SLOT_OFFSET: usize = 65;
+const INDEX_OFFSET: usize = 73;
+const FEC_SET_INDEX_OFFSET: usize = 79;
+const DATA_PARENT_OFFSET_OFFSET: usize = 83;
+const DATA_FLAGS_OFFSET: usize = 85;
+/// Data shred flags of the shred that ends its slot
+pub const LAST_SHRED_IN_SLOT: u8 = 0xc0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ShredKind {
+    Data,
+    Code,
+}
+
+/// What the variant byte of a shred tells
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Variant {
+    pub name: &'static str,
+    pub kind: ShredKind,
+    /// `None` for legacy shreds, which have no merkle proof
+    pub proof_size: Option<u8>,
+}
+
+/// The variant of a shred, `None` if it isn't one
+pub fn variant(shred: &[u8]) -> Option<Variant> {
+    let byte = *shred.get(VARIANT_OFFSET)?;
+    let (name, kind) = match byte {
+        0x5a => return Some(legacy("legacy_code", ShredKind::Code)),
+        0xa5 => return Some(legacy("legacy_data", ShredKind::Data)),
+        _ => match byte & 0xf0 {
+            0x40 => ("merkle_code", ShredKind::Code),
+            0x60 => ("chained_merkle_code", ShredKind::Code),
+            0x70 => ("chained_resigned_merkle_code", ShredKind::Code),
+            0x80 => ("merkle_data", ShredKind::Data),
+            0x90 => ("chained_merkle_data", ShredKind::Data),
+            0xb0 => ("chained_resigned_merkle_data", ShredKind::Data),
+            _ => return None,
+        },
+    };
+    Some(Variant {
+        name,
+        kind,
+        proof_size: Some(byte & 0x0f),
+    })
+}
+
+fn legacy(name: &'static str, kind: ShredKind) -> Variant {
+    Variant {
+        name,
+        kind,
+        proof_size: None,
+    }
+}
+
+/// The kind of a merkle shred, legacy shreds are no longer sent and get `None`
+pub fn merkle_kind(shred: &[u8]) -> Option<ShredKind> {
+    variant(shred)
+        .filter(|variant| variant.proof_size.is_some())
+        .map(|variant| variant.kind)
+}
+
+fn read<const N: usize>(shred: &[u8], offset: usize) -> Option<[u8; N]> {
+    shred.get(offset..offset + N)?.try_into().ok()
+}
+
+pub fn slot(shred: &[u8]) -> Option<u64> {
+    read(shred, SLOT_OFFSET).map(u64::from_le_bytes)
+}
+
+pub fn index(shred: &[u8]) -> Option<u32> {
+    read(shred, INDEX_OFFSET).map(u32::from_le_bytes)
+}
+
+pub fn fec_set_index(shred: &[u8]) -> Option<u32> {
+    read(shred, FEC_SET_INDEX_OFFSET).map(u32::from_le_bytes)
+}
+
+fn is_data(shred: &[u8]) -> bool {
+    variant(shred).is_some_and(|variant| variant.kind == ShredKind::Data)
+}
+
+/// How many slots the parent of a data shred's slot is behind it
+pub fn parent_offset(shred: &[u8]) -> Option<u16> {
+    if !is_data(shred) {
+        return None;
+    }
+    read(shred, DATA_PARENT_OFFSET_OFFSET).map(u16::from_le_bytes)
+}
+
+/// The flags of a data shred, see [`LAST_SHRED_IN_SLOT`]
+pub fn data_flags(shred: &[u8]) -> Option<u8> {
+    if !is_data(shred) {
+        return None;
+    }
+    shred.get(DATA_FLAGS_OFFSET).copied()
+}
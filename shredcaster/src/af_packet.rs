@@ -0,0 +1,469 @@
+use std::{collections::HashMap, io, mem, os::fd::AsRawFd};
+
+use futures::future::BoxFuture;
+use shredcaster_proto::pcap::parse_udp;
+use socket2::{Domain, SockFilter, Socket, Type};
+use tokio::io::unix::AsyncFd;
+
+use crate::{
+    CapturedPacket, PACKET_DATA_SIZE,
+    route::if_nametoindex,
+    source::{MAX_BATCH, PacketSource, shred_data, unix_now_ns},
+};
+
+// classic BPF, see linux/filter.h
+const BPF_LD: u16 = 0x00;
+const BPF_LDX: u16 = 0x01;
+const BPF_ALU: u16 = 0x04;
+const BPF_JMP: u16 = 0x05;
+const BPF_RET: u16 = 0x06;
+const BPF_W: u16 = 0x00;
+const BPF_H: u16 = 0x08;
+const BPF_B: u16 = 0x10;
+const BPF_IMM: u16 = 0x00;
+const BPF_ABS: u16 = 0x20;
+const BPF_IND: u16 = 0x40;
+const BPF_MSH: u16 = 0xa0;
+const BPF_RSH: u16 = 0x70;
+const BPF_JA: u16 = 0x00;
+const BPF_JEQ: u16 = 0x10;
+const BPF_JSET: u16 = 0x40;
+const SKF_AD_PKTTYPE: u32 = (-0x1000i32 + 4) as u32;
+
+/// Where a jump goes
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Label {
+    Next,
+    Ipv4,
+    Ipv6,
+    Ports,
+    Egress,
+    Accept,
+    Drop,
+}
+
+/// Assembles a classic BPF program with symbolic jump targets
+#[derive(Default)]
+struct Assembler {
+    insns: Vec<(u16, Label, Label, u32)>,
+    labels: HashMap<Label, usize>,
+}
+
+impl Assembler {
+    fn stmt(&mut self, code: u16, k: u32) {
+        self.insns.push((code, Label::Next, Label::Next, k));
+    }
+
+    fn jump(&mut self, code: u16, k: u32, jt: Label, jf: Label) {
+        self.insns.push((code, jt, jf, k));
+    }
+
+    fn label(&mut self, label: Label) {
+        self.labels.insert(label, self.insns.len());
+    }
+
+    fn finish(self) -> anyhow::Result<Vec<libc::sock_filter>> {
+        let offset = |at: usize, label: Label| -> anyhow::Result<u32> {
+            let target = match label {
+                Label::Next => at + 1,
+                label => self.labels[&label],
+            };
+            Ok((target - at - 1) as u32)
+        };
+        let jump_offset = |at: usize, label: Label| -> anyhow::Result<u8> {
+            u8::try_from(offset(at, label)?)
+                .map_err(|_| anyhow::anyhow!("too many ports for a packet filter"))
+        };
+        self.insns
+            .iter()
+            .enumerate()
+            .map(|(at, &(code, jt, jf, k))| {
+                if code == BPF_JMP | BPF_JA {
+                    return Ok(libc::sock_filter {
+                        code,
+                        jt: 0,
+                        jf: 0,
+                        k: offset(at, jt)?,
+                    });
+                }
+                Ok(libc::sock_filter {
+                    code,
+                    jt: jump_offset(at, jt)?,
+                    jf: jump_offset(at, jf)?,
+                    k,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Accepts UDP datagrams to `tvu_ports` and, if `egress` is set, outgoing ones,
+/// only from port `egress.unwrap()` if that is set too
+///
+/// Offsets are relative to the network header, as the socket is `SOCK_DGRAM`
+fn tvu_filter(
+    tvu_ports: &[u16],
+    egress: Option<Option<u16>>,
+) -> anyhow::Result<Vec<libc::sock_filter>> {
+    let mut asm = Assembler::default();
+    asm.stmt(BPF_LD | BPF_B | BPF_ABS, 0);
+    asm.stmt(BPF_ALU | BPF_RSH, 4);
+    asm.jump(BPF_JMP | BPF_JEQ, 4, Label::Ipv4, Label::Next);
+    asm.jump(BPF_JMP | BPF_JEQ, 6, Label::Ipv6, Label::Drop);
+
+    asm.label(Label::Ipv4);
+    asm.stmt(BPF_LD | BPF_B | BPF_ABS, 9);
+    asm.jump(
+        BPF_JMP | BPF_JEQ,
+        libc::IPPROTO_UDP as u32,
+        Label::Next,
+        Label::Drop,
+    );
+    // later fragments don't start with a UDP header
+    asm.stmt(BPF_LD | BPF_H | BPF_ABS, 6);
+    asm.jump(BPF_JMP | BPF_JSET, 0x1fff, Label::Drop, Label::Next);
+    // X = IPv4 header length
+    asm.stmt(BPF_LDX | BPF_B | BPF_MSH, 0);
+    asm.jump(BPF_JMP | BPF_JA, 0, Label::Ports, Label::Ports);
+
+    asm.label(Label::Ipv6);
+    asm.stmt(BPF_LD | BPF_B | BPF_ABS, 6);
+    asm.jump(
+        BPF_JMP | BPF_JEQ,
+        libc::IPPROTO_UDP as u32,
+        Label::Next,
+        Label::Drop,
+    );
+    asm.stmt(BPF_LDX | BPF_W | BPF_IMM, 40);
+
+    asm.label(Label::Ports);
+    asm.stmt(BPF_LD | BPF_W | BPF_ABS, SKF_AD_PKTTYPE);
+    asm.jump(
+        BPF_JMP | BPF_JEQ,
+        u32::from(libc::PACKET_OUTGOING),
+        Label::Egress,
+        Label::Next,
+    );
+    // destination port
+    asm.stmt(BPF_LD | BPF_H | BPF_IND, 2);
+    for port in tvu_ports {
+        asm.jump(
+            BPF_JMP | BPF_JEQ,
+            u32::from(*port),
+            Label::Accept,
+            Label::Next,
+        );
+    }
+    asm.jump(BPF_JMP | BPF_JA, 0, Label::Drop, Label::Drop);
+
+    asm.label(Label::Egress);
+    match egress {
+        None => asm.jump(BPF_JMP | BPF_JA, 0, Label::Drop, Label::Drop),
+        Some(None) => asm.jump(BPF_JMP | BPF_JA, 0, Label::Accept, Label::Accept),
+        Some(Some(port)) => {
+            // source port
+            asm.stmt(BPF_LD | BPF_H | BPF_IND, 0);
+            asm.jump(
+                BPF_JMP | BPF_JEQ,
+                u32::from(port),
+                Label::Accept,
+                Label::Drop,
+            );
+        }
+    }
+
+    asm.label(Label::Accept);
+    asm.stmt(BPF_RET, u32::MAX);
+    asm.label(Label::Drop);
+    asm.stmt(BPF_RET, 0);
+    asm.finish()
+}
+
+/// Packets copied off an interface by an `AF_PACKET` socket, which works
+/// without BPF program support but costs a copy per packet
+pub struct AfPacketSource {
+    socket: AsyncFd<Socket>,
+    buf: Vec<u8>,
+}
+
+impl AfPacketSource {
+    /// Captures UDP datagrams to `tvu_ports` on `iface`, and outgoing ones if
+    /// `egress` is set, only from port `egress.unwrap()` if that is set too
+    pub fn open(
+        iface: &str,
+        tvu_ports: &[u16],
+        egress: Option<Option<u16>>,
+    ) -> anyhow::Result<Self> {
+        if tvu_ports.is_empty() || tvu_ports.len() > 100 {
+            return Err(anyhow::anyhow!(
+                "must specify between 1 and 100 tvu ports to watch"
+            ));
+        }
+        let protocol = (libc::ETH_P_ALL as u16).to_be();
+        let socket = Socket::new(
+            Domain::PACKET,
+            Type::DGRAM,
+            Some(i32::from(protocol).into()),
+        )?;
+        // filter before binding, so no unfiltered packet is queued
+        let filter: Vec<_> = tvu_filter(tvu_ports, egress)?
+            .into_iter()
+            .map(|insn| SockFilter::new(insn.code, insn.jt, insn.jf, insn.k))
+            .collect();
+        socket.attach_filter(&filter)?;
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_protocol = protocol;
+        addr.sll_ifindex = if_nametoindex(iface)? as i32;
+        let bound = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if bound != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        socket.set_nonblocking(true)?;
+        for port in tvu_ports {
+            println!("started watching turbine on {port} through AF_PACKET");
+        }
+        Ok(Self {
+            socket: AsyncFd::new(socket)?,
+            // IP and UDP headers, plus one more byte than a shred to tell larger datagrams apart
+            buf: vec![0; 60 + 8 + PACKET_DATA_SIZE + 1],
+        })
+    }
+}
+
+/// Receives one packet into `buf`, returning its length and whether it was outgoing
+fn recv_packet(socket: &Socket, buf: &mut [u8]) -> io::Result<(usize, bool)> {
+    let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+    let mut addr_len = mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+    let len = unsafe {
+        libc::recvfrom(
+            socket.as_raw_fd(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            0,
+            &mut addr as *mut libc::sockaddr_ll as *mut libc::sockaddr,
+            &mut addr_len,
+        )
+    };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((len as usize, addr.sll_pkttype == libc::PACKET_OUTGOING))
+}
+
+impl PacketSource for AfPacketSource {
+    fn recv<'a>(
+        &'a mut self,
+        batch: &'a mut Vec<CapturedPacket>,
+    ) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            let mut guard = self.socket.readable().await?;
+            let ts_ns = unix_now_ns();
+            while batch.len() < MAX_BATCH {
+                let (len, is_egress) = match recv_packet(self.socket.get_ref(), &mut self.buf) {
+                    Ok(recv) => recv,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        guard.clear_ready();
+                        break;
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                let Some(datagram) = parse_udp(&self.buf[..len]) else {
+                    continue;
+                };
+                if let Some(data) = shred_data(datagram.payload) {
+                    batch.push(CapturedPacket {
+                        ts_ns,
+                        src: datagram.src,
+                        dst: Some(datagram.dst),
+                        is_egress,
+                        origin: None,
+                        data,
+                    });
+                }
+            }
+            Ok(true)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACKET_HOST: u32 = 0;
+    const PACKET_OUTGOING: u32 = libc::PACKET_OUTGOING as u32;
+
+    /// Runs `program` the way the kernel would, returning how many bytes it keeps
+    fn run(program: &[libc::sock_filter], packet: &[u8], pkttype: u32) -> u32 {
+        let load = |at: usize, len: usize| -> Option<u32> {
+            let bytes = packet.get(at..at + len)?;
+            Some(bytes.iter().fold(0, |acc, &b| acc << 8 | u32::from(b)))
+        };
+        let (mut a, mut x, mut pc) = (0u32, 0u32, 0);
+        loop {
+            let insn = program[pc];
+            pc += 1;
+            let size = match insn.code & 0x18 {
+                BPF_W => 4,
+                BPF_H => 2,
+                _ => 1,
+            };
+            match insn.code & 0x07 {
+                BPF_LD if insn.k == SKF_AD_PKTTYPE => a = pkttype,
+                BPF_LD => {
+                    let at = match insn.code & 0xe0 {
+                        BPF_ABS => insn.k as usize,
+                        BPF_IND => (x + insn.k) as usize,
+                        code => panic!("unexpected load mode {code:#x}"),
+                    };
+                    // the kernel drops packets read out of bounds
+                    let Some(value) = load(at, size) else {
+                        return 0;
+                    };
+                    a = value;
+                }
+                BPF_LDX => match insn.code & 0xe0 {
+                    BPF_IMM => x = insn.k,
+                    BPF_MSH => match load(insn.k as usize, 1) {
+                        Some(value) => x = (value & 0xf) * 4,
+                        None => return 0,
+                    },
+                    code => panic!("unexpected ldx mode {code:#x}"),
+                },
+                BPF_ALU => {
+                    assert_eq!(insn.code & 0xf0, BPF_RSH);
+                    a >>= insn.k;
+                }
+                BPF_JMP => {
+                    let taken = match insn.code & 0xf0 {
+                        BPF_JA => {
+                            pc += insn.k as usize;
+                            continue;
+                        }
+                        BPF_JEQ => a == insn.k,
+                        BPF_JSET => a & insn.k != 0,
+                        code => panic!("unexpected jump {code:#x}"),
+                    };
+                    pc += usize::from(if taken { insn.jt } else { insn.jf });
+                }
+                BPF_RET => return insn.k,
+                class => panic!("unexpected instruction class {class:#x}"),
+            }
+        }
+    }
+
+    fn ipv4(protocol: u8, fragment: u16, options: usize, sport: u16, dport: u16) -> Vec<u8> {
+        let mut packet = vec![0u8; 20 + options];
+        packet[0] = 0x40 | (5 + options / 4) as u8;
+        packet[6..8].copy_from_slice(&fragment.to_be_bytes());
+        packet[9] = protocol;
+        packet.extend_from_slice(&sport.to_be_bytes());
+        packet.extend_from_slice(&dport.to_be_bytes());
+        packet.extend_from_slice(&[0, 8, 0, 0]);
+        packet
+    }
+
+    fn ipv6(next_header: u8, sport: u16, dport: u16) -> Vec<u8> {
+        let mut packet = vec![0u8; 40];
+        packet[0] = 0x60;
+        packet[6] = next_header;
+        packet.extend_from_slice(&sport.to_be_bytes());
+        packet.extend_from_slice(&dport.to_be_bytes());
+        packet.extend_from_slice(&[0, 8, 0, 0]);
+        packet
+    }
+
+    const UDP: u8 = libc::IPPROTO_UDP as u8;
+    const TCP: u8 = libc::IPPROTO_TCP as u8;
+
+    #[test]
+    fn accepts_udp_to_tvu_ports_only() {
+        let program = tvu_filter(&[8001, 8002], None).unwrap();
+        let accepted = |packet: &[u8]| run(&program, packet, PACKET_HOST) != 0;
+
+        assert!(accepted(&ipv4(UDP, 0, 0, 9000, 8001)));
+        assert!(accepted(&ipv4(UDP, 0, 0, 9000, 8002)));
+        assert!(!accepted(&ipv4(UDP, 0, 0, 9000, 8003)));
+        assert!(!accepted(&ipv4(TCP, 0, 0, 9000, 8001)));
+        // the ports follow IPv4 options
+        assert!(accepted(&ipv4(UDP, 0, 8, 9000, 8001)));
+        assert!(!accepted(&ipv4(UDP, 0, 8, 8001, 9000)));
+
+        assert!(accepted(&ipv6(UDP, 9000, 8002)));
+        assert!(!accepted(&ipv6(UDP, 8002, 9000)));
+        assert!(!accepted(&ipv6(TCP, 9000, 8002)));
+
+        // neither IPv4 nor IPv6
+        let mut arp = ipv4(UDP, 0, 0, 9000, 8001);
+        arp[0] = 0x00;
+        assert!(!accepted(&arp));
+        assert!(!accepted(&[0x45, 0, 0]));
+    }
+
+    #[test]
+    fn drops_later_fragments() {
+        let program = tvu_filter(&[8001], None).unwrap();
+        // more fragments follow the first, which carries the UDP header
+        assert_ne!(
+            run(&program, &ipv4(UDP, 0x2000, 0, 9000, 8001), PACKET_HOST),
+            0
+        );
+        // a later fragment whose payload happens to look like port 8001
+        assert_eq!(
+            run(&program, &ipv4(UDP, 0x00b9, 0, 9000, 8001), PACKET_HOST),
+            0
+        );
+        assert_eq!(
+            run(&program, &ipv4(UDP, 0x2001, 0, 9000, 8001), PACKET_HOST),
+            0
+        );
+    }
+
+    #[test]
+    fn egress_follows_its_source_port() {
+        let sent = ipv4(UDP, 0, 0, 8001, 9000);
+        let sent_v6 = ipv6(UDP, 8001, 9000);
+        let other = ipv4(UDP, 0, 0, 8005, 9000);
+
+        let ingress_only = tvu_filter(&[8001], None).unwrap();
+        assert_eq!(run(&ingress_only, &sent, PACKET_OUTGOING), 0);
+        // outgoing packets are never matched by their destination
+        let to_tvu = ipv4(UDP, 0, 0, 9000, 8001);
+        assert_eq!(run(&ingress_only, &to_tvu, PACKET_OUTGOING), 0);
+
+        let any_port = tvu_filter(&[8001], Some(None)).unwrap();
+        assert_ne!(run(&any_port, &other, PACKET_OUTGOING), 0);
+        assert_eq!(
+            run(&any_port, &ipv4(TCP, 0, 0, 8001, 9000), PACKET_OUTGOING),
+            0
+        );
+
+        let one_port = tvu_filter(&[8001], Some(Some(8001))).unwrap();
+        assert_ne!(run(&one_port, &sent, PACKET_OUTGOING), 0);
+        assert_ne!(run(&one_port, &sent_v6, PACKET_OUTGOING), 0);
+        assert_eq!(run(&one_port, &other, PACKET_OUTGOING), 0);
+        assert_ne!(run(&one_port, &to_tvu, PACKET_HOST), 0);
+    }
+
+    #[test]
+    fn jumps_reach_past_a_hundred_ports() {
+        let ports: Vec<u16> = (8000..8100).collect();
+        let program = tvu_filter(&ports, Some(Some(8000))).unwrap();
+        for &port in &ports {
+            assert_ne!(run(&program, &ipv6(UDP, 1, port), PACKET_HOST), 0);
+        }
+        assert_eq!(run(&program, &ipv6(UDP, 1, 8100), PACKET_HOST), 0);
+        assert_ne!(run(&program, &ipv6(UDP, 8000, 1), PACKET_OUTGOING), 0);
+
+        // conditional jumps only reach 255 instructions ahead
+        let ports: Vec<u16> = (1..=300).collect();
+        assert!(tvu_filter(&ports, None).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use shredcaster_proto::{
+    archive::{self, Archive, Record, SegmentWriter},
+    envelope::Direction,
+};
+
+use crate::{
+    CapturedPacket,
+    capture::Origin,
+    sink::{BatchWriter, ThreadedSink},
+    source::{MAX_BATCH, PacketSource, shred_data, unix_now_ns},
+};
+
+/// Options of the `archive` sink
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ArchiveSinkOptions {
+    /// Directory the segments are written to
+    pub dir: PathBuf,
+    /// Starts a new segment once the current one has this many megabytes
+    #[serde(default = "default_segment_mb")]
+    pub segment_mb: u64,
+    /// Starts a new segment once the current one is this many seconds old,
+    /// segments can only be read once finished
+    #[serde(default = "default_segment_secs")]
+    pub segment_secs: u64,
+    /// Deletes the oldest segments once all of them take more megabytes
+    pub max_disk_mb: Option<u64>,
+    /// The zstd compression level
+    #[serde(default = "default_level")]
+    pub level: i32,
+}
+
+fn default_segment_mb() -> u64 {
+    256
+}
+
+fn default_segment_secs() -> u64 {
+    300
+}
+
+fn default_level() -> i32 {
+    3
+}
+
+impl ArchiveSinkOptions {
+    /// Starts the thread writing the segments
+    pub fn spawn(self) -> anyhow::Result<ThreadedSink> {
+        std::fs::create_dir_all(&self.dir)?;
+        println!("started archiving shreds to {}", self.dir.display());
+        Ok(ThreadedSink::spawn(
+            "archive",
+            ArchiveWriter {
+                options: self,
+                segment: None,
+            },
+        ))
+    }
+}
+
+/// Writes the captured shreds to segments of an archive directory
+struct ArchiveWriter {
+    options: ArchiveSinkOptions,
+    segment: Option<(SegmentWriter, Instant)>,
+}
+
+impl BatchWriter for ArchiveWriter {
+    fn write(&mut self, packets: &[CapturedPacket]) -> anyhow::Result<()> {
+        for packet in packets {
+            let (segment, _) = match &mut self.segment {
+                Some(segment) => segment,
+                None => {
+                    let path =
+                        self.options
+                            .dir
+                            .join(format!("{}.{}", unix_now_ns(), archive::EXTENSION));
+                    let segment = SegmentWriter::create(path, self.options.level)?;
+                    self.segment.insert((segment, Instant::now()))
+                }
+            };
+            segment.append(&record(packet))?;
+        }
+        if let Some((segment, _)) = &self.segment
+            && segment.len() >= self.options.segment_mb << 20
+        {
+            self.finish_segment()?;
+        }
+        self.tick(Instant::now())
+    }
+
+    /// When the current segment is old enough to be finished, also on a quiet feed
+    fn deadline(&self) -> Option<Instant> {
+        let (_, opened) = self.segment.as_ref()?;
+        Some(*opened + Duration::from_secs(self.options.segment_secs))
+    }
+
+    fn tick(&mut self, now: Instant) -> anyhow::Result<()> {
+        if self.deadline().is_some_and(|deadline| deadline <= now) {
+            self.finish_segment()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        self.finish_segment()
+    }
+}
+
+impl ArchiveWriter {
+    /// Finishes the current segment, which makes it readable, and enforces the disk budget
+    fn finish_segment(&mut self) -> anyhow::Result<()> {
+        let Some((segment, _)) = self.segment.take() else {
+            return Ok(());
+        };
+        segment.finish()?;
+
+        let Some(max_disk_mb) = self.options.max_disk_mb else {
+            return Ok(());
+        };
+        let mut segments = Vec::new();
+        for path in archive::segment_paths(&self.options.dir)? {
+            let len = std::fs::metadata(&path)?.len();
+            segments.push((path, len));
+        }
+        let mut total: u64 = segments.iter().map(|(_, len)| len).sum();
+        // the newest segment is kept even if it alone is over budget
+        for (path, len) in segments.iter().take(segments.len().saturating_sub(1)) {
+            if total <= max_disk_mb << 20 {
+                break;
+            }
+            match std::fs::remove_file(path) {
+                Ok(()) => total -= len,
+                Err(e) => eprintln!("failed to delete {}: {e}", path.display()),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn record(packet: &CapturedPacket) -> Record {
+    Record {
+        capture_ts_ns: packet.ts_ns,
+        direction: if packet.is_egress {
+            Direction::Egress
+        } else {
+            Direction::Ingress
+        },
+        src: packet.src,
+        dst: packet.dst,
+        origin: packet.origin.as_ref().map(|origin| archive::Origin {
+            host_id: origin.host_id,
+            iface: origin.iface.to_string(),
+        }),
+        shred: packet.data.as_ref().to_vec(),
+    }
+}
+
+/// The shreds of an archive directory, in the order they were captured and
+/// as fast as the sinks take them
+pub struct ArchiveSource {
+    archive: Archive,
+    segment: usize,
+    block: usize,
+    /// Records of the current block not yet handed over
+    records: std::vec::IntoIter<Record>,
+}
+
+impl ArchiveSource {
+    pub fn open(dir: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        let archive = Archive::open(&dir)?;
+        println!(
+            "started reading {} archive segments from {}",
+            archive.segments.len(),
+            dir.display()
+        );
+        Ok(Self {
+            archive,
+            segment: 0,
+            block: 0,
+            records: Vec::new().into_iter(),
+        })
+    }
+}
+
+impl PacketSource for ArchiveSource {
+    fn recv<'a>(
+        &'a mut self,
+        batch: &'a mut Vec<CapturedPacket>,
+    ) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            // reading never waits, give the other tasks a turn between batches
+            tokio::task::yield_now().await;
+            while batch.len() < MAX_BATCH {
+                let Some(record) = self.records.next() else {
+                    let Some(segment) = self.archive.segments.get(self.segment) else {
+                        return Ok(false);
+                    };
+                    if self.block == segment.blocks() {
+                        self.segment += 1;
+                        self.block = 0;
+                        continue;
+                    }
+                    self.records = segment.read_block(self.block)?.into_iter();
+                    self.block += 1;
+                    continue;
+                };
+                if let Some(data) = shred_data(&record.shred) {
+                    batch.push(CapturedPacket {
+                        ts_ns: record.capture_ts_ns,
+                        src: record.src,
+                        dst: record.dst,
+                        is_egress: record.direction == Direction::Egress,
+                        origin: record.origin.map(|origin| Origin {
+                            host_id: origin.host_id,
+                            iface: origin.iface.into(),
+                        }),
+                        data,
+                    });
+                }
+            }
+            Ok(true)
+        })
+    }
+}
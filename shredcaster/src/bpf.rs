@@ -0,0 +1,149 @@
+use std::net::{Ipv6Addr, SocketAddr};
+
+use arrayvec::ArrayVec;
+use aya::{
+    Ebpf, include_bytes_aligned,
+    maps::{Array, MapData, PerCpuValues, RingBuf},
+    programs::{SchedClassifier, TcAttachType, Xdp, XdpFlags, tc},
+    util::nr_cpus,
+};
+use futures::future::BoxFuture;
+use tokio::io::unix::AsyncFd;
+
+use crate::{
+    CapturedPacket, PACKET_DATA_SIZE, SharedPacketData, capture::realtime_offset_ns,
+    source::PacketSource,
+};
+
+/// Mirrors `PacketMeta` in turbine-ebpf-spy
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct PacketMeta {
+    ts_ns: u64,
+    src_addr: [u8; 16],
+    src_port: u16,
+    is_egress: bool,
+    dst_addr: [u8; 16],
+    dst_port: u16,
+}
+
+impl PacketMeta {
+    fn src(&self) -> SocketAddr {
+        SocketAddr::new(Ipv6Addr::from(self.src_addr).to_canonical(), self.src_port)
+    }
+
+    fn dst(&self) -> SocketAddr {
+        SocketAddr::new(Ipv6Addr::from(self.dst_addr).to_canonical(), self.dst_port)
+    }
+}
+
+/// Mirrors `PacketBuf` in turbine-ebpf-spy
+#[repr(C)]
+struct PacketBuf {
+    meta: PacketMeta,
+    data: ArrayVec<u8, PACKET_DATA_SIZE>,
+}
+
+fn load_tc_program(ebpf: &mut Ebpf, iface: &str) -> anyhow::Result<()> {
+    let program: &mut SchedClassifier = ebpf
+        .program_mut("tc_egress_probe")
+        .ok_or_else(|| anyhow::anyhow!("program not found"))?
+        .try_into()?;
+
+    _ = tc::qdisc_add_clsact(iface);
+    program.load()?;
+    program.attach(iface, TcAttachType::Egress)?;
+
+    Ok(())
+}
+
+/// Packets captured by the XDP and TC programs of turbine-ebpf-spy,
+/// which stay attached until the source is dropped
+pub struct BpfSource {
+    reader: AsyncFd<RingBuf<MapData>>,
+    /// Converts `bpf_ktime_get_ns` timestamps to unix time
+    realtime_offset_ns: u64,
+    _bpf: Ebpf,
+}
+
+impl BpfSource {
+    /// Watches turbine ingress on `tvu_ports` of `iface`, and egress on
+    /// `egress.0`, only from port `egress.1` if set
+    pub fn attach(
+        iface: &str,
+        tvu_ports: &[u16],
+        egress: Option<(&str, Option<u16>)>,
+    ) -> anyhow::Result<Self> {
+        if tvu_ports.is_empty() || tvu_ports.len() > 100 {
+            return Err(anyhow::anyhow!(
+                "must specify between 1 and 100 tvu ports to watch"
+            ));
+        }
+
+        let mut bpf = Ebpf::load(include_bytes_aligned!(concat!(
+            env!("OUT_DIR"),
+            "/turbine-ebpf-spy.o"
+        )))?;
+
+        let program: &mut Xdp = bpf
+            .program_mut("xdp_turbine_probe")
+            .ok_or_else(|| anyhow::anyhow!("program not found"))?
+            .try_into()?;
+        program.load()?;
+        program.attach(iface, XdpFlags::default())?;
+
+        if let Some((egress_iface, egress_port)) = egress {
+            load_tc_program(&mut bpf, egress_iface)?;
+            if let Some(egress_port) = egress_port {
+                let mut shred_egress_port_map =
+                    Array::try_from(bpf.map_mut("SHRED_EGRESS_PORT").unwrap())?;
+                shred_egress_port_map.set(0, egress_port, 0)?;
+                println!("started watching turbine egress on {egress_port}");
+            }
+        } else {
+            eprintln!("not watching turbine egress as disabled");
+        }
+
+        let nr_cpus = nr_cpus().map_err(|(_, error)| error)?;
+        let mut turbine_port_map =
+            aya::maps::PerCpuHashMap::<_, _, u8>::try_from(bpf.map_mut("TURBINE_PORTS").unwrap())?;
+        for port in tvu_ports.iter().copied() {
+            turbine_port_map.insert(port, PerCpuValues::try_from(vec![0; nr_cpus])?, 0)?;
+            println!("started watching turbine on {port}");
+        }
+
+        let turbine_packets = RingBuf::try_from(bpf.take_map("PACKET_BUF").unwrap())?;
+
+        Ok(Self {
+            reader: AsyncFd::new(turbine_packets)?,
+            realtime_offset_ns: realtime_offset_ns(),
+            _bpf: bpf,
+        })
+    }
+}
+
+impl PacketSource for BpfSource {
+    fn recv<'a>(
+        &'a mut self,
+        batch: &'a mut Vec<CapturedPacket>,
+    ) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            let mut guard = self.reader.readable_mut().await?;
+            let rb = guard.get_inner_mut();
+            while let Some(read) = rb.next() {
+                let ptr = read.as_ptr() as *const PacketBuf;
+                let PacketBuf { meta, data } = unsafe { core::ptr::read(ptr) };
+                batch.push(CapturedPacket {
+                    ts_ns: meta.ts_ns + self.realtime_offset_ns,
+                    src: meta.src(),
+                    dst: Some(meta.dst()),
+                    is_egress: meta.is_egress,
+                    origin: None,
+                    data: SharedPacketData::new(data),
+                });
+            }
+            guard.clear_ready();
+            Ok(true)
+        })
+    }
+}
@@ -0,0 +1,219 @@
+use std::{
+    net::SocketAddr,
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use shredcaster_proto::bundle::{self, BundleBuilder};
+
+/// Jumbo frames, a 1500 byte MTU only fits one shred per bundle
+const DEFAULT_MTU: usize = 9000;
+const DEFAULT_FLUSH: Duration = Duration::from_micros(1000);
+const UDP_HEADER_LEN: usize = 8;
+/// Most segments the kernel accepts in one GSO send
+const GSO_MAX_SEGMENTS: usize = 64;
+/// Stays below the largest UDP payload of both IPv4 and IPv6
+const GSO_MAX_BYTES: usize = 65000;
+
+/// How packets are combined for a listener
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BundleMode {
+    /// Length-prefixed packets in datagrams of up to `bundle_mtu`,
+    /// decoded with `shredcaster_proto::bundle`
+    Frames,
+    /// One UDP GSO send per batch, the listener receives ordinary datagrams
+    Gso,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct BundleOptions {
+    /// Combines packets sent to UDP listeners, one datagram per packet if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bundle: Option<BundleMode>,
+    /// Largest IP packet a bundle fills, 9000 if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bundle_mtu: Option<usize>,
+    /// Longest a packet waits for its bundle to fill, 1000 if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bundle_flush_us: Option<u64>,
+}
+
+impl BundleOptions {
+    /// Bytes of a bundle sent to `dst` left for frames, after the IP and UDP
+    /// headers and the `reserved` bytes of encryption and FEC
+    fn capacity(&self, dst: &SocketAddr, reserved: usize) -> usize {
+        let ip_header_len = match dst {
+            SocketAddr::V4(_) => 20,
+            SocketAddr::V6(_) => 40,
+        };
+        let overhead = ip_header_len + UDP_HEADER_LEN + reserved;
+        self.bundle_mtu
+            .unwrap_or(DEFAULT_MTU)
+            .saturating_sub(overhead)
+    }
+
+    /// Rejects a `bundle_mtu` too small for a packet of `packet_len` bytes,
+    /// and warns if it only fits one, since nothing is bundled then
+    pub fn check(
+        &self,
+        dst: &SocketAddr,
+        reserved: usize,
+        packet_len: usize,
+    ) -> anyhow::Result<()> {
+        if self.bundle != Some(BundleMode::Frames) {
+            return Ok(());
+        }
+        let capacity = self.capacity(dst, reserved);
+        let frame_len = bundle::FRAME_OVERHEAD + packet_len;
+        if bundle::HEADER_LEN + frame_len > capacity {
+            anyhow::bail!(
+                "bundle_mtu of {dst} leaves {capacity} bytes per bundle, too few for a {packet_len} byte packet"
+            );
+        }
+        if bundle::HEADER_LEN + 2 * frame_len > capacity {
+            eprintln!(
+                "bundle_mtu of {dst} only fits one packet per bundle, nothing will be bundled"
+            );
+        }
+        Ok(())
+    }
+}
+
+/// A batch of packets ready to be sent, along with their sequence numbers
+pub enum Flushed {
+    /// A bundle, to be sealed with the sequence number of its first packet
+    Frames { seqs: Range<u64>, data: Vec<u8> },
+    /// Packets of `segment` bytes, except for the last which may be shorter
+    Segments {
+        seqs: Range<u64>,
+        data: Vec<u8>,
+        segment: u16,
+    },
+}
+
+enum Batch {
+    Frames { builder: BundleBuilder },
+    Segments { data: Vec<u8>, segment: usize },
+}
+
+/// Collects the packets of a listener until its batch is full or due
+pub struct Bundler {
+    batch: Batch,
+    /// Sequence number of the first packet in the batch
+    seq: u64,
+    count: usize,
+    capacity: usize,
+    flush_after: Duration,
+    deadline: Option<Instant>,
+}
+
+impl Bundler {
+    /// `reserved` is what encryption and FEC add to every bundle
+    pub fn new(options: &BundleOptions, dst: &SocketAddr, reserved: usize) -> Option<Self> {
+        let flush_after = options
+            .bundle_flush_us
+            .map_or(DEFAULT_FLUSH, Duration::from_micros);
+        let (batch, capacity) = match options.bundle? {
+            BundleMode::Frames => {
+                let batch = Batch::Frames {
+                    builder: BundleBuilder::default(),
+                };
+                (batch, options.capacity(dst, reserved))
+            }
+            BundleMode::Gso => {
+                let batch = Batch::Segments {
+                    data: Vec::new(),
+                    segment: 0,
+                };
+                (batch, GSO_MAX_BYTES)
+            }
+        };
+
+        Some(Self {
+            batch,
+            seq: 0,
+            count: 0,
+            capacity,
+            flush_after,
+            deadline: None,
+        })
+    }
+
+    pub fn mode(&self) -> BundleMode {
+        match self.batch {
+            Batch::Frames { .. } => BundleMode::Frames,
+            Batch::Segments { .. } => BundleMode::Gso,
+        }
+    }
+
+    /// When the pending batch has to be sent
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Adds packet `seq`, returning the batch that has to be sent before it
+    /// or the batch it completed
+    pub fn push(&mut self, seq: u64, packet: &[u8], now: Instant) -> Option<Flushed> {
+        let full = match &self.batch {
+            Batch::Frames { builder, .. } => {
+                !builder.is_empty() && builder.len_with(packet.len()) > self.capacity
+            }
+            // only the last segment may be shorter than the others
+            Batch::Segments { data, segment } => {
+                !data.is_empty()
+                    && (packet.len() > *segment || data.len() + packet.len() > self.capacity)
+            }
+        };
+        let flushed = if full { self.take() } else { None };
+
+        if self.count == 0 {
+            self.seq = seq;
+        }
+        match &mut self.batch {
+            Batch::Frames { builder } => builder.push(packet),
+            Batch::Segments { data, segment } => {
+                if data.is_empty() {
+                    *segment = packet.len();
+                }
+                data.extend_from_slice(packet);
+            }
+        }
+        self.count += 1;
+        self.deadline.get_or_insert(now + self.flush_after);
+
+        let closed = match &self.batch {
+            Batch::Frames { .. } => false,
+            Batch::Segments { segment, .. } => {
+                packet.len() < *segment || self.count == GSO_MAX_SEGMENTS
+            }
+        };
+        if closed { self.take() } else { flushed }
+    }
+
+    /// Returns the pending batch if it is due
+    pub fn flush(&mut self, now: Instant) -> Option<Flushed> {
+        if self.deadline? > now {
+            return None;
+        }
+        self.take()
+    }
+
+    fn take(&mut self) -> Option<Flushed> {
+        self.deadline.take()?;
+        let seqs = self.seq..self.seq + std::mem::take(&mut self.count) as u64;
+        match &mut self.batch {
+            Batch::Frames { builder } => Some(Flushed::Frames {
+                seqs,
+                data: builder.finish(),
+            }),
+            Batch::Segments { data, segment } => Some(Flushed::Segments {
+                seqs,
+                data: std::mem::take(data),
+                segment: *segment as u16,
+            }),
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::{
+    ffi::CStr,
+    net::SocketAddr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use shredcaster_proto::envelope::{self, Envelope};
+
+use crate::{CapturedPacket, forwarder::ForwardData};
+
+/// How forwarded packets are laid out for a listener
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryMode {
+    /// The bare shred
+    #[default]
+    Raw,
+    /// The shred prefixed with a `shredcaster_proto::envelope` header
+    Envelope,
+}
+
+/// FNV-1a of the hostname, used as host id unless one is configured
+pub fn default_host_id() -> u64 {
+    let mut buf = [0 as libc::c_char; 256];
+    let hostname = if unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len()) } == 0 {
+        unsafe { CStr::from_ptr(buf.as_ptr()) }.to_bytes()
+    } else {
+        &[]
+    };
+    hostname.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn monotonic_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// Converts `bpf_ktime_get_ns` timestamps to unix time when added to them
+pub fn realtime_offset_ns() -> u64 {
+    let realtime_ns = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    realtime_ns.saturating_sub(monotonic_ns())
+}
+
+/// The host and interface an upstream shredcaster captured a relayed packet on
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Origin {
+    pub host_id: u64,
+    pub iface: Arc<str>,
+}
+
+/// What the envelope says about the capturing host
+pub struct CaptureContext {
+    host_id: u64,
+    iface: String,
+    egress_iface: String,
+}
+
+impl CaptureContext {
+    pub fn new(host_id: u64, iface: String, egress_iface: String) -> Self {
+        Self {
+            host_id,
+            iface,
+            egress_iface,
+        }
+    }
+
+    /// The host and interface a packet was captured on,
+    /// relayed packets keep those of the host that captured them
+    pub fn origin<'a>(&'a self, is_egress: bool, origin: Option<&'a Origin>) -> (u64, &'a str) {
+        match origin {
+            Some(origin) => (origin.host_id, &origin.iface),
+            None if is_egress => (self.host_id, &self.egress_iface),
+            None => (self.host_id, &self.iface),
+        }
+    }
+
+    pub fn capture<'a>(&'a self, packet: &'a CapturedPacket) -> Capture<'a> {
+        Capture {
+            context: self,
+            ts_ns: packet.ts_ns,
+            src: packet.src,
+            is_egress: packet.is_egress,
+            origin: packet.origin.as_ref(),
+        }
+    }
+}
+
+/// Where and when a packet was captured
+pub struct Capture<'a> {
+    context: &'a CaptureContext,
+    ts_ns: u64,
+    src: SocketAddr,
+    is_egress: bool,
+    origin: Option<&'a Origin>,
+}
+
+impl Capture<'_> {
+    /// The address the packet was originally sent from
+    pub fn src(&self) -> SocketAddr {
+        self.src
+    }
+
+    pub fn envelope(&self, seq: u64, shred: &[u8]) -> ForwardData {
+        let direction = if self.is_egress {
+            envelope::Direction::Egress
+        } else {
+            envelope::Direction::Ingress
+        };
+        let (host_id, iface) = self.context.origin(self.is_egress, self.origin);
+        let mut buf = Vec::with_capacity(envelope::HEADER_LEN + shred.len());
+        Envelope {
+            seq,
+            capture_ts_ns: self.ts_ns,
+            host_id,
+            direction,
+            src: self.src,
+            iface: iface.to_owned(),
+        }
+        .encode(shred, &mut buf);
+        ForwardData::Framed(Arc::from(buf))
+    }
+}
@@ -1,26 +1,333 @@
 use std::{
+    collections::HashSet,
+    fmt,
     net::SocketAddr,
     path::Path,
+    str::FromStr,
     sync::{Arc, RwLock},
 };
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use figment::{
     Figment,
     providers::{Format, Serialized, Toml},
 };
 use notify::Watcher;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error as _};
+use shredcaster_proto::{
+    envelope, fec,
+    secure::{self, Key},
+};
+
+use crate::{
+    PACKET_DATA_SIZE, bundle::BundleOptions, capture::DeliveryMode, fec::FecOptions,
+    filter::ListenerFilter, group::GroupOptions, inspect::InspectArgs, multicast::MulticastOptions,
+    rate_limit::RateLimit, relay::FeedConfig, sink::SinkConfig, source::SourceKind,
+    stream::StreamOptions,
+};
 
 const CONFIG_TOML: &str = "./config.toml";
 
+/// Where and how packets are delivered to a listener
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ListenerAddr {
+    /// Raw UDP, the default
+    Udp(SocketAddr),
+    /// Raw UDP to every address `host:port` resolves to
+    Host(String),
+    /// A single QUIC stream of framed shreds to `host:port`
+    Quic(String),
+    /// A TCP connection of framed shreds to `host:port`
+    Tcp(String),
+}
+
+impl ListenerAddr {
+    pub fn udp(&self) -> Option<SocketAddr> {
+        match self {
+            ListenerAddr::Udp(addr) => Some(*addr),
+            _ => None,
+        }
+    }
+
+    /// Whether packets are delivered over a QUIC or TCP stream
+    pub fn is_stream(&self) -> bool {
+        matches!(self, ListenerAddr::Quic(_) | ListenerAddr::Tcp(_))
+    }
+
+    /// A UDP socket address, or a hostname to resolve
+    fn udp_host(host_port: &str) -> anyhow::Result<Self> {
+        if let Ok(addr) = host_port.parse() {
+            return Ok(ListenerAddr::Udp(addr));
+        }
+        match host_port.rsplit_once(':') {
+            Some((host, port))
+                if !host.is_empty() && !host.contains(':') && port.parse::<u16>().is_ok() =>
+            {
+                Ok(ListenerAddr::Host(host_port.to_owned()))
+            }
+            _ => anyhow::bail!("invalid listener address {host_port}"),
+        }
+    }
+}
+
+impl FromStr for ListenerAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((scheme, host_port)) = s.split_once("://") else {
+            return ListenerAddr::udp_host(s);
+        };
+        if host_port.rsplit_once(':').is_none() {
+            anyhow::bail!("listener {s} is missing a port");
+        }
+        match scheme {
+            "udp" => ListenerAddr::udp_host(host_port),
+            "quic" => Ok(ListenerAddr::Quic(host_port.to_owned())),
+            "tcp" => Ok(ListenerAddr::Tcp(host_port.to_owned())),
+            _ => anyhow::bail!("unsupported listener scheme {scheme}://"),
+        }
+    }
+}
+
+impl fmt::Display for ListenerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenerAddr::Udp(addr) => write!(f, "{addr}"),
+            ListenerAddr::Host(host_port) => write!(f, "{host_port}"),
+            ListenerAddr::Quic(host_port) => write!(f, "quic://{host_port}"),
+            ListenerAddr::Tcp(host_port) => write!(f, "tcp://{host_port}"),
+        }
+    }
+}
+
+impl Serialize for ListenerAddr {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenerAddr {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        String::deserialize(d)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// A listener is either a bare address,
+/// or a table with an `addr` and delivery options
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ListenerEntry {
+    Addr(ListenerAddr),
+    Table(Box<ListenerTable>),
+}
+
+#[derive(Deserialize)]
+struct ListenerTable {
+    addr: ListenerAddr,
+    #[serde(flatten)]
+    filter: ListenerFilter,
+    #[serde(flatten)]
+    rate_limit: RateLimit,
+    #[serde(default)]
+    heartbeat: bool,
+    #[serde(default)]
+    health_check: bool,
+    #[serde(flatten)]
+    stream: StreamOptions,
+    #[serde(default)]
+    mode: DeliveryMode,
+    #[serde(default, deserialize_with = "deserialize_psk")]
+    psk: Option<Key>,
+    #[serde(flatten)]
+    bundle: BundleOptions,
+    #[serde(flatten)]
+    fec: FecOptions,
+    #[serde(default)]
+    nack_window_ms: Option<u64>,
+    #[serde(flatten)]
+    multicast: MulticastOptions,
+    #[serde(flatten)]
+    group: GroupOptions,
+    #[serde(default)]
+    transparent: bool,
+}
+
+pub fn deserialize_psk<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Key>, D::Error> {
+    let Some(psk) = Option::<String>::deserialize(d)? else {
+        return Ok(None);
+    };
+    psk.parse()
+        .map(Some)
+        .map_err(|e| D::Error::custom(format!("invalid psk: {e}")))
+}
+
+/// A single core, as configured before there could be several, or a list of them
+pub fn deserialize_cores<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<usize>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Cores {
+        One(usize),
+        Many(Vec<usize>),
+    }
+    let cores = match Cores::deserialize(d)? {
+        Cores::One(core) => vec![core],
+        Cores::Many(cores) => cores,
+    };
+    if cores.is_empty() {
+        return Err(D::Error::custom(
+            "tx_pinned_cpu_core needs at least one core",
+        ));
+    }
+    Ok(cores)
+}
+
+pub fn serialize_psk<S: Serializer>(psk: &Option<Key>, s: S) -> Result<S::Ok, S::Error> {
+    psk.as_ref().map(Key::to_string).serialize(s)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(from = "ListenerEntry")]
+pub struct ListenerConfig {
+    pub addr: ListenerAddr,
+    #[serde(flatten)]
+    pub filter: ListenerFilter,
+    #[serde(flatten)]
+    pub rate_limit: RateLimit,
+    /// Whether the listener echoes heartbeats back, which lets
+    /// shredcaster detect listeners that silently went away
+    pub heartbeat: bool,
+    /// Whether the listener is probed with empty datagrams and suspended
+    /// while the kernel reports it unreachable, implied by `heartbeat`
+    #[serde(skip_serializing_if = "is_false")]
+    pub health_check: bool,
+    /// Options of QUIC and TCP listeners
+    #[serde(flatten)]
+    pub stream: StreamOptions,
+    pub mode: DeliveryMode,
+    /// Hex encoded 32 byte key every packet is encrypted and authenticated with
+    #[serde(
+        serialize_with = "serialize_psk",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub psk: Option<Key>,
+    /// Options of UDP listeners receiving several packets at once
+    #[serde(flatten)]
+    pub bundle: BundleOptions,
+    /// Reed-Solomon parity sent to UDP listeners on lossy links
+    #[serde(flatten)]
+    pub fec: FecOptions,
+    /// How long sent packets are kept for retransmission, NACKs are ignored if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nack_window_ms: Option<u64>,
+    /// Options of UDP listeners that are multicast groups
+    #[serde(flatten)]
+    pub multicast: MulticastOptions,
+    /// Load balancing between the members of a listener group
+    #[serde(flatten)]
+    pub group: GroupOptions,
+    /// Whether packets keep the source address and port they were captured with,
+    /// only for networks that let spoofed sources through
+    #[serde(skip_serializing_if = "is_false")]
+    pub transparent: bool,
+}
+
+fn is_false(b: &bool) -> bool {
+    !b
+}
+
+impl ListenerConfig {
+    /// What encryption and FEC add to every datagram
+    pub fn reserved(&self) -> usize {
+        let sealed = if self.psk.is_some() {
+            secure::OVERHEAD
+        } else {
+            0
+        };
+        let fec = if self.fec.fec_parity.is_some() {
+            fec::OVERHEAD
+        } else {
+            0
+        };
+        sealed + fec
+    }
+
+    /// Largest packet sent to the listener, before encryption and FEC
+    fn max_packet_len(&self) -> usize {
+        match self.mode {
+            DeliveryMode::Raw => PACKET_DATA_SIZE,
+            DeliveryMode::Envelope => envelope::HEADER_LEN + PACKET_DATA_SIZE,
+        }
+    }
+}
+
+impl From<ListenerAddr> for ListenerConfig {
+    fn from(addr: ListenerAddr) -> Self {
+        Self {
+            addr,
+            filter: ListenerFilter::default(),
+            rate_limit: RateLimit::default(),
+            heartbeat: false,
+            health_check: false,
+            stream: StreamOptions::default(),
+            mode: DeliveryMode::default(),
+            psk: None,
+            bundle: BundleOptions::default(),
+            fec: FecOptions::default(),
+            nack_window_ms: None,
+            multicast: MulticastOptions::default(),
+            group: GroupOptions::default(),
+            transparent: false,
+        }
+    }
+}
+
+impl From<ListenerEntry> for ListenerConfig {
+    fn from(entry: ListenerEntry) -> Self {
+        match entry {
+            ListenerEntry::Addr(addr) => addr.into(),
+            ListenerEntry::Table(table) => Self {
+                addr: table.addr,
+                filter: table.filter,
+                rate_limit: table.rate_limit,
+                heartbeat: table.heartbeat,
+                health_check: table.health_check,
+                stream: table.stream,
+                mode: table.mode,
+                psk: table.psk,
+                bundle: table.bundle,
+                fec: table.fec,
+                nack_window_ms: table.nack_window_ms,
+                multicast: table.multicast,
+                group: table.group,
+                transparent: table.transparent,
+            },
+        }
+    }
+}
+
+impl FromStr for ListenerConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(s.parse::<ListenerAddr>()?.into())
+    }
+}
+
 pub enum MaybeSharedListeners {
-    Static(Arc<[SocketAddr]>),
-    Shared(Arc<RwLock<Arc<[SocketAddr]>>>),
+    Static(Arc<[ListenerConfig]>),
+    Shared(Arc<RwLock<Arc<[ListenerConfig]>>>),
+}
+
+impl From<Vec<ListenerConfig>> for MaybeSharedListeners {
+    fn from(listeners: Vec<ListenerConfig>) -> Self {
+        MaybeSharedListeners::Static(listeners.into())
+    }
 }
 
 impl MaybeSharedListeners {
-    pub fn get(&self) -> Arc<[SocketAddr]> {
+    pub fn get(&self) -> Arc<[ListenerConfig]> {
         match self {
             MaybeSharedListeners::Static(listeners) => listeners.clone(),
             MaybeSharedListeners::Shared(mutex) => mutex.read().unwrap().clone(),
@@ -31,31 +338,81 @@ impl MaybeSharedListeners {
 #[derive(Parser, Serialize, Deserialize, Clone)]
 pub struct Config {
     /// The TVU ports to monitor
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub tvu_ports: Vec<u16>,
-    /// The network interface to attach to
-    #[arg(short, long)]
-    pub iface: String,
+    /// The network interface to attach to, not needed by relays
+    #[arg(short, long, global = true)]
+    pub iface: Option<String>,
+    /// Where packets are captured from, `xdp` attaches the BPF programs,
+    /// `af_packet` copies them off the interface without BPF program support,
+    /// `udp` receives on the TVU ports like an ordinary socket,
+    /// `pcap:<path>` reads a pcap or pcapng file,
+    /// `archive:<dir>` the segments of an `archive` sink,
+    /// `relay` receives the `feeds` of upstream shredcasters,
+    /// `merge` only the first copy of every shred they deliver
+    #[arg(long, default_value_t, global = true, verbatim_doc_comment)]
+    pub source: SourceKind,
+    /// Where a relay or merge receives upstream shredcasters, `host:port` for UDP,
+    /// `quic://host:port` or `tcp://host:port` for framed shreds
+    /// in config.toml, feeds can also be tables with the options
+    /// of the upstream listener they are:
+    /// `{ addr = "quic://0.0.0.0:4433", mode = "envelope",
+    ///   cert = "cert.pem", private_key = "key.pem" }`
+    /// `mode = "envelope"` keeps the capture metadata across hops
+    /// `psk = "<64 hex chars>"` opens encrypted feeds, UDP, QUIC and TCP alike
+    #[arg(long, global = true, verbatim_doc_comment)]
+    pub feeds: Vec<FeedConfig>,
     /// The egress interface to attach to (if different from ingress)
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub egress_iface: Option<String>,
-    /// A list of UDP listeners to forward packets to
-    #[arg(short, long)]
-    pub listeners: Vec<SocketAddr>,
+    /// The network interface to forward packets from
+    /// if unset, each listener is forwarded through the interface
+    /// the routing table resolves it to
+    #[arg(long, verbatim_doc_comment)]
+    pub forward_iface: Option<String>,
+    /// A list of listeners to forward packets to, either UDP `host:port`
+    /// or `quic://host:port` / `tcp://host:port` for framed reliable delivery
+    /// UDP hostnames are resolved at startup and again as their records expire
+    /// in config.toml, listeners can also be tables with delivery filters:
+    /// `{ addr = "1.2.3.4:5000", direction = "ingress", shred_type = "data",
+    ///   slot_modulo = 4, slot_remainder = 0, sample_rate = 0.1,
+    ///   source_cidrs = ["10.0.0.0/8"], leaders = ["<pubkey>"],
+    ///   max_pps = 10000, max_mbps = 100, heartbeat = true }`
+    /// `mode = "envelope"` prefixes every shred with a metadata header
+    /// `psk = "<64 hex chars>"` encrypts and authenticates every packet
+    /// `bundle = "frames"` or `"gso"` combines packets, with `bundle_mtu`
+    /// and `bundle_flush_us` bounding their size and delay
+    /// `fec_parity = 4` adds parity packets to every block of `fec_data`
+    /// packets, closing blocks after `fec_flush_us`
+    /// `nack_window_ms = 500` keeps packets for retransmission on request
+    /// multicast groups take `multicast_ttl` and `multicast_iface`
+    /// `group = "workers"` splits packets between the listeners sharing it,
+    /// `balance = "slot"` or `"fec_set"` picks what stays together
+    /// `transparent = true` keeps the original source address of packets
+    /// QUIC and TCP listeners additionally take `buffer`, `on_lag`,
+    /// `ca_cert` and `server_name`
+    #[arg(short, long, verbatim_doc_comment)]
+    pub listeners: Vec<ListenerConfig>,
     /// The port to use for forwarding packets
     #[arg(short, long, default_value_t = 9122)]
     pub forwarder_port: u16,
     /// Whether to watch turbine egress traffic (experimental)
-    #[arg(short, long, default_value_t = false)]
+    #[arg(short, long, default_value_t = false, global = true)]
     pub watch_egress: bool,
     /// Egress port to filter on, if known
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub egress_port: Option<u16>,
-    /// The CPU core to pin the TX thread to  
-    /// IMPORTANT: This must not live on a CPU Heavy Core (e.g PoH core 0)  
+    /// Forward to IPv4 listeners through kernel sockets instead of AF_XDP,
+    /// which works without root, relays always do
+    #[arg(long, default_value_t = false, verbatim_doc_comment)]
+    pub kernel_tx: bool,
+    /// The CPU cores to pin the TX threads to, one per interface forwarded
+    /// through with AF_XDP, e.g. 2,3  
+    /// IMPORTANT: These must not live on a CPU Heavy Core (e.g PoH core 0)  
     /// the default is 2 to ensure maximal compatibility
-    #[arg(long, default_value_t = 2, verbatim_doc_comment)]
-    pub tx_pinned_cpu_core: usize,
+    #[arg(long, value_delimiter = ',', default_values_t = [2], verbatim_doc_comment)]
+    #[serde(deserialize_with = "deserialize_cores")]
+    pub tx_pinned_cpu_core: Vec<usize>,
     /// Optional path to a PEM encoded TLS certificate for WebTransport
     /// enables webtransport server if set
     /// webtransport_private_key must also be set
@@ -70,15 +427,83 @@ pub struct Config {
     pub webtransport_auth_token: Option<String>,
     #[arg(long, default_value_t = 4433)]
     pub webtransport_port: u16,
+    /// Optional RPC endpoint used to fetch the leader schedule
+    /// required by listeners filtering on `leaders`
+    #[arg(long, verbatim_doc_comment)]
+    pub rpc_url: Option<String>,
+    /// Maximum packets per second forwarded to all listeners combined
+    #[arg(long)]
+    pub global_max_pps: Option<f64>,
+    /// Maximum megabits per second forwarded to all listeners combined
+    #[arg(long)]
+    pub global_max_mbps: Option<f64>,
+    /// Don't probe the listeners opting into health checks or heartbeats
+    #[arg(long, default_value_t = false)]
+    pub disable_health_checks: bool,
+    /// Identifies this instance in envelopes, derived from the hostname if unset
+    #[arg(long)]
+    pub host_id: Option<u64>,
+    /// Optional address to serve prometheus metrics on, e.g. 127.0.0.1:9100
+    #[arg(long)]
+    pub metrics_addr: Option<SocketAddr>,
+    /// Optional address listeners with a `nack_window_ms` send NACKs to
+    #[arg(long)]
+    pub nack_addr: Option<SocketAddr>,
+    /// Longest the addresses of UDP listener hostnames are used before
+    /// they are resolved again, even if their records live longer
+    #[arg(long, default_value_t = 30, verbatim_doc_comment)]
+    pub dns_refresh_secs: u64,
+    /// Shortest the addresses of UDP listener hostnames are used for,
+    /// also how long a failed lookup waits before it is retried
+    #[arg(long, default_value_t = 5, verbatim_doc_comment)]
+    pub dns_min_ttl_secs: u64,
+    /// Additional outputs, only set in config.toml as `[[sinks]]` tables
+    /// naming the `sink` next to its options
+    #[arg(skip)]
+    #[serde(default)]
+    pub sinks: Vec<SinkConfig>,
+    #[command(subcommand)]
+    #[serde(skip)]
+    pub command: Option<Command>,
+}
+
+/// What shredcaster does with the captured packets instead of forwarding them
+#[derive(Subcommand, Clone, Debug)]
+pub enum Command {
+    /// Prints every captured shred, like tcpdump, captures from `--source`
+    /// with the same options as forwarding
+    #[command(verbatim_doc_comment)]
+    Inspect(InspectArgs),
+}
+
+/// Rejects listeners sharing an address, they would share their sequence
+/// numbers, keys, rate limits and counters, and bundles that can't fit a shred
+pub fn check_listeners(listeners: &[ListenerConfig]) -> anyhow::Result<()> {
+    let mut addrs = HashSet::new();
+    for listener in listeners {
+        if !addrs.insert(&listener.addr) {
+            anyhow::bail!("listener {} is configured more than once", listener.addr);
+        }
+        if let Some(addr) = listener.addr.udp() {
+            listener
+                .bundle
+                .check(&addr, listener.reserved(), listener.max_packet_len())?;
+        }
+    }
+    Ok(())
 }
 
 impl Config {
     pub fn load() -> anyhow::Result<Self> {
-        Figment::new()
-            .merge(Serialized::defaults(Self::parse()))
+        let args = Self::parse();
+        // subcommands are only taken from the command line
+        let command = args.command.clone();
+        let config: Self = Figment::new()
+            .merge(Serialized::defaults(args))
             .merge(Toml::file(CONFIG_TOML))
-            .extract()
-            .map_err(|e| e.into())
+            .extract()?;
+        check_listeners(&config.listeners)?;
+        Ok(Self { command, ..config })
     }
 
     pub fn spawn_config_listener(
@@ -108,6 +533,10 @@ impl Config {
                     else {
                         return;
                     };
+                    if let Err(e) = check_listeners(&new_config.listeners) {
+                        eprintln!("ignoring config update: {e}");
+                        return;
+                    }
                     println!(
                         "config was updated, updating listeners to: {:?}",
                         new_config.listeners
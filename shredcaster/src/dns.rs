@@ -0,0 +1,157 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use hickory_resolver::TokioAsyncResolver;
+use tokio::task::JoinHandle;
+
+use crate::config::{ListenerAddr, ListenerConfig, MaybeSharedListeners};
+
+/// How often the configured listeners are checked for changes and due lookups
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+struct Host {
+    addrs: Vec<SocketAddr>,
+    next_lookup: Instant,
+}
+
+/// Replaces listeners named by hostname with UDP listeners of the addresses they resolve to
+struct ListenerResolver {
+    resolver: TokioAsyncResolver,
+    /// Longest a lookup is used for, even if its records live longer
+    refresh: Duration,
+    /// Shortest a lookup is used for, also the retry delay of failed lookups
+    min_ttl: Duration,
+    hosts: HashMap<String, Host>,
+}
+
+impl ListenerResolver {
+    fn is_due(&self, now: Instant) -> bool {
+        self.hosts.values().any(|host| host.next_lookup <= now)
+    }
+
+    /// Looks up `host_port`, keeping the last known good addresses if that fails
+    async fn lookup(&mut self, host_port: &str, now: Instant) {
+        let Some((host, port)) = host_port
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+        else {
+            return;
+        };
+        let lookup = self.resolver.lookup_ip(host).await;
+        let entry = self.hosts.entry(host_port.to_owned()).or_insert(Host {
+            addrs: Vec::new(),
+            next_lookup: now,
+        });
+        match lookup {
+            Ok(lookup) => {
+                let mut addrs: Vec<_> = lookup
+                    .iter()
+                    .map(|ip| SocketAddr::new(ip.to_canonical(), port))
+                    .collect();
+                addrs.sort();
+                addrs.dedup();
+                if addrs != entry.addrs {
+                    println!("listener {host_port} resolved to {addrs:?}");
+                    entry.addrs = addrs;
+                }
+                let ttl = lookup.valid_until().saturating_duration_since(now);
+                entry.next_lookup = now + ttl.min(self.refresh).max(self.min_ttl);
+            }
+            Err(e) => {
+                eprintln!(
+                    "failed to resolve listener {host_port}, keeping {:?}: {e}",
+                    entry.addrs
+                );
+                entry.next_lookup = now + self.min_ttl;
+            }
+        }
+    }
+
+    /// Looks up the hosts that are due, returning `listeners` with every
+    /// hostname replaced by the addresses it resolved to
+    async fn resolve(&mut self, listeners: &[ListenerConfig]) -> Vec<ListenerConfig> {
+        let now = Instant::now();
+        self.hosts.retain(|host_port, _| {
+            listeners
+                .iter()
+                .any(|listener| matches!(&listener.addr, ListenerAddr::Host(h) if h == host_port))
+        });
+        for listener in listeners {
+            if let ListenerAddr::Host(host_port) = &listener.addr
+                && self
+                    .hosts
+                    .get(host_port)
+                    .is_none_or(|host| host.next_lookup <= now)
+            {
+                self.lookup(host_port, now).await;
+            }
+        }
+
+        let mut resolved = Vec::with_capacity(listeners.len());
+        for listener in listeners {
+            let ListenerAddr::Host(host_port) = &listener.addr else {
+                resolved.push(listener.clone());
+                continue;
+            };
+            let addrs = self
+                .hosts
+                .get(host_port)
+                .map_or(&[][..], |host| &host.addrs);
+            resolved.extend(addrs.iter().map(|addr| ListenerConfig {
+                addr: ListenerAddr::Udp(*addr),
+                ..listener.clone()
+            }));
+        }
+        resolved
+    }
+}
+
+/// Resolves the hostname listeners of `listeners` and keeps re-resolving them,
+/// the returned listeners only hold addresses and change when a lookup does
+pub async fn spawn_listener_resolver(
+    listeners: MaybeSharedListeners,
+    refresh: Duration,
+    min_ttl: Duration,
+) -> anyhow::Result<(MaybeSharedListeners, Option<JoinHandle<()>>)> {
+    if let MaybeSharedListeners::Static(configured) = &listeners
+        && !configured
+            .iter()
+            .any(|listener| matches!(listener.addr, ListenerAddr::Host(_)))
+    {
+        return Ok((listeners, None));
+    }
+
+    let mut resolver = ListenerResolver {
+        resolver: TokioAsyncResolver::tokio_from_system_conf()?,
+        refresh,
+        min_ttl,
+        hosts: HashMap::new(),
+    };
+    let mut configured = listeners.get();
+    let resolved: Arc<[ListenerConfig]> = resolver.resolve(&configured).await.into();
+    let resolved = Arc::new(RwLock::new(resolved));
+    let shared = resolved.clone();
+    let handle = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            let latest = listeners.get();
+            let changed = !Arc::ptr_eq(&latest, &configured);
+            if !changed && !resolver.is_due(Instant::now()) {
+                continue;
+            }
+            configured = latest;
+            let updated = resolver.resolve(&configured).await;
+            // the forwarder reconciles whenever the listeners are swapped
+            if *updated != **resolved.read().unwrap() {
+                *resolved.write().unwrap() = updated.into();
+            }
+        }
+    });
+
+    Ok((MaybeSharedListeners::Shared(shared), Some(handle)))
+}
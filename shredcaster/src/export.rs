@@ -0,0 +1,334 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use arrow_array::{
+    RecordBatch,
+    builder::{
+        StringBuilder, TimestampNanosecondBuilder, UInt16Builder, UInt32Builder, UInt64Builder,
+    },
+};
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef, TimeUnit};
+use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
+use serde::{Deserialize, Serialize};
+use shredcaster_proto::shred;
+use solana_ledger::shred::layout;
+
+use crate::{
+    CapturedPacket,
+    capture::CaptureContext,
+    filter::ShredKind,
+    sink::{BatchWriter, ThreadedSink, delete_oldest},
+    source::unix_now_ns,
+};
+
+/// Suffix of the files still being written
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// What the `export` sink writes
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Parquet,
+    Csv,
+}
+
+impl ExportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Parquet => "parquet",
+            ExportFormat::Csv => "csv",
+        }
+    }
+}
+
+/// Options of the `export` sink
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ExportSinkOptions {
+    /// Directory the files are written to
+    pub dir: PathBuf,
+    /// `parquet`, the default, or `csv`
+    #[serde(default)]
+    pub format: ExportFormat,
+    /// Files are named `<prefix>-<unix time in ns>.parquet` or `.csv`
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// Starts a new file once the current one is this many seconds old,
+    /// files only get their name once finished
+    #[serde(default = "default_rotate_secs")]
+    pub rotate_secs: u64,
+    /// Starts a new file once the current one has this many rows
+    pub max_rows: Option<u64>,
+    /// Deletes the oldest files with the prefix beyond this many
+    pub max_files: Option<usize>,
+}
+
+fn default_prefix() -> String {
+    "shreds".to_owned()
+}
+
+fn default_rotate_secs() -> u64 {
+    3600
+}
+
+impl ExportSinkOptions {
+    /// Starts the thread writing the files
+    pub fn spawn(self) -> anyhow::Result<ThreadedSink> {
+        std::fs::create_dir_all(&self.dir)?;
+        println!("started exporting shred metadata to {}", self.dir.display());
+        Ok(ThreadedSink::spawn(
+            "export",
+            ExportWriter {
+                options: self,
+                schema: schema(),
+                context: None,
+                file: None,
+            },
+        ))
+    }
+}
+
+/// One row per shred, packets that aren't shreds are skipped
+fn schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new(
+            "capture_ts",
+            DataType::Timestamp(TimeUnit::Nanosecond, Some("+00:00".into())),
+            false,
+        ),
+        Field::new("slot", DataType::UInt64, false),
+        Field::new("index", DataType::UInt32, false),
+        Field::new("fec_set", DataType::UInt32, false),
+        Field::new("shred_type", DataType::Utf8, false),
+        Field::new("direction", DataType::Utf8, false),
+        Field::new("src_ip", DataType::Utf8, false),
+        Field::new("src_port", DataType::UInt16, false),
+        // unknown for local shreds if the sink isn't attached to a capture
+        Field::new("host_id", DataType::UInt64, true),
+        Field::new("iface", DataType::Utf8, true),
+    ]))
+}
+
+fn record_batch(
+    schema: &SchemaRef,
+    context: Option<&CaptureContext>,
+    packets: &[CapturedPacket],
+) -> Result<RecordBatch, ArrowError> {
+    let mut capture_ts =
+        TimestampNanosecondBuilder::with_capacity(packets.len()).with_timezone("+00:00");
+    let mut slots = UInt64Builder::with_capacity(packets.len());
+    let mut indexes = UInt32Builder::with_capacity(packets.len());
+    let mut fec_sets = UInt32Builder::with_capacity(packets.len());
+    let mut shred_types = StringBuilder::new();
+    let mut directions = StringBuilder::new();
+    let mut src_ips = StringBuilder::new();
+    let mut src_ports = UInt16Builder::with_capacity(packets.len());
+    let mut host_ids = UInt64Builder::with_capacity(packets.len());
+    let mut ifaces = StringBuilder::new();
+    for packet in packets {
+        let shred = packet.data.as_ref();
+        let (Some(kind), Some(slot), Some(index), Some(fec_set)) = (
+            ShredKind::from_shred(shred),
+            layout::get_slot(shred),
+            layout::get_index(shred),
+            shred::fec_set_index(shred),
+        ) else {
+            continue;
+        };
+        capture_ts.append_value(packet.ts_ns as i64);
+        slots.append_value(slot);
+        indexes.append_value(index);
+        fec_sets.append_value(fec_set);
+        shred_types.append_value(match kind {
+            ShredKind::Data => "data",
+            ShredKind::Code => "code",
+        });
+        directions.append_value(if packet.is_egress {
+            "egress"
+        } else {
+            "ingress"
+        });
+        src_ips.append_value(packet.src.ip().to_string());
+        src_ports.append_value(packet.src.port());
+        let origin = match context {
+            Some(context) => Some(context.origin(packet.is_egress, packet.origin.as_ref())),
+            None => packet
+                .origin
+                .as_ref()
+                .map(|origin| (origin.host_id, &*origin.iface)),
+        };
+        host_ids.append_option(origin.map(|(host_id, _)| host_id));
+        // sources that aren't interfaces, e.g. files, have no name for it
+        ifaces.append_option(
+            origin
+                .map(|(_, iface)| iface)
+                .filter(|iface| !iface.is_empty()),
+        );
+    }
+    RecordBatch::try_new(
+        schema.clone(),
+        vec![
+            Arc::new(capture_ts.finish()),
+            Arc::new(slots.finish()),
+            Arc::new(indexes.finish()),
+            Arc::new(fec_sets.finish()),
+            Arc::new(shred_types.finish()),
+            Arc::new(directions.finish()),
+            Arc::new(src_ips.finish()),
+            Arc::new(src_ports.finish()),
+            Arc::new(host_ids.finish()),
+            Arc::new(ifaces.finish()),
+        ],
+    )
+}
+
+enum FileWriter {
+    Parquet(Box<ArrowWriter<File>>),
+    Csv(Box<arrow_csv::Writer<BufWriter<File>>>),
+}
+
+/// The file being written, renamed to `path` once finished
+struct ExportFile {
+    writer: FileWriter,
+    path: PathBuf,
+    partial_path: PathBuf,
+    rows: u64,
+    opened: Instant,
+}
+
+impl ExportFile {
+    fn finish(self) -> anyhow::Result<()> {
+        match self.writer {
+            FileWriter::Parquet(writer) => {
+                writer.close()?;
+            }
+            FileWriter::Csv(writer) => {
+                writer.into_inner().flush()?;
+            }
+        }
+        std::fs::rename(&self.partial_path, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Writes the metadata of the captured shreds to rotating Parquet or CSV files
+struct ExportWriter {
+    options: ExportSinkOptions,
+    schema: SchemaRef,
+    /// Where local shreds are captured
+    context: Option<Arc<CaptureContext>>,
+    file: Option<ExportFile>,
+}
+
+impl BatchWriter for ExportWriter {
+    fn attach(&mut self, context: Arc<CaptureContext>) {
+        self.context = Some(context);
+    }
+
+    fn write(&mut self, packets: &[CapturedPacket]) -> anyhow::Result<()> {
+        let batch = record_batch(&self.schema, self.context.as_deref(), packets)?;
+        if batch.num_rows() == 0 {
+            return Ok(());
+        }
+        if self.file.as_ref().is_none_or(|file| self.is_full(file)) {
+            self.rotate()?;
+        }
+        let Some(file) = &mut self.file else {
+            unreachable!("rotating opens a file");
+        };
+        match &mut file.writer {
+            FileWriter::Parquet(writer) => writer.write(&batch)?,
+            FileWriter::Csv(writer) => writer.write(&batch)?,
+        }
+        file.rows += batch.num_rows() as u64;
+        Ok(())
+    }
+
+    /// When the current file is old enough to be finished, also on a quiet feed
+    fn deadline(&self) -> Option<Instant> {
+        let file = self.file.as_ref()?;
+        Some(file.opened + Duration::from_secs(self.options.rotate_secs))
+    }
+
+    /// Finishes the current file once it is due, the next rows start another
+    fn tick(&mut self, now: Instant) -> anyhow::Result<()> {
+        if self.deadline().is_some_and(|deadline| deadline <= now) {
+            self.finish()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> anyhow::Result<()> {
+        match self.file.take() {
+            Some(file) => file.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl ExportWriter {
+    fn is_full(&self, file: &ExportFile) -> bool {
+        self.options
+            .max_rows
+            .is_some_and(|max_rows| file.rows >= max_rows)
+            || self
+                .deadline()
+                .is_some_and(|deadline| deadline <= Instant::now())
+    }
+
+    /// Finishes the current file, opens the next and deletes the oldest beyond `max_files`
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        if let Some(file) = self.file.take() {
+            file.finish()?;
+        }
+        let extension = self.options.format.extension();
+        let path = self.options.dir.join(format!(
+            "{}-{}.{extension}",
+            self.options.prefix,
+            unix_now_ns()
+        ));
+        let mut partial_path = path.clone().into_os_string();
+        partial_path.push(PARTIAL_SUFFIX);
+        let partial_path = PathBuf::from(partial_path);
+        let out = File::create_new(&partial_path)?;
+        let writer = match self.options.format {
+            ExportFormat::Parquet => {
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::ZSTD(Default::default()))
+                    .build();
+                FileWriter::Parquet(Box::new(ArrowWriter::try_new(
+                    out,
+                    self.schema.clone(),
+                    Some(properties),
+                )?))
+            }
+            ExportFormat::Csv => {
+                FileWriter::Csv(Box::new(arrow_csv::Writer::new(BufWriter::new(out))))
+            }
+        };
+        println!("started writing shred metadata to {}", path.display());
+        self.file = Some(ExportFile {
+            writer,
+            path,
+            partial_path,
+            rows: 0,
+            opened: Instant::now(),
+        });
+
+        if let Some(max_files) = self.options.max_files {
+            // the file being written isn't finished yet and doesn't count
+            delete_oldest(
+                &self.options.dir,
+                &self.options.prefix,
+                extension,
+                max_files,
+            )?;
+        }
+        Ok(())
+    }
+}
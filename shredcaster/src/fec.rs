@@ -0,0 +1,68 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use shredcaster_proto::fec::FecEncoder;
+
+const DEFAULT_DATA_SHARDS: usize = 32;
+const DEFAULT_FLUSH: Duration = Duration::from_millis(10);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct FecOptions {
+    /// Parity packets sent per block, no FEC if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fec_parity: Option<usize>,
+    /// Datagrams per block, 32 if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fec_data: Option<usize>,
+    /// Longest a block waits to fill up before its parity is sent, 10000 if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fec_flush_us: Option<u64>,
+}
+
+/// The FEC encoder of a listener and when its current block has to be closed
+pub struct Fec {
+    encoder: FecEncoder,
+    flush_after: Duration,
+    deadline: Option<Instant>,
+}
+
+impl Fec {
+    pub fn new(options: &FecOptions) -> anyhow::Result<Option<Self>> {
+        let Some(parity_shards) = options.fec_parity else {
+            return Ok(None);
+        };
+        let data_shards = options.fec_data.unwrap_or(DEFAULT_DATA_SHARDS);
+        Ok(Some(Self {
+            encoder: FecEncoder::new(data_shards, parity_shards)?,
+            flush_after: options
+                .fec_flush_us
+                .map_or(DEFAULT_FLUSH, Duration::from_micros),
+            deadline: None,
+        }))
+    }
+
+    /// When the current block has to be closed
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Returns the packets to send for `datagram`
+    pub fn encode(&mut self, datagram: &[u8], now: Instant) -> Vec<Vec<u8>> {
+        let packets = self.encoder.encode(datagram);
+        self.deadline = self
+            .encoder
+            .is_pending()
+            .then(|| self.deadline.unwrap_or(now + self.flush_after));
+        packets
+    }
+
+    /// Closes the current block if it is due, returning its parity packets
+    pub fn flush(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        if self.deadline.is_none_or(|deadline| deadline > now) {
+            return Vec::new();
+        }
+        self.deadline = None;
+        self.encoder.finish()
+    }
+}
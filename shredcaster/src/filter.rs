@@ -0,0 +1,166 @@
+use std::{net::IpAddr, str::FromStr};
+
+use clap::ValueEnum;
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use shredcaster_proto::{hash::mix, shred};
+use solana_ledger::shred::layout;
+use solana_pubkey::Pubkey;
+
+use crate::leader_schedule::LeaderSchedule;
+
+#[derive(Serialize, Deserialize, ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Ingress,
+    Egress,
+}
+
+#[derive(Serialize, Deserialize, ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ShredKind {
+    Data,
+    Code,
+}
+
+impl ShredKind {
+    pub fn from_shred(shred: &[u8]) -> Option<Self> {
+        shred::merkle_kind(shred).map(Self::from)
+    }
+}
+
+impl From<shred::ShredKind> for ShredKind {
+    fn from(kind: shred::ShredKind) -> Self {
+        match kind {
+            shred::ShredKind::Data => ShredKind::Data,
+            shred::ShredKind::Code => ShredKind::Code,
+        }
+    }
+}
+
+/// The fields of a captured packet that listener filters can match on
+pub struct PacketInfo<'a> {
+    pub direction: Direction,
+    pub src_ip: IpAddr,
+    pub slot: Option<u64>,
+    pub index: Option<u32>,
+    pub fec_set: Option<u32>,
+    pub kind: Option<ShredKind>,
+    pub leader: Option<&'a Pubkey>,
+}
+
+impl<'a> PacketInfo<'a> {
+    pub fn new(
+        shred: &[u8],
+        is_egress: bool,
+        src_ip: IpAddr,
+        leader_schedule: &'a LeaderSchedule,
+    ) -> Self {
+        let slot = layout::get_slot(shred);
+        Self {
+            direction: if is_egress {
+                Direction::Egress
+            } else {
+                Direction::Ingress
+            },
+            src_ip,
+            slot,
+            index: layout::get_index(shred),
+            fec_set: shred::fec_set_index(shred),
+            kind: ShredKind::from_shred(shred),
+            leader: slot.and_then(|slot| leader_schedule.leader(slot)),
+        }
+    }
+
+    /// A uniformly distributed value identifying the shred, so that sampling
+    /// picks the same shreds on every shredcaster instance
+    pub fn sample_hash(&self) -> u64 {
+        mix(self.slot.unwrap_or_default()
+            ^ (u64::from(self.index.unwrap_or_default()) << 1)
+            ^ (self.kind == Some(ShredKind::Code)) as u64)
+    }
+}
+
+fn deserialize_pubkeys<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Pubkey>, D::Error> {
+    Vec::<String>::deserialize(d)?
+        .iter()
+        .map(|key| Pubkey::from_str(key).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+fn serialize_pubkeys<S: Serializer>(keys: &[Pubkey], s: S) -> Result<S::Ok, S::Error> {
+    s.collect_seq(keys.iter().map(Pubkey::to_string))
+}
+
+/// Criteria a packet has to match to be delivered to a listener,
+/// unset criteria match everything
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct ListenerFilter {
+    /// Only deliver packets captured in this direction
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub direction: Option<Direction>,
+    /// Only deliver shreds of this type
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shred_type: Option<ShredKind>,
+    /// Only deliver slots where `slot % slot_modulo == slot_remainder`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slot_modulo: Option<u64>,
+    pub slot_remainder: u64,
+    /// Fraction of shreds to deliver, between 0 and 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_rate: Option<f64>,
+    /// Only deliver packets sent from these networks
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub source_cidrs: Vec<IpNet>,
+    /// Only deliver slots led by these validators, requires `rpc_url`
+    #[serde(
+        skip_serializing_if = "Vec::is_empty",
+        serialize_with = "serialize_pubkeys",
+        deserialize_with = "deserialize_pubkeys"
+    )]
+    pub leaders: Vec<Pubkey>,
+}
+
+impl ListenerFilter {
+    pub fn matches(&self, packet: &PacketInfo) -> bool {
+        if self
+            .direction
+            .is_some_and(|direction| direction != packet.direction)
+        {
+            return false;
+        }
+        if self.shred_type.is_some() && self.shred_type != packet.kind {
+            return false;
+        }
+        if let Some(modulo) = self.slot_modulo.filter(|modulo| *modulo > 0)
+            && packet
+                .slot
+                .is_none_or(|slot| slot % modulo != self.slot_remainder)
+        {
+            return false;
+        }
+        if let Some(rate) = self.sample_rate
+            && packet.sample_hash() as f64 >= rate * u64::MAX as f64
+        {
+            return false;
+        }
+        if !self.source_cidrs.is_empty()
+            && !self
+                .source_cidrs
+                .iter()
+                .any(|net| net.contains(&packet.src_ip))
+        {
+            return false;
+        }
+        if !self.leaders.is_empty()
+            && packet
+                .leader
+                .is_none_or(|leader| !self.leaders.contains(leader))
+        {
+            return false;
+        }
+
+        true
+    }
+}
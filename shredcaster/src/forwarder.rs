@@ -0,0 +1,1331 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    ops::Range,
+    os::fd::AsRawFd,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use agave_xdp::{
+    device::{NetworkDevice, QueueId},
+    netlink::MacAddress,
+};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use shredcaster_proto::{
+    bundle, envelope, fec,
+    net::ipv6_mapped,
+    secure::{self, Key, Sealer},
+};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::runtime::Handle;
+
+use crate::{
+    CapturedPacket, SharedPacketData,
+    bundle::{BundleMode, BundleOptions, Bundler, Flushed},
+    capture::{Capture, CaptureContext, DeliveryMode},
+    config::{ListenerAddr, ListenerConfig, MaybeSharedListeners},
+    fec::{Fec, FecOptions},
+    filter::{ListenerFilter, PacketInfo},
+    group::{Balance, ListenerGroup, member_id},
+    health::HealthChecker,
+    leader_schedule::SharedLeaderSchedule,
+    metrics::{ListenerCtr, SharedListenerCtrs},
+    multicast::multicast_mac,
+    nack::{Retransmits, SharedRetransmits},
+    rate_limit::{self, Priority, RateLimit, RateLimiter},
+    route::{NextHop, RouteEvents, Router, if_indextoname, if_nametoindex},
+    sink::ShredSink,
+    stream::{StreamLane, StreamTarget},
+    transparent::spawn_transparent_tx_loop,
+};
+
+/// How often listeners and pending neighbour resolutions are re-checked
+/// when no netlink event arrives in between
+const RESOLVE_INTERVAL: Duration = Duration::from_millis(500);
+/// Port used to provoke ARP/NDP resolution of a next hop
+const DISCARD_PORT: u16 = 9;
+
+/// Away from the PoH core 0 for maximal compatibility
+pub const DEFAULT_TX_CPU_CORE: usize = 2;
+
+/// The bytes sent to a listener
+#[derive(Clone)]
+pub enum ForwardData {
+    /// The captured shred, shared by every raw listener
+    Raw(SharedPacketData),
+    /// The shred wrapped for a single listener
+    Framed(Arc<[u8]>),
+    /// Packets of the given size sent in one UDP GSO send, only handled by socket lanes
+    Segments(Arc<[u8]>, u16),
+    /// A packet to be sent from the address the shred was captured from,
+    /// only handled by transparent lanes
+    Transparent(SocketAddr, Box<ForwardData>),
+}
+
+impl AsRef<[u8]> for ForwardData {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            ForwardData::Raw(data) => data.as_ref(),
+            ForwardData::Framed(data) | ForwardData::Segments(data, _) => data,
+            ForwardData::Transparent(_, data) => (**data).as_ref(),
+        }
+    }
+}
+
+pub type ForwardPacket = (Arc<[SocketAddr]>, ForwardData);
+
+/// Rate limiter, counters and sequence numbers of a listener, kept across forward plans
+struct ListenerState {
+    rate_limit: RateLimit,
+    limiter: Option<Arc<Mutex<RateLimiter>>>,
+    psk: Option<Key>,
+    /// Unset for listeners with a `psk` if starting the session failed
+    sealer: Option<Sealer>,
+    bundle: BundleOptions,
+    bundler: Option<Mutex<Bundler>>,
+    fec_options: FecOptions,
+    fec: Option<Mutex<Fec>>,
+    nack_window_ms: Option<u64>,
+    retransmits: Option<Arc<Retransmits>>,
+    ctr: Arc<ListenerCtr>,
+    next_seq: AtomicU64,
+}
+
+impl ListenerState {
+    /// Fresh state for `listener`, keeping the counters and sequence numbers of `previous`
+    fn new(
+        listener: &ListenerConfig,
+        previous: Option<&ListenerState>,
+        global_limiter: Option<&Arc<Mutex<RateLimiter>>>,
+    ) -> Self {
+        let ctr = previous.map(|state| state.ctr.clone()).unwrap_or_default();
+        let limiter = RateLimiter::new(listener.rate_limit)
+            .map(Mutex::new)
+            .map(Arc::new);
+        Self {
+            rate_limit: listener.rate_limit,
+            limiter: limiter.clone(),
+            psk: listener.psk.clone(),
+            sealer: listener.psk.as_ref().and_then(|psk| {
+                Sealer::new(psk)
+                    .inspect_err(|e| {
+                        eprintln!("failed to start session with {}: {e}", listener.addr)
+                    })
+                    .ok()
+            }),
+            bundle: listener.bundle,
+            bundler: listener
+                .addr
+                .udp()
+                .and_then(|addr| Bundler::new(&listener.bundle, &addr, listener.reserved()))
+                .map(Mutex::new),
+            fec_options: listener.fec,
+            fec: match listener.addr.udp().map(|_| Fec::new(&listener.fec)) {
+                Some(Ok(fec)) => fec.map(Mutex::new),
+                Some(Err(e)) => {
+                    eprintln!("not sending parity to {}: {e}", listener.addr);
+                    None
+                }
+                None => None,
+            },
+            nack_window_ms: listener.nack_window_ms,
+            retransmits: listener.addr.udp().and(listener.nack_window_ms).map(|ms| {
+                if listener.mode != DeliveryMode::Envelope {
+                    eprintln!(
+                        "{} cannot name missed packets without mode = \"envelope\"",
+                        listener.addr
+                    );
+                }
+                Arc::new(Retransmits::new(
+                    Duration::from_millis(ms),
+                    listener.psk.as_ref(),
+                    limiter.clone(),
+                    global_limiter.cloned(),
+                    ctr.clone(),
+                ))
+            }),
+            ctr,
+            next_seq: AtomicU64::new(
+                previous.map_or(0, |state| state.next_seq.load(Ordering::Relaxed)),
+            ),
+        }
+    }
+}
+
+/// Decides which packets a listener receives and in which form
+#[derive(Clone)]
+pub struct Admission {
+    filter: ListenerFilter,
+    mode: DeliveryMode,
+    state: Arc<ListenerState>,
+    /// The listener's group and its id within it
+    group: Option<(Arc<ListenerGroup>, u64)>,
+}
+
+impl Admission {
+    pub fn ctr(&self) -> &ListenerCtr {
+        &self.state.ctr
+    }
+
+    /// Takes the listener's next sequence number
+    pub fn next_seq(&self) -> u64 {
+        self.state.next_seq.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Whether the listener receives the captured shred as is,
+    /// so the packet can be shared with other listeners
+    fn is_raw(&self) -> bool {
+        self.mode == DeliveryMode::Raw
+            && self.state.psk.is_none()
+            && self.state.bundler.is_none()
+            && self.state.fec.is_none()
+            && self.state.retransmits.is_none()
+    }
+
+    fn frame(&self, capture: &Capture, seq: u64, shred: &SharedPacketData) -> ForwardData {
+        match self.mode {
+            DeliveryMode::Raw => ForwardData::Raw(shred.clone()),
+            DeliveryMode::Envelope => capture.envelope(seq, shred.as_ref()),
+        }
+    }
+
+    fn seal(&self, seq: u64, data: ForwardData) -> ForwardData {
+        match &self.state.sealer {
+            Some(sealer) => {
+                let mut sealed = Vec::new();
+                sealer.seal(seq, data.as_ref(), &mut sealed);
+                ForwardData::Framed(Arc::from(sealed))
+            }
+            None => data,
+        }
+    }
+
+    /// The packet to send for `shred`, `seq` is only used by envelopes and sealed packets
+    pub fn payload(&self, capture: &Capture, seq: u64, shred: &SharedPacketData) -> ForwardData {
+        self.seal(seq, self.frame(capture, seq, shred))
+    }
+
+    /// Adds `shred` to the listener's bundle, returning a batch that is ready to be sent
+    fn bundle(
+        &self,
+        bundler: &Mutex<Bundler>,
+        capture: &Capture,
+        shred: &SharedPacketData,
+        global_limiter: Option<&Mutex<RateLimiter>>,
+        now: Instant,
+    ) -> Option<(ForwardData, Range<u64>)> {
+        let seq = self.next_seq();
+        let mut bundler = bundler.lock().unwrap();
+        // bundles are sealed as a whole, GSO segments one by one
+        let packet = match bundler.mode() {
+            BundleMode::Frames => self.frame(capture, seq, shred),
+            BundleMode::Gso => self.payload(capture, seq, shred),
+        };
+        let flushed = bundler.push(seq, packet.as_ref(), now);
+        drop(bundler);
+        flushed.map(|flushed| self.finish(flushed, global_limiter, now))
+    }
+
+    /// Returns the listener's bundle if it is due and when the next one is
+    fn flush_bundle(
+        &self,
+        global_limiter: Option<&Mutex<RateLimiter>>,
+        now: Instant,
+    ) -> (Option<(ForwardData, Range<u64>)>, Option<Instant>) {
+        let Some(bundler) = &self.state.bundler else {
+            return (None, None);
+        };
+        let mut bundler = bundler.lock().unwrap();
+        let flushed = bundler.flush(now);
+        let deadline = bundler.deadline();
+        drop(bundler);
+        (
+            flushed.map(|flushed| self.finish(flushed, global_limiter, now)),
+            deadline,
+        )
+    }
+
+    /// Returns the parity of the listener's FEC block if it is due and when the next one is
+    fn flush_fec(&self, now: Instant) -> (Vec<Vec<u8>>, Option<Instant>) {
+        let Some(fec) = &self.state.fec else {
+            return (Vec::new(), None);
+        };
+        let mut fec = fec.lock().unwrap();
+        (fec.flush(now), fec.deadline())
+    }
+
+    fn finish(
+        &self,
+        flushed: Flushed,
+        global_limiter: Option<&Mutex<RateLimiter>>,
+        now: Instant,
+    ) -> (ForwardData, Range<u64>) {
+        match flushed {
+            Flushed::Frames { seqs, data } => {
+                // the bundled shreds were admitted, what wraps them is charged on top
+                self.charge(
+                    bundle::HEADER_LEN + self.datagram_overhead(),
+                    global_limiter,
+                    now,
+                );
+                (
+                    self.seal(seqs.start, ForwardData::Framed(Arc::from(data))),
+                    seqs,
+                )
+            }
+            Flushed::Segments {
+                seqs,
+                data,
+                segment,
+            } => (ForwardData::Segments(Arc::from(data), segment), seqs),
+        }
+    }
+
+    /// Keeps `payload`, which carries packets `seqs`, for retransmission
+    fn record(&self, payload: &ForwardData, seqs: Range<u64>, now: Instant) {
+        let Some(retransmits) = &self.state.retransmits else {
+            return;
+        };
+        match payload {
+            ForwardData::Segments(data, segment) => {
+                for (seq, datagram) in seqs.zip(data.chunks(*segment as usize)) {
+                    retransmits.record(seq, Arc::from(datagram), now);
+                }
+            }
+            ForwardData::Framed(data) => {
+                for seq in seqs {
+                    retransmits.record(seq, data.clone(), now);
+                }
+            }
+            ForwardData::Raw(data) => {
+                let data: Arc<[u8]> = Arc::from(data.as_ref());
+                for seq in seqs {
+                    retransmits.record(seq, data.clone(), now);
+                }
+            }
+            ForwardData::Transparent(_, data) => self.record(data, seqs, now),
+        }
+    }
+
+    /// Whether `packet` passes the listener's filter and rate limits and is
+    /// not for another member of its group, taking its tokens from the limiters if it does
+    pub fn admit(
+        &self,
+        packet: &PacketInfo,
+        len: usize,
+        global_limiter: Option<&Mutex<RateLimiter>>,
+        now: Instant,
+    ) -> bool {
+        // never fall back to plaintext for listeners expecting sealed packets
+        if !self.filter.matches(packet) || self.state.psk.is_some() && self.state.sealer.is_none() {
+            return false;
+        }
+        if let Some((group, member)) = &self.group
+            && group.owner(packet) != Some(*member)
+        {
+            return false;
+        }
+        if !self.acquire(
+            self.wire_len(len),
+            Priority::of(packet),
+            global_limiter,
+            now,
+        ) {
+            return false;
+        }
+        self.state
+            .ctr
+            .forwarded_packets
+            .fetch_add(1, Ordering::Relaxed);
+
+        true
+    }
+
+    /// Takes a packet of `len` bytes from the listener's and the global budget
+    /// if both allow it, counting it as throttled by the first that doesn't
+    fn acquire(
+        &self,
+        len: usize,
+        priority: Priority,
+        global_limiter: Option<&Mutex<RateLimiter>>,
+        now: Instant,
+    ) -> bool {
+        rate_limit::acquire(
+            self.state.limiter.as_deref(),
+            global_limiter,
+            &self.state.ctr,
+            len,
+            priority,
+            now,
+        )
+    }
+
+    /// Takes `len` bytes that are sent regardless from both budgets
+    fn charge(&self, len: usize, global_limiter: Option<&Mutex<RateLimiter>>, now: Instant) {
+        for limiter in self
+            .state
+            .limiter
+            .as_deref()
+            .into_iter()
+            .chain(global_limiter)
+        {
+            let mut limiter = limiter.lock().unwrap();
+            limiter.allows(now, len, Priority::Normal);
+            limiter.take(len);
+        }
+        self.state
+            .ctr
+            .forwarded_bytes
+            .fetch_add(len, Ordering::Relaxed);
+    }
+
+    /// Bytes sent for a shred of `len` bytes, except for what
+    /// wraps whole bundles, which is charged once they are sent
+    fn wire_len(&self, len: usize) -> usize {
+        let framed = match self.mode {
+            DeliveryMode::Raw => len,
+            DeliveryMode::Envelope => envelope::HEADER_LEN + len,
+        };
+        if self.state.bundler.is_some() && self.state.bundle.bundle == Some(BundleMode::Frames) {
+            framed + bundle::FRAME_OVERHEAD
+        } else {
+            framed + self.datagram_overhead()
+        }
+    }
+
+    /// What sealing and FEC add to every datagram
+    fn datagram_overhead(&self) -> usize {
+        let sealed = if self.state.sealer.is_some() {
+            secure::OVERHEAD
+        } else {
+            0
+        };
+        let fec = if self.state.fec.is_some() {
+            fec::OVERHEAD
+        } else {
+            0
+        };
+        sealed + fec
+    }
+}
+
+pub struct ListenerTarget {
+    pub addr: SocketAddr,
+    admission: Admission,
+}
+
+/// The listeners served by a single TX lane
+#[derive(Clone)]
+pub struct LaneTargets {
+    pub tx: Sender<ForwardPacket>,
+    pub listeners: Arc<[ListenerTarget]>,
+    /// Whether packets keep the source address they were captured with
+    transparent: bool,
+}
+
+impl LaneTargets {
+    /// Queues `shred` for the listeners of this lane that should receive it,
+    /// raw listeners share a single packet while envelopes, sealed packets
+    /// and bundles are built per listener
+    pub fn forward(
+        &self,
+        packet: &PacketInfo,
+        capture: &Capture,
+        shred: &SharedPacketData,
+        global_limiter: Option<&Mutex<RateLimiter>>,
+        now: Instant,
+    ) {
+        let mut raw = Vec::new();
+        for listener in self.listeners.iter() {
+            let admission = &listener.admission;
+            if !admission.admit(packet, shred.0.len(), global_limiter, now) {
+                continue;
+            }
+            if admission.is_raw() {
+                raw.push(listener.addr);
+            } else if let Some(bundler) = &admission.state.bundler {
+                if let Some((payload, seqs)) =
+                    admission.bundle(bundler, capture, shred, global_limiter, now)
+                {
+                    let payload = self.sourced(capture, payload);
+                    self.send(listener, payload, seqs, global_limiter, now);
+                }
+            } else {
+                let seq = admission.next_seq();
+                let payload = self.sourced(capture, admission.payload(capture, seq, shred));
+                self.send(listener, payload, seq..seq + 1, global_limiter, now);
+            }
+        }
+        if !raw.is_empty() {
+            let payload = self.sourced(capture, ForwardData::Raw(shred.clone()));
+            _ = self.tx.try_send((raw.into(), payload));
+        }
+    }
+
+    /// Marks `payload` with the captured source address if the lane is transparent
+    fn sourced(&self, capture: &Capture, payload: ForwardData) -> ForwardData {
+        if self.transparent {
+            ForwardData::Transparent(capture.src(), Box::new(payload))
+        } else {
+            payload
+        }
+    }
+
+    /// Queues `payload`, which carries packets `seqs`, for a single listener,
+    /// adding parity packets if it uses FEC
+    fn send(
+        &self,
+        listener: &ListenerTarget,
+        payload: ForwardData,
+        seqs: Range<u64>,
+        global_limiter: Option<&Mutex<RateLimiter>>,
+        now: Instant,
+    ) {
+        listener.admission.record(&payload, seqs, now);
+        let Some(fec) = &listener.admission.state.fec else {
+            _ = self.tx.try_send((Arc::from([listener.addr]), payload));
+            return;
+        };
+        // every GSO segment is a datagram of its own, so they are protected one by one
+        let datagrams = match &payload {
+            ForwardData::Segments(data, segment) => data.chunks(*segment as usize).collect(),
+            data => vec![data.as_ref()],
+        };
+        let mut fec = fec.lock().unwrap();
+        for datagram in datagrams {
+            let mut packets = fec.encode(datagram, now).into_iter();
+            // the data packet was admitted with its FEC header
+            if let Some(packet) = packets.next() {
+                self.send_fec(listener, packet);
+            }
+            self.send_parity(listener, packets, global_limiter, now);
+        }
+    }
+
+    fn send_fec(&self, listener: &ListenerTarget, packet: Vec<u8>) {
+        _ = self.tx.try_send((
+            Arc::from([listener.addr]),
+            ForwardData::Framed(Arc::from(packet)),
+        ));
+    }
+
+    /// Queues the parity packets the listener's budget allows, they are dropped first
+    fn send_parity(
+        &self,
+        listener: &ListenerTarget,
+        parity: impl IntoIterator<Item = Vec<u8>>,
+        global_limiter: Option<&Mutex<RateLimiter>>,
+        now: Instant,
+    ) {
+        for packet in parity {
+            if listener
+                .admission
+                .acquire(packet.len(), Priority::Low, global_limiter, now)
+            {
+                self.send_fec(listener, packet);
+            }
+        }
+    }
+
+    /// Sends the bundles and FEC parity that are due, returning when the next are
+    pub fn flush(
+        &self,
+        global_limiter: Option<&Mutex<RateLimiter>>,
+        now: Instant,
+    ) -> Option<Instant> {
+        let mut next: Option<Instant> = None;
+        for listener in self.listeners.iter() {
+            let (payload, bundle_deadline) = listener.admission.flush_bundle(global_limiter, now);
+            if let Some((payload, seqs)) = payload {
+                self.send(listener, payload, seqs, global_limiter, now);
+            }
+            let (parity, fec_deadline) = listener.admission.flush_fec(now);
+            self.send_parity(listener, parity, global_limiter, now);
+            next = [next, bundle_deadline, fec_deadline]
+                .into_iter()
+                .flatten()
+                .min();
+        }
+        next
+    }
+}
+
+#[derive(Default)]
+pub struct ForwardPlan {
+    pub lanes: Vec<LaneTargets>,
+    pub streams: Vec<StreamTarget>,
+    /// Budget shared by every listener
+    pub global_limiter: Option<Arc<Mutex<RateLimiter>>>,
+}
+
+impl ForwardPlan {
+    /// Sends the bundles and FEC parity that are due, returning when the next are
+    pub fn flush(&self, now: Instant) -> Option<Instant> {
+        let global_limiter = self.global_limiter.as_deref();
+        self.lanes
+            .iter()
+            .filter_map(|lane| lane.flush(global_limiter, now))
+            .min()
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct SharedForwardPlan(Arc<RwLock<Arc<ForwardPlan>>>);
+
+impl SharedForwardPlan {
+    pub fn get(&self) -> Arc<ForwardPlan> {
+        self.0.read().unwrap().clone()
+    }
+
+    fn set(&self, plan: ForwardPlan) {
+        *self.0.write().unwrap() = Arc::new(plan);
+    }
+}
+
+/// Forwards captured packets to the listeners of the current plan
+pub struct ForwardSink {
+    pub plan: SharedForwardPlan,
+    pub leader_schedule: SharedLeaderSchedule,
+    pub capture_context: Arc<CaptureContext>,
+}
+
+impl ShredSink for ForwardSink {
+    fn send(&mut self, packets: &[CapturedPacket]) {
+        let plan = self.plan.get();
+        let leader_schedule = self.leader_schedule.get();
+        let global_limiter = plan.global_limiter.as_deref();
+        for packet in packets {
+            let info = PacketInfo::new(
+                &packet.data.0,
+                packet.is_egress,
+                packet.src.ip(),
+                &leader_schedule,
+            );
+            let capture = self.capture_context.capture(packet);
+            let now = Instant::now();
+            for lane in plan.lanes.iter() {
+                lane.forward(&info, &capture, &packet.data, global_limiter, now);
+            }
+            for stream in plan.streams.iter() {
+                stream.forward(&info, &capture, &packet.data, global_limiter, now);
+            }
+        }
+    }
+
+    fn flush(&mut self, now: Instant) -> Option<Instant> {
+        self.plan.get().flush(now)
+    }
+}
+
+#[derive(Clone)]
+pub struct ForwarderConfig {
+    /// Restrict forwarding to this interface instead of following the routing table
+    pub forward_iface: Option<String>,
+    pub forwarder_port: u16,
+    /// Each AF_XDP lane is pinned to one of these, the least used one when it starts
+    pub tx_pinned_cpu_cores: Vec<usize>,
+    pub global_rate_limit: RateLimit,
+    pub health_checks: bool,
+    /// Send to IPv4 listeners through kernel sockets instead of AF_XDP, which needs no root
+    pub kernel_tx: bool,
+}
+
+/// How packets leave a lane
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TxBackend {
+    /// AF_XDP through agave's tx_loop, which only supports IPv4
+    Xdp,
+    /// A kernel UDP socket bound to the lane's interface, also used for GSO
+    /// and for multicast groups that are not on-link or need their own TTL
+    Socket { multicast_ttl: Option<u32> },
+    /// A raw socket sending packets from the address they were captured from
+    Transparent,
+}
+
+/// Whether packets to `listener` can keep their original source address,
+/// which requires IPv4 and one shred per datagram
+fn is_transparent(listener: &ListenerConfig, addr: &SocketAddr) -> bool {
+    listener.transparent
+        && addr.is_ipv4()
+        && !addr.ip().is_multicast()
+        && listener.bundle.bundle.is_none()
+        && listener.fec.fec_parity.is_none()
+}
+
+impl TxBackend {
+    fn for_listener(
+        listener: &ListenerConfig,
+        addr: &SocketAddr,
+        hop: &NextHop,
+        kernel_tx: bool,
+    ) -> Self {
+        if is_transparent(listener, addr) {
+            return TxBackend::Transparent;
+        }
+        let multicast_ttl = listener.multicast.multicast_ttl;
+        let multicast = addr.ip().is_multicast();
+        match (addr, listener.bundle.bundle) {
+            (_, Some(BundleMode::Gso)) | (SocketAddr::V6(_), _) => TxBackend::Socket {
+                multicast_ttl: multicast_ttl.filter(|_| multicast),
+            },
+            (SocketAddr::V4(_), _) if kernel_tx => TxBackend::Socket {
+                multicast_ttl: multicast_ttl.filter(|_| multicast),
+            },
+            // tx_loop can only reach groups without a gateway, and only with its own TTL
+            (SocketAddr::V4(_), _)
+                if multicast && (multicast_ttl.is_some() || hop.ip_addr != addr.ip()) =>
+            {
+                TxBackend::Socket { multicast_ttl }
+            }
+            (SocketAddr::V4(_), _) => TxBackend::Xdp,
+        }
+    }
+}
+
+type LaneKey = (u32, TxBackend);
+
+/// A TX backend bound to one interface
+///
+/// tx_loop snapshots the neighbour table when it starts, so an XDP lane is
+/// restarted whenever the MAC address of one of its next hops changes
+struct Lane {
+    if_name: String,
+    tx: Sender<ForwardPacket>,
+    thread: Option<JoinHandle<()>>,
+    next_hops: HashMap<IpAddr, Option<MacAddress>>,
+    /// The core the tx_loop of an AF_XDP lane is pinned to
+    cpu: Option<usize>,
+}
+
+impl Lane {
+    fn stop(mut self) {
+        drop(self.tx);
+        if let Some(thread) = self.thread.take()
+            && thread.join().is_err()
+        {
+            eprintln!("tx loop on {} panicked", self.if_name);
+        }
+    }
+}
+
+struct Forwarder {
+    config: ForwarderConfig,
+    oif: Option<u32>,
+    router: Router,
+    listeners: MaybeSharedListeners,
+    plan: SharedForwardPlan,
+    drop_sender: Sender<ForwardPacket>,
+    lanes: HashMap<LaneKey, Lane>,
+    resolved: HashMap<SocketAddr, Option<(NextHop, TxBackend)>>,
+    /// Neighbour entries this process added so that tx_loop can address multicast
+    /// groups, entries that were already there are left alone, and the ones added
+    /// are left behind if the process dies without stopping
+    multicast_neighbours: HashSet<(u32, IpAddr)>,
+    last_listeners: Arc<[ListenerConfig]>,
+    states: HashMap<ListenerAddr, Arc<ListenerState>>,
+    global_limiter: Option<Arc<Mutex<RateLimiter>>>,
+    listener_ctrs: SharedListenerCtrs,
+    retransmits: SharedRetransmits,
+    groups: HashMap<String, Arc<ListenerGroup>>,
+    health: Option<HealthChecker>,
+    runtime: Handle,
+    streams: HashMap<ListenerAddr, StreamLane>,
+}
+
+/// Bytes that encryption and FEC add to every datagram of `listener`
+fn probe_neighbour(dst: IpAddr) {
+    let bind: SocketAddr = match dst {
+        IpAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        IpAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    if let Ok(socket) = UdpSocket::bind(bind) {
+        _ = socket.send_to(&[], (dst, DISCARD_PORT));
+    }
+}
+
+fn spawn_tx_loop(
+    config: &ForwarderConfig,
+    if_name: String,
+    cpu: usize,
+    rx: Receiver<ForwardPacket>,
+    drop_sender: Sender<ForwardPacket>,
+) -> anyhow::Result<JoinHandle<()>> {
+    let dev = NetworkDevice::new(if_name)?;
+    let src_port = config.forwarder_port;
+    Ok(thread::spawn(move || {
+        agave_xdp::tx_loop::tx_loop(
+            cpu,
+            &dev,
+            QueueId(cpu as u64),
+            false,
+            None,
+            None,
+            src_port,
+            None,
+            rx,
+            drop_sender,
+        )
+    }))
+}
+
+fn spawn_socket_tx_loop(
+    config: &ForwarderConfig,
+    if_name: String,
+    multicast_ttl: Option<u32>,
+    rx: Receiver<ForwardPacket>,
+) -> anyhow::Result<JoinHandle<()>> {
+    // dual stack, IPv4 listeners end up here when they use GSO or multicast
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(false)?;
+    if let Some(ttl) = multicast_ttl {
+        socket.set_multicast_ttl_v4(ttl)?;
+        socket.set_multicast_hops_v6(ttl)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind_device(Some(if_name.as_bytes()))?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, config.forwarder_port)).into())?;
+    let socket = UdpSocket::from(socket);
+    Ok(thread::spawn(move || {
+        while let Ok((addrs, data)) = rx.recv() {
+            for addr in addrs.iter() {
+                let addr = SocketAddr::new(ipv6_mapped(addr.ip()).into(), addr.port());
+                _ = match &data {
+                    ForwardData::Segments(data, segment) => {
+                        send_segments(&socket, data, *segment, &addr)
+                    }
+                    data => socket.send_to(data.as_ref(), addr).map(drop),
+                };
+            }
+        }
+    }))
+}
+
+/// Sends `data` as datagrams of `segment` bytes with a single UDP GSO send,
+/// falling back to one send per datagram if the kernel refuses
+fn send_segments(
+    socket: &UdpSocket,
+    data: &[u8],
+    segment: u16,
+    addr: &SocketAddr,
+) -> io::Result<()> {
+    if data.len() > segment as usize {
+        let dst = SockAddr::from(*addr);
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let mut cmsg_buf = [0u64; 4];
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_name = dst.as_ptr() as *mut libc::c_void;
+        msg.msg_namelen = dst.len();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(size_of::<u16>() as u32) } as libc::size_t;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_UDP;
+            (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<u16>() as u32) as libc::size_t;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment);
+        }
+        if unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) } >= 0 {
+            return Ok(());
+        }
+    }
+    for datagram in data.chunks(segment.max(1) as usize) {
+        socket.send_to(datagram, addr)?;
+    }
+    Ok(())
+}
+
+impl Forwarder {
+    fn resolve(
+        &mut self,
+        listeners: &[ListenerConfig],
+    ) -> HashMap<LaneKey, Vec<(ListenerConfig, NextHop)>> {
+        let neighbours = match self.router.neighbours() {
+            Ok(neighbours) => neighbours,
+            Err(e) => {
+                eprintln!("failed to read neighbour table: {e}");
+                HashMap::new()
+            }
+        };
+
+        let mut by_lane: HashMap<LaneKey, Vec<_>> = HashMap::new();
+        let mut resolved = HashMap::with_capacity(listeners.len());
+        let mut multicast_neighbours = HashSet::new();
+        for listener in listeners.iter() {
+            let Some(addr) = listener.addr.udp() else {
+                continue;
+            };
+            let oif = match &listener.multicast.multicast_iface {
+                Some(if_name) if addr.ip().is_multicast() => match if_nametoindex(if_name) {
+                    Ok(if_index) => Some(if_index),
+                    Err(e) => {
+                        eprintln!("cannot forward to listener {addr} through {if_name}: {e}");
+                        continue;
+                    }
+                },
+                _ => self.oif,
+            };
+            let hop = match self.router.next_hop(addr.ip(), oif, &neighbours) {
+                Ok(hop) => Some(hop),
+                Err(e) => {
+                    if self.resolved.get(&addr).is_none_or(Option::is_some) {
+                        eprintln!("cannot route to listener {addr}: {e}");
+                    }
+                    None
+                }
+            };
+            let hop = hop.map(|mut hop| {
+                let backend = TxBackend::for_listener(listener, &addr, &hop, self.config.kernel_tx);
+                if backend == TxBackend::Xdp && addr.ip().is_multicast() {
+                    // tx_loop looks the group up in the neighbour table like any other next hop
+                    let mac = multicast_mac(addr.ip());
+                    let key = (hop.if_index, addr.ip());
+                    if neighbours.get(&key) != Some(&mac) {
+                        match self.router.set_neighbour(hop.if_index, addr.ip(), mac) {
+                            Ok(()) => {
+                                multicast_neighbours.insert(key);
+                            }
+                            Err(e) => {
+                                eprintln!("failed to add neighbour entry for group {addr}: {e}")
+                            }
+                        }
+                    } else if self.multicast_neighbours.contains(&key) {
+                        multicast_neighbours.insert(key);
+                    }
+                    hop.mac_addr = Some(mac);
+                }
+                (hop, backend)
+            });
+            if let Some((hop, backend)) = &hop {
+                let backend = *backend;
+                if self.resolved.get(&addr).and_then(Option::as_ref)
+                    != Some(&(hop.clone(), backend))
+                {
+                    let if_name = if_indextoname(hop.if_index).unwrap_or_default();
+                    match (backend, hop.mac_addr) {
+                        (TxBackend::Xdp, None) => eprintln!(
+                            "forwarding to {addr} via {} on {if_name}: next hop MAC unresolved",
+                            hop.ip_addr
+                        ),
+                        (TxBackend::Xdp, Some(MacAddress(mac))) => println!(
+                            "forwarding to {addr} via {} on {if_name} ({mac:02x?})",
+                            hop.ip_addr
+                        ),
+                        (TxBackend::Socket { .. }, _) => println!(
+                            "forwarding to {addr} via {} on {if_name} (socket)",
+                            hop.ip_addr
+                        ),
+                        (TxBackend::Transparent, _) => println!(
+                            "forwarding to {addr} via {} on {if_name} (transparent)",
+                            hop.ip_addr
+                        ),
+                    }
+                    if listener.transparent && backend != TxBackend::Transparent {
+                        eprintln!(
+                            "{addr} receives packets from this host, transparent forwarding \
+                             needs a unicast IPv4 listener without bundle or fec"
+                        );
+                    }
+                }
+                if backend == TxBackend::Xdp && hop.mac_addr.is_none() {
+                    probe_neighbour(addr.ip());
+                }
+                by_lane
+                    .entry((hop.if_index, backend))
+                    .or_default()
+                    .push((listener.clone(), hop.clone()));
+            }
+            resolved.insert(addr, hop);
+        }
+        self.resolved = resolved;
+        for &(if_index, group) in self.multicast_neighbours.difference(&multicast_neighbours) {
+            _ = self.router.del_neighbour(if_index, group);
+        }
+        self.multicast_neighbours = multicast_neighbours;
+
+        by_lane
+    }
+
+    /// Reuses the state of listeners whose rate limit, key, bundling, FEC and
+    /// NACK window are unchanged, so their buckets, counters, sessions,
+    /// pending batches and retransmit buffers survive plan updates
+    fn update_states(&mut self, listeners: &[ListenerConfig]) {
+        let mut states = HashMap::with_capacity(listeners.len());
+        for listener in listeners {
+            let state = match self.states.remove(&listener.addr) {
+                Some(state)
+                    if state.rate_limit == listener.rate_limit
+                        && state.psk == listener.psk
+                        && state.bundle == listener.bundle
+                        && state.fec_options == listener.fec
+                        && state.nack_window_ms == listener.nack_window_ms =>
+                {
+                    state
+                }
+                state => Arc::new(ListenerState::new(
+                    listener,
+                    state.as_deref(),
+                    self.global_limiter.as_ref(),
+                )),
+            };
+            states.entry(listener.addr.clone()).or_insert(state);
+        }
+        self.states = states;
+        if let Some(health) = &mut self.health {
+            health.update(listeners.iter().filter_map(|listener| {
+                if !listener.health_check && !listener.heartbeat {
+                    return None;
+                }
+                // any member of a group may answer, so groups are not probed
+                let addr = listener
+                    .addr
+                    .udp()
+                    .filter(|addr| !addr.ip().is_multicast())?;
+                // probes follow the packets, through the interface the listener is routed to
+                let if_name = self
+                    .resolved
+                    .get(&addr)
+                    .and_then(Option::as_ref)
+                    .and_then(|(hop, _)| if_indextoname(hop.if_index).ok());
+                let ctr = self.states[&listener.addr].ctr.clone();
+                Some((addr, listener.heartbeat, if_name, ctr))
+            }));
+        }
+
+        let mut ctrs: Vec<_> = self
+            .states
+            .iter()
+            .map(|(addr, state)| (addr.clone(), state.ctr.clone()))
+            .collect();
+        ctrs.sort_by(|(a, _), (b, _)| a.cmp(b));
+        self.listener_ctrs.set(ctrs);
+
+        *self.retransmits.write().unwrap() = listeners
+            .iter()
+            .filter_map(|listener| {
+                let retransmits = self.states[&listener.addr].retransmits.clone()?;
+                Some((listener.addr.udp()?, retransmits))
+            })
+            .collect();
+    }
+
+    /// Whether packets can currently be forwarded to `listener`
+    fn is_up(&self, listener: &ListenerConfig) -> bool {
+        let Some(addr) = listener.addr.udp() else {
+            return true;
+        };
+        self.resolved.get(&addr).is_some_and(Option::is_some)
+            && self
+                .health
+                .as_ref()
+                .is_none_or(|health| health.is_up(&addr))
+    }
+
+    /// Rebuilds listener groups out of the members that are up,
+    /// members that stay keep the packets they already had
+    fn update_groups(&mut self, listeners: &[ListenerConfig]) {
+        let mut members: HashMap<&str, (Balance, Vec<&ListenerAddr>)> = HashMap::new();
+        for listener in listeners {
+            let Some(name) = &listener.group.group else {
+                continue;
+            };
+            let (_, up) = members
+                .entry(name)
+                .or_insert((listener.group.balance.unwrap_or_default(), Vec::new()));
+            if self.is_up(listener) && !up.contains(&&listener.addr) {
+                up.push(&listener.addr);
+            }
+        }
+
+        let mut groups = HashMap::with_capacity(members.len());
+        for (name, (balance, up)) in members {
+            let group = ListenerGroup::new(balance, up.iter().copied().map(member_id).collect());
+            let group = match self.groups.remove(name) {
+                Some(previous) if *previous == group => previous,
+                _ => {
+                    let up: Vec<_> = up.iter().map(ToString::to_string).collect();
+                    println!("listener group {name} is split between {up:?}");
+                    Arc::new(group)
+                }
+            };
+            groups.insert(name.to_owned(), group);
+        }
+        self.groups = groups;
+    }
+
+    fn admission(&self, listener: ListenerConfig) -> Admission {
+        Admission {
+            state: self.states[&listener.addr].clone(),
+            mode: listener.mode,
+            group: listener
+                .group
+                .group
+                .as_ref()
+                .map(|name| (self.groups[name].clone(), member_id(&listener.addr))),
+            filter: listener.filter,
+        }
+    }
+
+    /// Starts and stops stream tasks to match the QUIC and TCP listeners
+    fn update_streams(&mut self, listeners: &[ListenerConfig]) -> Vec<StreamTarget> {
+        let mut streams = HashMap::new();
+        let mut targets = Vec::new();
+        for listener in listeners {
+            if !listener.addr.is_stream() {
+                continue;
+            }
+            let lane = match (
+                streams.remove(&listener.addr),
+                self.streams.remove(&listener.addr),
+            ) {
+                (Some(lane), _) => lane,
+                (None, Some(lane)) if *lane.options() == listener.stream => lane,
+                (None, _) => StreamLane::spawn(
+                    &self.runtime,
+                    listener.addr.clone(),
+                    listener.stream.clone(),
+                    self.states[&listener.addr].ctr.clone(),
+                ),
+            };
+            targets.push(lane.target(self.admission(listener.clone())));
+            streams.insert(listener.addr.clone(), lane);
+        }
+        self.streams = streams;
+
+        targets
+    }
+
+    /// The configured core the fewest AF_XDP lanes are pinned to, the first one on a tie
+    fn tx_core(&self) -> usize {
+        let mut best: Option<(usize, usize)> = None;
+        for core in self.config.tx_pinned_cpu_cores.iter().copied() {
+            let used = self
+                .lanes
+                .values()
+                .filter(|lane| lane.cpu == Some(core))
+                .count();
+            if best.is_none_or(|(_, best_used)| used < best_used) {
+                best = Some((core, used));
+            }
+        }
+        let (core, used) = best.unwrap_or((DEFAULT_TX_CPU_CORE, 0));
+        if used > 0 {
+            eprintln!(
+                "more AF_XDP interfaces than tx_pinned_cpu_core has cores, sharing core {core}"
+            );
+        }
+        core
+    }
+
+    fn reconcile(&mut self) {
+        let listeners = self.listeners.get();
+        let by_lane = self.resolve(&listeners);
+        self.update_states(&listeners);
+        self.update_groups(&listeners);
+
+        let mut retired = Vec::new();
+        let mut pending = Vec::new();
+        for key in self.lanes.keys().copied().collect::<Vec<_>>() {
+            if !by_lane.contains_key(&key) {
+                retired.extend(self.lanes.remove(&key));
+            }
+        }
+
+        let mut lanes = Vec::with_capacity(by_lane.len());
+        for (key @ (if_index, backend), targets) in by_lane {
+            // the kernel resolves neighbours for socket lanes by itself
+            let next_hops: HashMap<_, _> = match backend {
+                TxBackend::Xdp => targets
+                    .iter()
+                    .map(|(_, hop)| (hop.ip_addr, hop.mac_addr))
+                    .collect(),
+                TxBackend::Socket { .. } | TxBackend::Transparent => HashMap::new(),
+            };
+            let current = self.lanes.get(&key).map(|lane| &lane.next_hops);
+            if current != Some(&next_hops) {
+                let if_name = match if_indextoname(if_index) {
+                    Ok(if_name) => if_name,
+                    Err(e) => {
+                        eprintln!("failed to resolve interface {if_index}: {e}");
+                        continue;
+                    }
+                };
+                let (tx, rx) = crossbeam_channel::unbounded();
+                // a restarted lane keeps its core
+                let cpu = match self.lanes.get(&key) {
+                    Some(lane) => lane.cpu,
+                    None if backend == TxBackend::Xdp => Some(self.tx_core()),
+                    None => None,
+                };
+                let lane = Lane {
+                    if_name: if_name.clone(),
+                    tx,
+                    thread: None,
+                    next_hops,
+                    cpu,
+                };
+                retired.extend(self.lanes.insert(key, lane));
+                pending.push((key, if_name, rx));
+            }
+
+            let mut listeners: Vec<(SocketAddr, ListenerConfig)> =
+                Vec::with_capacity(targets.len());
+            for (listener, _) in targets {
+                let Some(addr) = listener.addr.udp() else {
+                    continue;
+                };
+                // suspended listeners keep their lane so that it isn't restarted on recovery
+                if self
+                    .health
+                    .as_ref()
+                    .is_some_and(|health| !health.is_up(&addr))
+                {
+                    continue;
+                }
+                if !listeners.iter().any(|(_, l)| *l == listener) {
+                    listeners.push((addr, listener));
+                }
+            }
+            lanes.push(LaneTargets {
+                tx: self.lanes[&key].tx.clone(),
+                transparent: backend == TxBackend::Transparent,
+                listeners: listeners
+                    .into_iter()
+                    .map(|(addr, listener)| ListenerTarget {
+                        addr,
+                        admission: self.admission(listener),
+                    })
+                    .collect(),
+            });
+        }
+        let streams = self.update_streams(&listeners);
+        self.last_listeners = listeners;
+        self.plan.set(ForwardPlan {
+            lanes,
+            streams,
+            global_limiter: self.global_limiter.clone(),
+        });
+
+        // the old tx_loop has to release its queue before the new one can bind to it
+        for lane in retired {
+            lane.stop();
+        }
+        for (key @ (_, backend), if_name, rx) in pending {
+            let cpu = self.lanes.get(&key).and_then(|lane| lane.cpu);
+            let thread = match (backend, cpu) {
+                (TxBackend::Xdp, Some(cpu)) => {
+                    println!("pinning the packet forwarder on {if_name} to core {cpu}");
+                    spawn_tx_loop(
+                        &self.config,
+                        if_name.clone(),
+                        cpu,
+                        rx,
+                        self.drop_sender.clone(),
+                    )
+                }
+                (TxBackend::Xdp, None) => unreachable!("AF_XDP lanes are assigned a core"),
+                (TxBackend::Socket { multicast_ttl }, _) => {
+                    spawn_socket_tx_loop(&self.config, if_name.clone(), multicast_ttl, rx)
+                }
+                (TxBackend::Transparent, _) => spawn_transparent_tx_loop(if_name.clone(), rx),
+            };
+            match thread {
+                Ok(thread) => {
+                    println!("started {backend:?} packet forwarder on {if_name}");
+                    if let Some(lane) = self.lanes.get_mut(&key) {
+                        lane.thread = Some(thread);
+                    }
+                }
+                Err(e) => eprintln!("failed to start packet forwarder on {if_name}: {e}"),
+            }
+        }
+    }
+
+    fn run(mut self, exit: Receiver<()>) -> anyhow::Result<()> {
+        let mut events = RouteEvents::subscribe()?;
+        self.reconcile();
+        while let Err(RecvTimeoutError::Timeout) = exit.recv_timeout(RESOLVE_INTERVAL) {
+            let routes_changed = events.poll_changed()?;
+            let listeners_changed = !Arc::ptr_eq(&self.listeners.get(), &self.last_listeners);
+            let unresolved = self.resolved.values().any(|hop| {
+                hop.as_ref().is_none_or(|(hop, backend)| {
+                    *backend == TxBackend::Xdp && hop.mac_addr.is_none()
+                })
+            });
+            let health_changed = self.health.as_mut().is_some_and(HealthChecker::poll);
+            if routes_changed || listeners_changed || unresolved || health_changed {
+                self.reconcile();
+            }
+        }
+
+        self.plan.set(ForwardPlan::default());
+        self.streams.clear();
+        for (if_index, group) in self.multicast_neighbours.drain() {
+            _ = self.router.del_neighbour(if_index, group);
+        }
+        for (_, lane) in self.lanes.drain() {
+            lane.stop();
+        }
+        Ok(())
+    }
+}
+
+/// Spawns the packet forwarder, which keeps one lane per outgoing interface and
+/// TX backend and routes every listener to the lane of the interface it is
+/// reachable through
+pub fn spawn_forwarder(
+    config: ForwarderConfig,
+    listeners: MaybeSharedListeners,
+    listener_ctrs: SharedListenerCtrs,
+    retransmits: SharedRetransmits,
+    drop_sender: Sender<ForwardPacket>,
+    exit: Receiver<()>,
+) -> anyhow::Result<(SharedForwardPlan, JoinHandle<()>)> {
+    let oif = config
+        .forward_iface
+        .as_deref()
+        .map(if_nametoindex)
+        .transpose()?;
+    let plan = SharedForwardPlan::default();
+    let global_limiter = RateLimiter::new(config.global_rate_limit)
+        .map(Mutex::new)
+        .map(Arc::new);
+    let health = config.health_checks.then(HealthChecker::default);
+    let forwarder = Forwarder {
+        config,
+        oif,
+        router: Router::new()?,
+        listeners,
+        plan: plan.clone(),
+        drop_sender,
+        lanes: HashMap::new(),
+        resolved: HashMap::new(),
+        multicast_neighbours: HashSet::new(),
+        last_listeners: Arc::from([]),
+        states: HashMap::new(),
+        global_limiter,
+        listener_ctrs,
+        retransmits,
+        groups: HashMap::new(),
+        health,
+        runtime: Handle::current(),
+        streams: HashMap::new(),
+    };
+    let handle = thread::spawn(move || {
+        if let Err(e) = forwarder.run(exit) {
+            eprintln!("packet forwarder stopped: {e}");
+        }
+    });
+
+    Ok((plan, handle))
+}
@@ -0,0 +1,164 @@
+use serde::{Deserialize, Serialize};
+use shredcaster_proto::hash::mix;
+
+use crate::{config::ListenerAddr, filter::PacketInfo};
+
+/// Which packets of a listener group always go to the same member
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    /// The shreds of a slot
+    #[default]
+    Slot,
+    /// The shreds of an FEC set, so a member can recover it on its own
+    FecSet,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct GroupOptions {
+    /// Listeners sharing a group split the packets they match,
+    /// each packet goes to exactly one member
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// How the group is split, `slot` if unset, the first member's is used
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub balance: Option<Balance>,
+}
+
+/// FNV-1a of the member's address, which identifies it across plan updates
+pub fn member_id(addr: &ListenerAddr) -> u64 {
+    addr.to_string()
+        .bytes()
+        .fold(0xcbf29ce484222325, |hash, b| {
+            (hash ^ u64::from(b)).wrapping_mul(0x100000001b3)
+        })
+}
+
+/// The members of a listener group that can currently be forwarded to
+///
+/// Packets go to the member with the highest hash of the packet's key and its
+/// id, so adding or removing a member only moves the packets it gains or loses
+#[derive(Debug, PartialEq, Eq)]
+pub struct ListenerGroup {
+    balance: Balance,
+    members: Vec<u64>,
+}
+
+impl ListenerGroup {
+    pub fn new(balance: Balance, members: Vec<u64>) -> Self {
+        Self { balance, members }
+    }
+
+    /// The id of the member that receives `packet`
+    pub fn owner(&self, packet: &PacketInfo) -> Option<u64> {
+        let mut key = mix(packet.slot.unwrap_or_default());
+        if self.balance == Balance::FecSet {
+            key = mix(key ^ u64::from(packet.fec_set.unwrap_or_default()));
+        }
+        self.members
+            .iter()
+            .copied()
+            .max_by_key(|member| mix(key ^ member))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, net::Ipv4Addr};
+
+    use super::*;
+    use crate::filter::Direction;
+
+    fn packet(slot: u64, fec_set: u32) -> PacketInfo<'static> {
+        PacketInfo {
+            direction: Direction::Ingress,
+            src_ip: Ipv4Addr::LOCALHOST.into(),
+            slot: Some(slot),
+            index: Some(fec_set),
+            fec_set: Some(fec_set),
+            kind: None,
+            leader: None,
+        }
+    }
+
+    fn members(count: u16) -> Vec<u64> {
+        (0..count)
+            .map(|i| {
+                let addr: ListenerAddr = format!("10.0.0.1:{}", 8000 + i).parse().unwrap();
+                member_id(&addr)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn member_ids_follow_the_address() {
+        let a: ListenerAddr = "10.0.0.1:8000".parse().unwrap();
+        let b: ListenerAddr = "10.0.0.1:8001".parse().unwrap();
+        assert_eq!(member_id(&a), member_id(&a.clone()));
+        assert_ne!(member_id(&a), member_id(&b));
+    }
+
+    #[test]
+    fn every_packet_has_one_member_of_the_group() {
+        let members = members(4);
+        let group = ListenerGroup::new(Balance::Slot, members.clone());
+        let mut received = vec![0; members.len()];
+        for slot in 0..1000 {
+            let owner = group.owner(&packet(slot, 0)).unwrap();
+            let i = members.iter().position(|&m| m == owner).unwrap();
+            received[i] += 1;
+        }
+        // every member gets a share of the slots
+        assert!(received.iter().all(|&n| n > 100), "{received:?}");
+
+        let empty = ListenerGroup::new(Balance::Slot, Vec::new());
+        assert_eq!(empty.owner(&packet(1, 0)), None);
+    }
+
+    #[test]
+    fn shreds_of_a_slot_stay_together() {
+        let group = ListenerGroup::new(Balance::Slot, members(3));
+        for slot in 0..100 {
+            let owner = group.owner(&packet(slot, 0));
+            for fec_set in (32..320).step_by(32) {
+                assert_eq!(group.owner(&packet(slot, fec_set)), owner);
+            }
+        }
+    }
+
+    #[test]
+    fn fec_sets_of_a_slot_are_spread() {
+        let group = ListenerGroup::new(Balance::FecSet, members(3));
+        let owners: HashSet<_> = (0..64)
+            .map(|set| group.owner(&packet(7, set * 32)))
+            .collect();
+        assert_eq!(owners.len(), 3);
+    }
+
+    #[test]
+    fn removing_a_member_only_moves_its_packets() {
+        for balance in [Balance::Slot, Balance::FecSet] {
+            let before = members(5);
+            let removed = before[2];
+            let after: Vec<_> = before.iter().copied().filter(|&m| m != removed).collect();
+            let before = ListenerGroup::new(balance, before);
+            let after = ListenerGroup::new(balance, after);
+
+            let mut moved = 0;
+            for slot in 1000..1200 {
+                for fec_set in [0, 32, 64] {
+                    let packet = packet(slot, fec_set);
+                    let (old, new) = (before.owner(&packet), after.owner(&packet));
+                    if old == Some(removed) {
+                        assert_ne!(new, Some(removed));
+                        moved += 1;
+                    } else {
+                        assert_eq!(old, new, "{balance:?} slot {slot} fec set {fec_set}");
+                    }
+                }
+            }
+            assert!(moved > 0);
+        }
+    }
+}
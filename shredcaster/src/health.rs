@@ -0,0 +1,274 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{Arc, atomic::Ordering},
+    time::{Duration, Instant},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::metrics::ListenerCtr;
+
+/// How often live and re-probed listeners are probed
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a re-probed listener has to stay reachable before it is re-enabled,
+/// long enough for an ARP/NDP timeout to surface as host unreachable
+const PROBE_WINDOW: Duration = Duration::from_secs(5);
+/// How long a heartbeat listener may go without echoing a heartbeat
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many probes in a row have to fail before a live listener is suspended,
+/// a single ICMP error may be a listener restarting or a transient route change
+const MAX_FAILED_PROBES: u32 = 3;
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// Prefix of heartbeat datagrams, listeners opting into heartbeats echo them back verbatim
+pub const HEARTBEAT_MAGIC: &[u8; 8] = b"SCHBEAT1";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ListenerStatus {
+    Up,
+    /// Not forwarded to until `retry_at`
+    Suspended {
+        failures: u32,
+        retry_at: Instant,
+    },
+    /// Probed again but not forwarded to until it stays reachable for `PROBE_WINDOW`
+    Probing {
+        failures: u32,
+        since: Instant,
+    },
+}
+
+fn backoff(failures: u32) -> Duration {
+    MIN_BACKOFF
+        .saturating_mul(1 << failures.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF)
+}
+
+fn is_unreachable(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::ECONNREFUSED | libc::EHOSTUNREACH | libc::ENETUNREACH | libc::EHOSTDOWN)
+    )
+}
+
+/// Tracks a single listener through a companion socket connected to it
+///
+/// The kernel reports ICMP port and host unreachable errors for the probes
+/// sent from this socket on its next send or receive
+struct ListenerHealth {
+    addr: SocketAddr,
+    socket: UdpSocket,
+    heartbeat: bool,
+    if_name: Option<String>,
+    status: ListenerStatus,
+    /// Consecutive probes of a live listener that failed
+    failed_probes: u32,
+    next_probe: Instant,
+    last_echo: Instant,
+    seq: u64,
+    ctr: Arc<ListenerCtr>,
+}
+
+impl ListenerHealth {
+    fn new(
+        addr: SocketAddr,
+        heartbeat: bool,
+        if_name: Option<String>,
+        ctr: Arc<ListenerCtr>,
+        now: Instant,
+    ) -> io::Result<Self> {
+        let (domain, bind) = match addr {
+            SocketAddr::V4(_) => (Domain::IPV4, SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))),
+            SocketAddr::V6(_) => (Domain::IPV6, SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))),
+        };
+        let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+        if let Some(if_name) = &if_name {
+            socket.bind_device(Some(if_name.as_bytes()))?;
+        }
+        socket.bind(&bind.into())?;
+        socket.connect(&addr.into())?;
+        socket.set_nonblocking(true)?;
+        ctr.suspended.store(false, Ordering::Relaxed);
+
+        Ok(Self {
+            addr,
+            socket: socket.into(),
+            heartbeat,
+            if_name,
+            status: ListenerStatus::Up,
+            failed_probes: 0,
+            next_probe: now,
+            last_echo: now,
+            seq: 0,
+            ctr,
+        })
+    }
+
+    fn probe(&mut self) -> io::Result<()> {
+        if !self.heartbeat {
+            // an empty datagram is enough to provoke ICMP errors
+            return self.socket.send(&[]).map(|_| ());
+        }
+        self.seq += 1;
+        let mut buf = [0; HEARTBEAT_MAGIC.len() + 8];
+        buf[..HEARTBEAT_MAGIC.len()].copy_from_slice(HEARTBEAT_MAGIC);
+        buf[HEARTBEAT_MAGIC.len()..].copy_from_slice(&self.seq.to_le_bytes());
+        self.socket.send(&buf).map(|_| ())
+    }
+
+    /// Drains the companion socket, returning the last unreachable error
+    fn drain(&mut self, now: Instant) -> Option<io::Error> {
+        let mut error = None;
+        let mut buf = [0; 64];
+        loop {
+            match self.socket.recv(&mut buf) {
+                Ok(len) => {
+                    if buf[..len].starts_with(HEARTBEAT_MAGIC) {
+                        self.last_echo = now;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return error,
+                Err(e) if is_unreachable(&e) => error = Some(e),
+                Err(e) => {
+                    eprintln!("health check of listener {} failed: {e}", self.addr);
+                    return error;
+                }
+            }
+        }
+    }
+
+    fn suspend(&mut self, failures: u32, reason: &str, now: Instant) {
+        let backoff = backoff(failures);
+        eprintln!(
+            "listener {} is unreachable ({reason}), suspending it for {}s",
+            self.addr,
+            backoff.as_secs()
+        );
+        self.status = ListenerStatus::Suspended {
+            failures,
+            retry_at: now + backoff,
+        };
+        self.failed_probes = 0;
+        self.ctr.suspended.store(true, Ordering::Relaxed);
+        self.ctr.suspensions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Advances the listener's status, returning whether it changed
+    fn poll(&mut self, now: Instant) -> bool {
+        let status = self.status;
+        let suspended = matches!(status, ListenerStatus::Suspended { .. });
+        // errors for probes sent before the suspension are stale
+        let mut error = self
+            .drain(now)
+            .filter(|_| !suspended)
+            .map(|e| e.to_string());
+        let mut probed = false;
+        if error.is_none() && !suspended && now >= self.next_probe {
+            self.next_probe = now + PROBE_INTERVAL;
+            probed = true;
+            if let Err(e) = self.probe() {
+                if is_unreachable(&e) {
+                    error = Some(e.to_string());
+                } else {
+                    eprintln!("failed to probe listener {}: {e}", self.addr);
+                }
+            }
+        }
+
+        match status {
+            ListenerStatus::Up => {
+                if let Some(error) = error {
+                    self.failed_probes += 1;
+                    if self.failed_probes >= MAX_FAILED_PROBES {
+                        self.suspend(1, &error, now);
+                    }
+                } else if self.heartbeat && now.duration_since(self.last_echo) > HEARTBEAT_TIMEOUT {
+                    self.suspend(1, "no heartbeat echo", now);
+                } else if probed {
+                    self.failed_probes = 0;
+                }
+            }
+            ListenerStatus::Suspended { failures, retry_at } => {
+                if now >= retry_at {
+                    self.status = ListenerStatus::Probing {
+                        failures,
+                        since: now,
+                    };
+                    self.next_probe = now;
+                }
+            }
+            ListenerStatus::Probing { failures, since } => {
+                if let Some(error) = error {
+                    self.suspend(failures + 1, &error, now);
+                } else if now.duration_since(since) >= PROBE_WINDOW {
+                    if self.heartbeat && self.last_echo < since {
+                        self.suspend(failures + 1, "no heartbeat echo", now);
+                    } else {
+                        println!("listener {} is reachable again", self.addr);
+                        self.status = ListenerStatus::Up;
+                        self.ctr.suspended.store(false, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+
+        (self.status == ListenerStatus::Up) != (status == ListenerStatus::Up)
+    }
+}
+
+/// Probes the listeners opting into it and suspends the ones that became
+/// unreachable, re-probing them with exponential backoff
+#[derive(Default)]
+pub struct HealthChecker {
+    listeners: HashMap<SocketAddr, ListenerHealth>,
+}
+
+impl HealthChecker {
+    /// Starts tracking new listeners and stops tracking removed ones, each is
+    /// probed through the interface it is forwarded through if known
+    pub fn update(
+        &mut self,
+        listeners: impl Iterator<Item = (SocketAddr, bool, Option<String>, Arc<ListenerCtr>)>,
+    ) {
+        let now = Instant::now();
+        let mut tracked = HashMap::with_capacity(self.listeners.len());
+        for (addr, heartbeat, if_name, ctr) in listeners {
+            if tracked.contains_key(&addr) {
+                continue;
+            }
+            if let Some(health) = self.listeners.remove(&addr)
+                && health.heartbeat == heartbeat
+                && health.if_name == if_name
+            {
+                tracked.insert(addr, health);
+                continue;
+            }
+            match ListenerHealth::new(addr, heartbeat, if_name, ctr, now) {
+                Ok(health) => {
+                    tracked.insert(addr, health);
+                }
+                Err(e) => eprintln!("cannot track health of listener {addr}: {e}"),
+            }
+        }
+        self.listeners = tracked;
+    }
+
+    /// Probes all listeners, returning whether any was suspended or re-enabled
+    pub fn poll(&mut self) -> bool {
+        let now = Instant::now();
+        let mut changed = false;
+        for health in self.listeners.values_mut() {
+            changed |= health.poll(now);
+        }
+        changed
+    }
+
+    /// Untracked listeners are considered up
+    pub fn is_up(&self, addr: &SocketAddr) -> bool {
+        self.listeners
+            .get(addr)
+            .is_none_or(|health| health.status == ListenerStatus::Up)
+    }
+}
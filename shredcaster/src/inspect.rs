@@ -0,0 +1,220 @@
+use std::{
+    fmt,
+    io::{self, BufWriter, Write},
+    net::SocketAddr,
+    ops::{Bound, RangeBounds},
+};
+
+use clap::Args;
+use ipnet::IpNet;
+use serde::Serialize;
+use shredcaster_proto::shred;
+use solana_ledger::shred::layout;
+use tokio::sync::oneshot;
+
+use crate::{
+    CapturedPacket,
+    filter::{Direction, ListenerFilter, PacketInfo, ShredKind},
+    leader_schedule::LeaderSchedule,
+    sink::ShredSink,
+};
+
+/// Options of `shredcaster inspect`, which captures from `--source`
+/// like forwarding does and prints the shreds instead
+#[derive(Args, Clone, Debug)]
+pub struct InspectArgs {
+    /// Only prints shreds of this slot and later ones
+    #[arg(long)]
+    pub from_slot: Option<u64>,
+    /// Only prints shreds of this slot and earlier ones
+    #[arg(long)]
+    pub to_slot: Option<u64>,
+    /// Only prints shreds of this type
+    #[arg(long, value_enum)]
+    pub shred_type: Option<ShredKind>,
+    /// Only prints packets captured in this direction
+    #[arg(long, value_enum)]
+    pub direction: Option<Direction>,
+    /// Only prints packets sent from these networks, e.g. 10.0.0.0/8
+    #[arg(long)]
+    pub source_cidrs: Vec<IpNet>,
+    /// Prints a JSON object per packet instead of a line
+    #[arg(long, default_value_t = false)]
+    pub json: bool,
+}
+
+impl InspectArgs {
+    /// The sink printing the shreds, and a receiver told once stdout was closed
+    pub fn inspector(&self) -> (Inspector, oneshot::Receiver<()>) {
+        let (closed_tx, closed_rx) = oneshot::channel();
+        let inspector = Inspector {
+            filter: ListenerFilter {
+                direction: self.direction,
+                shred_type: self.shred_type,
+                source_cidrs: self.source_cidrs.clone(),
+                ..ListenerFilter::default()
+            },
+            slots: (
+                self.from_slot.map_or(Bound::Unbounded, Bound::Included),
+                self.to_slot.map_or(Bound::Unbounded, Bound::Included),
+            ),
+            json: self.json,
+            leader_schedule: LeaderSchedule::default(),
+            closed: Some(closed_tx),
+        };
+        (inspector, closed_rx)
+    }
+}
+
+/// Prints the packets matching its filters to stdout, like tcpdump does
+pub struct Inspector {
+    filter: ListenerFilter,
+    slots: (Bound<u64>, Bound<u64>),
+    json: bool,
+    leader_schedule: LeaderSchedule,
+    /// Taken once stdout is closed, e.g. by `| head`
+    closed: Option<oneshot::Sender<()>>,
+}
+
+impl Inspector {
+    fn matches(&self, info: &PacketInfo) -> bool {
+        let any_slot = self.slots == (Bound::Unbounded, Bound::Unbounded);
+        self.filter.matches(info)
+            && (any_slot || info.slot.is_some_and(|slot| self.slots.contains(&slot)))
+    }
+
+    fn print(&self, packets: &[CapturedPacket]) -> io::Result<()> {
+        let mut out = BufWriter::new(io::stdout().lock());
+        for packet in packets {
+            let shred = packet.data.as_ref();
+            let info = PacketInfo::new(
+                shred,
+                packet.is_egress,
+                packet.src.ip(),
+                &self.leader_schedule,
+            );
+            if !self.matches(&info) {
+                continue;
+            }
+            let inspected = InspectedPacket::new(packet, &info);
+            if self.json {
+                let json = sonic_rs::to_string(&inspected).map_err(io::Error::other)?;
+                writeln!(out, "{json}")?;
+            } else {
+                writeln!(out, "{inspected}")?;
+            }
+        }
+        out.flush()
+    }
+}
+
+impl ShredSink for Inspector {
+    fn send(&mut self, packets: &[CapturedPacket]) {
+        if self.closed.is_none() {
+            return;
+        }
+        if let Err(e) = self.print(packets) {
+            if e.kind() != io::ErrorKind::BrokenPipe {
+                eprintln!("failed to print shreds: {e}");
+            }
+            if let Some(closed) = self.closed.take() {
+                _ = closed.send(());
+            }
+        }
+    }
+}
+
+/// What the inspector prints about a packet, the shred fields are unset
+/// for packets that aren't shreds
+#[derive(Serialize)]
+struct InspectedPacket<'a> {
+    ts_ns: u64,
+    direction: Direction,
+    src: SocketAddr,
+    dst: Option<SocketAddr>,
+    /// The host and interface an upstream shredcaster captured the packet on
+    host_id: Option<u64>,
+    iface: Option<&'a str>,
+    len: usize,
+    slot: Option<u64>,
+    index: Option<u32>,
+    fec_set: Option<u32>,
+    shred_type: Option<ShredKind>,
+    variant: Option<&'static str>,
+    proof_size: Option<u8>,
+    parent_offset: Option<u16>,
+    flags: Option<u8>,
+    merkle_root: Option<String>,
+}
+
+impl<'a> InspectedPacket<'a> {
+    fn new(packet: &'a CapturedPacket, info: &PacketInfo) -> Self {
+        let shred = packet.data.as_ref();
+        let variant = shred::variant(shred);
+        let is_shred = variant.is_some();
+        let proof_size = variant.and_then(|variant| variant.proof_size);
+        Self {
+            ts_ns: packet.ts_ns,
+            direction: info.direction,
+            src: packet.src,
+            dst: packet.dst,
+            host_id: packet.origin.as_ref().map(|origin| origin.host_id),
+            iface: packet.origin.as_ref().map(|origin| &*origin.iface),
+            len: shred.len(),
+            slot: info.slot.filter(|_| is_shred),
+            index: info.index.filter(|_| is_shred),
+            fec_set: info.fec_set.filter(|_| is_shred),
+            shred_type: info.kind,
+            variant: variant.map(|variant| variant.name),
+            proof_size,
+            parent_offset: shred::parent_offset(shred),
+            flags: shred::data_flags(shred),
+            merkle_root: proof_size
+                .and_then(|_| layout::get_merkle_root(shred))
+                .map(|root| root.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for InspectedPacket<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = match self.direction {
+            Direction::Ingress => "ingress",
+            Direction::Egress => "egress",
+        };
+        write!(
+            f,
+            "{}.{:09} {direction} {} > ",
+            self.ts_ns / 1_000_000_000,
+            self.ts_ns % 1_000_000_000,
+            self.src,
+        )?;
+        match self.dst {
+            Some(dst) => write!(f, "{dst}")?,
+            None => write!(f, "?")?,
+        }
+        if let (Some(host_id), Some(iface)) = (self.host_id, self.iface) {
+            write!(f, " on {iface} of host {host_id:016x}")?;
+        }
+        write!(f, ", {} bytes", self.len)?;
+        let (Some(variant), Some(slot), Some(index), Some(fec_set)) =
+            (self.variant, self.slot, self.index, self.fec_set)
+        else {
+            return write!(f, ", not a shred");
+        };
+        write!(f, ", slot {slot} index {index} fec_set {fec_set} {variant}")?;
+        if let Some(proof_size) = self.proof_size {
+            write!(f, " proof_size {proof_size}")?;
+        }
+        if let Some(parent_offset) = self.parent_offset {
+            write!(f, " parent_offset {parent_offset}")?;
+        }
+        if let Some(flags) = self.flags {
+            write!(f, " flags {flags:#04x}")?;
+        }
+        if let Some(merkle_root) = &self.merkle_root {
+            write!(f, " merkle_root {merkle_root}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use solana_pubkey::Pubkey;
+use tokio::time::sleep;
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The leaders of one epoch
+struct EpochLeaders {
+    first_slot: u64,
+    keys: Vec<Pubkey>,
+    /// Index into `keys` for every slot of the epoch
+    slot_leaders: Vec<u16>,
+}
+
+impl EpochLeaders {
+    fn from_rpc(first_slot: u64, schedule: HashMap<String, Vec<u64>>) -> anyhow::Result<Self> {
+        let mut keys = Vec::with_capacity(schedule.len());
+        let mut slot_leaders = Vec::new();
+        for (key, slots) in schedule {
+            let idx = u16::try_from(keys.len())?;
+            keys.push(key.parse()?);
+            for slot in slots {
+                let slot = slot as usize;
+                if slot >= slot_leaders.len() {
+                    slot_leaders.resize(slot + 1, u16::MAX);
+                }
+                slot_leaders[slot] = idx;
+            }
+        }
+        Ok(Self {
+            first_slot,
+            keys,
+            slot_leaders,
+        })
+    }
+
+    fn leader(&self, slot: u64) -> Option<&Pubkey> {
+        let idx = slot.checked_sub(self.first_slot)?;
+        let key = *self.slot_leaders.get(idx as usize)?;
+        self.keys.get(key as usize)
+    }
+}
+
+/// The leader schedules of the current and next epoch
+#[derive(Default)]
+pub struct LeaderSchedule {
+    epochs: Vec<Arc<EpochLeaders>>,
+}
+
+impl LeaderSchedule {
+    pub fn leader(&self, slot: u64) -> Option<&Pubkey> {
+        self.epochs.iter().find_map(|epoch| epoch.leader(slot))
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct SharedLeaderSchedule(Arc<RwLock<Arc<LeaderSchedule>>>);
+
+impl SharedLeaderSchedule {
+    pub fn get(&self) -> Arc<LeaderSchedule> {
+        self.0.read().unwrap().clone()
+    }
+}
+
+#[derive(Serialize)]
+struct RpcRequest<'a, P> {
+    jsonrpc: &'static str,
+    id: u64,
+    method: &'a str,
+    params: P,
+}
+
+#[derive(Deserialize)]
+struct RpcResponse<T> {
+    result: T,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EpochInfo {
+    absolute_slot: u64,
+    slot_index: u64,
+    slots_in_epoch: u64,
+}
+
+async fn rpc_call<P: Serialize, T: for<'de> Deserialize<'de>>(
+    client: &reqwest::Client,
+    rpc_url: &str,
+    method: &str,
+    params: P,
+) -> anyhow::Result<T> {
+    let body = sonic_rs::to_vec(&RpcRequest {
+        jsonrpc: "2.0",
+        id: 1,
+        method,
+        params,
+    })?;
+    let res = client
+        .post(rpc_url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    let res: RpcResponse<T> = sonic_rs::from_slice(&res)?;
+    Ok(res.result)
+}
+
+async fn fetch_leader_schedule(
+    client: &reqwest::Client,
+    rpc_url: &str,
+    current: &LeaderSchedule,
+) -> anyhow::Result<LeaderSchedule> {
+    let info: EpochInfo = rpc_call(client, rpc_url, "getEpochInfo", [(); 0]).await?;
+    let first_slot = info.absolute_slot - info.slot_index;
+
+    let mut epochs = Vec::with_capacity(2);
+    for first_slot in [first_slot, first_slot + info.slots_in_epoch] {
+        if let Some(epoch) = current
+            .epochs
+            .iter()
+            .find(|epoch| epoch.first_slot == first_slot)
+        {
+            epochs.push(epoch.clone());
+            continue;
+        }
+        // the next epoch's schedule is null until it is known
+        let schedule: Option<HashMap<String, Vec<u64>>> =
+            rpc_call(client, rpc_url, "getLeaderSchedule", [first_slot]).await?;
+        if let Some(schedule) = schedule {
+            epochs.push(Arc::new(EpochLeaders::from_rpc(first_slot, schedule)?));
+        }
+    }
+
+    Ok(LeaderSchedule { epochs })
+}
+
+/// Periodically fetches the leader schedule from `rpc_url`
+pub fn spawn_leader_schedule_updater(rpc_url: String) -> SharedLeaderSchedule {
+    let schedule = SharedLeaderSchedule::default();
+    let schedule_c = schedule.clone();
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        loop {
+            let current = schedule_c.get();
+            match fetch_leader_schedule(&client, &rpc_url, &current).await {
+                Ok(leaders) => *schedule_c.0.write().unwrap() = Arc::new(leaders),
+                Err(e) => eprintln!("failed to fetch leader schedule: {e}"),
+            }
+            sleep(REFRESH_INTERVAL).await;
+        }
+    });
+
+    schedule
+}
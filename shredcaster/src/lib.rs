@@ -0,0 +1,594 @@
+pub mod af_packet;
+pub mod archive;
+mod bpf;
+pub mod bundle;
+pub mod capture;
+pub mod config;
+mod dns;
+pub mod export;
+pub mod fec;
+pub mod filter;
+mod forwarder;
+pub mod group;
+mod health;
+pub mod inspect;
+mod leader_schedule;
+mod metrics;
+pub mod multicast;
+mod nack;
+pub mod pcap;
+pub mod rate_limit;
+pub mod relay;
+mod route;
+pub mod shred_sampler;
+pub mod sink;
+pub mod source;
+pub mod stream;
+mod transparent;
+
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    thread::{self},
+    time::{Duration, Instant},
+};
+
+use arrayvec::ArrayVec;
+use crossbeam_channel::TryRecvError;
+use tokio::{
+    sync::{broadcast, oneshot, watch},
+    task::JoinHandle,
+    time::sleep_until,
+};
+
+use crate::{
+    bpf::BpfSource,
+    capture::{CaptureContext, Origin, default_host_id},
+    config::{MaybeSharedListeners, check_listeners},
+    dns::spawn_listener_resolver,
+    forwarder::{DEFAULT_TX_CPU_CORE, ForwardSink, ForwarderConfig, spawn_forwarder},
+    leader_schedule::{SharedLeaderSchedule, spawn_leader_schedule_updater},
+    metrics::{
+        PacketCtr, SharedListenerCtrs, SharedPacketCtr, serve_metrics,
+        start_packet_counter_print_loop,
+    },
+    nack::{SharedRetransmits, serve_nacks},
+    rate_limit::RateLimit,
+    sink::ShredSink,
+    source::PacketSource,
+};
+
+pub const PACKET_DATA_SIZE: usize = 1232;
+
+#[derive(Clone)]
+pub struct SharedPacketData(pub Arc<ArrayVec<u8, PACKET_DATA_SIZE>>);
+
+impl SharedPacketData {
+    pub fn new(data: ArrayVec<u8, PACKET_DATA_SIZE>) -> Self {
+        Self(Arc::new(data))
+    }
+}
+
+impl AsRef<[u8]> for SharedPacketData {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+/// A packet seen at the TVU, as handed to every [`ShredSink`]
+#[derive(Clone)]
+pub struct CapturedPacket {
+    /// Unix time the packet was captured at, in nanoseconds
+    pub ts_ns: u64,
+    /// The address the packet was sent from
+    pub src: SocketAddr,
+    /// The address the packet was sent to, `None` if the source doesn't know it
+    pub dst: Option<SocketAddr>,
+    pub is_egress: bool,
+    /// Where an upstream shredcaster captured the packet, `None` if it was captured here
+    pub origin: Option<Origin>,
+    pub data: SharedPacketData,
+}
+
+/// Fans the packets of a source out to the sinks
+struct TurbineWatcher {
+    source: Box<dyn PacketSource>,
+    sinks: Vec<Box<dyn ShredSink>>,
+    packet_counter: SharedPacketCtr,
+}
+
+/// How long a stopping watcher waits for the sinks to send what they buffered
+const FINAL_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+async fn turbine_watcher_loop(
+    watcher: TurbineWatcher,
+    mut exit: oneshot::Receiver<()>,
+) -> anyhow::Result<()> {
+    let TurbineWatcher {
+        mut source,
+        mut sinks,
+        packet_counter,
+    } = watcher;
+    let mut batch = Vec::new();
+    // set once a source that ends, e.g. a file, has no more packets
+    let mut exhausted = false;
+    // when the sinks next have to flush what they buffered
+    let mut flush_at: Option<Instant> = None;
+    let flush = |sinks: &mut [Box<dyn ShredSink>], now| {
+        sinks.iter_mut().filter_map(|sink| sink.flush(now)).min()
+    };
+
+    loop {
+        if exhausted && flush_at.is_none() {
+            println!("packet source has no more packets");
+            break;
+        }
+        let flush_deadline = flush_at.unwrap_or_else(Instant::now).into();
+        tokio::select! {
+            _ = &mut exit => {
+                // send what the sinks still buffer, e.g. bundles and FEC parity
+                let give_up = Instant::now() + FINAL_FLUSH_TIMEOUT;
+                while let Some(at) = flush_at.filter(|at| *at <= give_up) {
+                    sleep_until(at.into()).await;
+                    flush_at = flush(&mut sinks, Instant::now());
+                }
+                break;
+            }
+            _ = sleep_until(flush_deadline), if flush_at.is_some() => {
+                flush_at = flush(&mut sinks, Instant::now());
+            }
+            more = source.recv(&mut batch), if !exhausted => {
+                exhausted = !more?;
+
+                let mut ingress_packets = 0;
+                let mut egress_packets = 0;
+                let mut ipv6_packets = 0;
+                for packet in batch.iter() {
+                    if packet.is_egress {
+                        egress_packets += 1;
+                    } else {
+                        ingress_packets += 1;
+                    }
+                    if packet.src.is_ipv6() {
+                        ipv6_packets += 1;
+                    }
+                }
+                for sink in sinks.iter_mut() {
+                    sink.send(&batch);
+                }
+                flush_at = flush(&mut sinks, Instant::now());
+                packet_counter.add(egress_packets, ingress_packets, ipv6_packets);
+                batch.clear();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Also returns a receiver that turns true once the watcher stopped
+fn spawn_turbine_watcher(
+    watcher: TurbineWatcher,
+    exit: oneshot::Receiver<()>,
+) -> (JoinHandle<()>, watch::Receiver<bool>) {
+    let (stopped_tx, stopped_rx) = watch::channel(false);
+    let handle = tokio::spawn(async move {
+        if let Err(e) = turbine_watcher_loop(watcher, exit).await {
+            eprintln!("turbine watcher stopped: {e}");
+        }
+        _ = stopped_tx.send(true);
+    });
+    (handle, stopped_rx)
+}
+
+/// Configures a [`Shredcaster`] before attaching it, see [`Shredcaster::builder`]
+pub struct ShredcasterBuilder {
+    source: Option<Box<dyn PacketSource>>,
+    tvu_ports: Vec<u16>,
+    iface: Option<String>,
+    egress_iface: Option<String>,
+    watch_egress: bool,
+    egress_port: Option<u16>,
+    listeners: MaybeSharedListeners,
+    forwarder: ForwarderConfig,
+    sinks: Vec<Box<dyn ShredSink>>,
+    rpc_url: Option<String>,
+    host_id: Option<u64>,
+    metrics_addr: Option<SocketAddr>,
+    nack_addr: Option<SocketAddr>,
+    dns_refresh: Duration,
+    dns_min_ttl: Duration,
+    packet_capacity: Option<usize>,
+    print_counters: bool,
+}
+
+impl Default for ShredcasterBuilder {
+    fn default() -> Self {
+        Self {
+            source: None,
+            tvu_ports: Vec::new(),
+            iface: None,
+            egress_iface: None,
+            watch_egress: false,
+            egress_port: None,
+            listeners: MaybeSharedListeners::Static(Arc::new([])),
+            forwarder: ForwarderConfig {
+                forward_iface: None,
+                forwarder_port: 9122,
+                tx_pinned_cpu_cores: vec![DEFAULT_TX_CPU_CORE],
+                global_rate_limit: RateLimit::default(),
+                health_checks: true,
+                kernel_tx: false,
+            },
+            sinks: Vec::new(),
+            rpc_url: None,
+            host_id: None,
+            metrics_addr: None,
+            nack_addr: None,
+            dns_refresh: Duration::from_secs(30),
+            dns_min_ttl: Duration::from_secs(5),
+            packet_capacity: None,
+            print_counters: false,
+        }
+    }
+}
+
+impl ShredcasterBuilder {
+    /// Where packets are captured from, the BPF programs attached to
+    /// [`Self::iface`] and [`Self::tvu_ports`] if unset
+    pub fn source(mut self, source: impl PacketSource + 'static) -> Self {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    /// The network interface to attach to, also recorded in envelopes
+    pub fn iface(mut self, iface: impl Into<String>) -> Self {
+        self.iface = Some(iface.into());
+        self
+    }
+
+    /// The TVU ports to monitor, between 1 and 100
+    pub fn tvu_ports(mut self, ports: impl IntoIterator<Item = u16>) -> Self {
+        self.tvu_ports.extend(ports);
+        self
+    }
+
+    /// Watches turbine egress traffic on `iface`, or on the ingress interface if
+    /// `None`, optionally only the traffic from `port` (experimental)
+    pub fn watch_egress(mut self, iface: Option<String>, port: Option<u16>) -> Self {
+        self.watch_egress = true;
+        self.egress_iface = iface;
+        self.egress_port = port;
+        self
+    }
+
+    /// Where packets are forwarded to, shared listeners are reconciled whenever they're swapped
+    /// and the initial listeners may not share an address
+    pub fn listeners(mut self, listeners: impl Into<MaybeSharedListeners>) -> Self {
+        self.listeners = listeners.into();
+        self
+    }
+
+    /// Restricts forwarding to this interface instead of following the routing table
+    pub fn forward_iface(mut self, iface: impl Into<String>) -> Self {
+        self.forwarder.forward_iface = Some(iface.into());
+        self
+    }
+
+    /// The port packets are forwarded from, 9122 if unset
+    pub fn forwarder_port(mut self, port: u16) -> Self {
+        self.forwarder.forwarder_port = port;
+        self
+    }
+
+    /// The CPU core the TX thread is pinned to, 2 if unset
+    pub fn tx_pinned_cpu_core(self, core: usize) -> Self {
+        self.tx_pinned_cpu_cores([core])
+    }
+
+    /// The CPU cores the TX threads are pinned to, each interface forwarded
+    /// through with AF_XDP gets one, shared once there are more interfaces than cores
+    pub fn tx_pinned_cpu_cores(mut self, cores: impl IntoIterator<Item = usize>) -> Self {
+        let cores: Vec<usize> = cores.into_iter().collect();
+        if !cores.is_empty() {
+            self.forwarder.tx_pinned_cpu_cores = cores;
+        }
+        self
+    }
+
+    /// Limits what is forwarded to all listeners combined
+    pub fn global_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.forwarder.global_rate_limit = rate_limit;
+        self
+    }
+
+    /// Whether to probe the listeners that set `health_check` or `heartbeat`
+    /// and suspend the unreachable ones, on by default
+    pub fn health_checks(mut self, enabled: bool) -> Self {
+        self.forwarder.health_checks = enabled;
+        self
+    }
+
+    /// Sends to IPv4 listeners through kernel sockets instead of AF_XDP,
+    /// which works without root at a higher cost per packet
+    pub fn kernel_tx(mut self, enabled: bool) -> Self {
+        self.forwarder.kernel_tx = enabled;
+        self
+    }
+
+    /// Also hands every captured packet to `sink`, after the listeners were forwarded to
+    pub fn sink(mut self, sink: impl ShredSink + 'static) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    /// RPC endpoint used to fetch the leader schedule
+    pub fn rpc_url(mut self, rpc_url: impl Into<String>) -> Self {
+        self.rpc_url = Some(rpc_url.into());
+        self
+    }
+
+    /// Identifies this instance in envelopes, derived from the hostname if unset
+    pub fn host_id(mut self, host_id: u64) -> Self {
+        self.host_id = Some(host_id);
+        self
+    }
+
+    /// Serves prometheus metrics on `addr`
+    pub fn metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Serves NACKs of listeners with a `nack_window_ms` on `addr`
+    pub fn nack_addr(mut self, addr: SocketAddr) -> Self {
+        self.nack_addr = Some(addr);
+        self
+    }
+
+    /// Bounds how long the addresses of UDP listener hostnames are used for
+    pub fn dns_ttl(mut self, refresh: Duration, min_ttl: Duration) -> Self {
+        self.dns_refresh = refresh;
+        self.dns_min_ttl = min_ttl;
+        self
+    }
+
+    /// Publishes every captured packet to [`Shredcaster::subscribe`], subscribers
+    /// more than `capacity` packets behind skip the oldest ones
+    pub fn packet_stream(mut self, capacity: usize) -> Self {
+        self.packet_capacity = Some(capacity);
+        self
+    }
+
+    /// Prints the packet counters to the terminal
+    pub fn print_counters(mut self, enabled: bool) -> Self {
+        self.print_counters = enabled;
+        self
+    }
+
+    /// Attaches the BPF programs unless another source was set and starts
+    /// forwarding, must be called from within a tokio runtime
+    pub async fn start(self) -> anyhow::Result<Shredcaster> {
+        let Self {
+            source,
+            tvu_ports,
+            iface,
+            egress_iface,
+            watch_egress,
+            egress_port,
+            listeners,
+            forwarder,
+            sinks,
+            rpc_url,
+            host_id,
+            metrics_addr,
+            nack_addr,
+            dns_refresh,
+            dns_min_ttl,
+            packet_capacity,
+            print_counters,
+        } = self;
+        check_listeners(&listeners.get())?;
+
+        let source = match source {
+            Some(source) => source,
+            None => {
+                let iface = iface
+                    .as_deref()
+                    .ok_or_else(|| anyhow::anyhow!("must specify an interface to watch"))?;
+                let egress_iface = egress_iface.as_deref().unwrap_or(iface);
+                Box::new(BpfSource::attach(
+                    iface,
+                    &tvu_ports,
+                    watch_egress.then_some((egress_iface, egress_port)),
+                )?)
+            }
+        };
+        let source_metrics = source.metrics();
+        let iface = iface.unwrap_or_default();
+        let egress_iface = egress_iface.unwrap_or_else(|| iface.clone());
+
+        let (exit_tx, exit_rx) = oneshot::channel();
+
+        let packet_counter = Arc::new(PacketCtr::default());
+        let listener_ctrs = SharedListenerCtrs::default();
+
+        let (drop_sender, drop_rx) = crossbeam_channel::unbounded();
+        let (fwd_exit_tx, fwd_exit_rx) = crossbeam_channel::bounded(1);
+
+        let leader_schedule = match rpc_url {
+            Some(rpc_url) => spawn_leader_schedule_updater(rpc_url),
+            None => {
+                if listeners
+                    .get()
+                    .iter()
+                    .any(|listener| !listener.filter.leaders.is_empty())
+                {
+                    eprintln!("rpc_url is not set, listeners filtering on leaders receive nothing");
+                }
+                SharedLeaderSchedule::default()
+            }
+        };
+
+        let capture_context = Arc::new(CaptureContext::new(
+            host_id.unwrap_or_else(default_host_id),
+            iface,
+            egress_iface,
+        ));
+
+        let (listeners, listener_resolver) =
+            spawn_listener_resolver(listeners, dns_refresh, dns_min_ttl).await?;
+        let retransmits = SharedRetransmits::default();
+        let (forward_plan, pkt_fwder) = spawn_forwarder(
+            forwarder,
+            listeners,
+            listener_ctrs.clone(),
+            retransmits.clone(),
+            drop_sender,
+            fwd_exit_rx,
+        )?;
+        let packets = packet_capacity.map(|capacity| broadcast::channel(capacity).0);
+        let mut watcher = TurbineWatcher {
+            source,
+            sinks: vec![Box::new(ForwardSink {
+                plan: forward_plan,
+                leader_schedule,
+                capture_context: capture_context.clone(),
+            })],
+            packet_counter: packet_counter.clone(),
+        };
+        for mut sink in sinks {
+            sink.attach(&capture_context);
+            watcher.sinks.push(sink);
+        }
+        if let Some(packets) = packets.clone() {
+            watcher.sinks.push(Box::new(packets));
+        }
+        let (turbine_loop, watcher_stopped) = spawn_turbine_watcher(watcher, exit_rx);
+
+        let metrics_server = metrics_addr.map(|addr| {
+            let packet_counter = packet_counter.clone();
+            let listener_ctrs = listener_ctrs.clone();
+            tokio::spawn(async move {
+                if let Err(e) =
+                    serve_metrics(addr, packet_counter, listener_ctrs, source_metrics).await
+                {
+                    eprintln!("metrics server stopped: {e}");
+                }
+            })
+        });
+
+        let nack_server = nack_addr.map(|addr| {
+            tokio::spawn(async move {
+                if let Err(e) = serve_nacks(addr, retransmits).await {
+                    eprintln!("NACK server stopped: {e}");
+                }
+            })
+        });
+
+        let pkt_counter_loop = print_counters.then(|| {
+            tokio::spawn(async move {
+                if let Err(e) = start_packet_counter_print_loop(packet_counter, listener_ctrs).await
+                {
+                    eprintln!("packet metrics stopped: {e}");
+                }
+            })
+        });
+
+        let pkt_dropper = std::thread::spawn(move || {
+            loop {
+                match drop_rx.try_recv() {
+                    Ok(i) => {
+                        drop(i);
+                    }
+                    Err(TryRecvError::Empty) => {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            }
+        });
+
+        Ok(Shredcaster {
+            exit_tx,
+            turbine_loop,
+            watcher_stopped,
+            fwd_exit_tx,
+            pkt_fwder,
+            pkt_dropper,
+            metrics_server,
+            listener_resolver,
+            nack_server,
+            pkt_counter_loop,
+            packets,
+        })
+    }
+}
+
+/// A running shredcaster, the source is closed once it is stopped
+pub struct Shredcaster {
+    exit_tx: oneshot::Sender<()>,
+    turbine_loop: JoinHandle<()>,
+    watcher_stopped: watch::Receiver<bool>,
+    fwd_exit_tx: crossbeam_channel::Sender<()>,
+    pkt_fwder: thread::JoinHandle<()>,
+    pkt_dropper: thread::JoinHandle<()>,
+    metrics_server: Option<JoinHandle<()>>,
+    listener_resolver: Option<JoinHandle<()>>,
+    nack_server: Option<JoinHandle<()>>,
+    pkt_counter_loop: Option<JoinHandle<()>>,
+    packets: Option<broadcast::Sender<CapturedPacket>>,
+}
+
+impl Shredcaster {
+    pub fn builder() -> ShredcasterBuilder {
+        ShredcasterBuilder::default()
+    }
+
+    /// Receives the packets captured from now on, `None` unless the builder
+    /// enabled [`ShredcasterBuilder::packet_stream`]
+    pub fn subscribe(&self) -> Option<broadcast::Receiver<CapturedPacket>> {
+        self.packets.as_ref().map(broadcast::Sender::subscribe)
+    }
+
+    /// Waits until nothing more is captured, because the source ended, e.g.
+    /// a file was read to its end, or failed
+    pub async fn finished(&self) {
+        _ = self
+            .watcher_stopped
+            .clone()
+            .wait_for(|stopped| *stopped)
+            .await;
+    }
+
+    /// Stops capturing, waits for the queued packets to be forwarded and detaches
+    pub async fn stop(self) -> anyhow::Result<()> {
+        _ = self.exit_tx.send(());
+
+        self.turbine_loop.await?;
+        _ = self.fwd_exit_tx.send(());
+        // the metrics server holds a packet counter reference the print loop waits on
+        if let Some(metrics_server) = self.metrics_server {
+            metrics_server.abort();
+            _ = metrics_server.await;
+        }
+        if let Some(listener_resolver) = self.listener_resolver {
+            listener_resolver.abort();
+        }
+        if let Some(nack_server) = self.nack_server {
+            nack_server.abort();
+            _ = nack_server.await;
+        }
+        if let Some(pkt_counter_loop) = self.pkt_counter_loop {
+            pkt_counter_loop.await?;
+        }
+        self.pkt_fwder
+            .join()
+            .map_err(|e| anyhow::anyhow!("packet forwarder panicked: {e:?}"))?;
+        self.pkt_dropper
+            .join()
+            .map_err(|e| anyhow::anyhow!("packet dropper panicked: {e:?}"))?;
+
+        Ok(())
+    }
+}
@@ -1,14 +1,22 @@
 use std::{
+    fmt::Write as _,
     io::{self, Write},
+    net::SocketAddr,
     sync::{
-        Arc,
-        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
     },
     time::Duration,
 };
 
 use crossterm::{ExecutableCommand, cursor, terminal};
-use tokio::time::sleep;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    time::sleep,
+};
+
+use crate::{config::ListenerAddr, source::SourceMetrics};
 
 pub type SharedPacketCtr = Arc<PacketCtr>;
 
@@ -16,24 +24,84 @@ pub type SharedPacketCtr = Arc<PacketCtr>;
 pub struct PacketCtr {
     ingress: AtomicUsize,
     egress: AtomicUsize,
+    ipv6: AtomicUsize,
 }
 
 impl PacketCtr {
-    pub fn add(&self, egress_packets: usize, ingress_packets: usize) {
+    pub fn add(&self, egress_packets: usize, ingress_packets: usize, ipv6_packets: usize) {
         self.egress.fetch_add(egress_packets, Ordering::SeqCst);
         self.ingress.fetch_add(ingress_packets, Ordering::SeqCst);
+        self.ipv6.fetch_add(ipv6_packets, Ordering::SeqCst);
+    }
+}
+
+/// Delivery counters of a single listener
+#[derive(Default)]
+pub struct ListenerCtr {
+    pub forwarded_packets: AtomicUsize,
+    pub forwarded_bytes: AtomicUsize,
+    /// Packets dropped by the listener's own rate limit
+    pub throttled_packets: AtomicUsize,
+    /// Packets dropped by the global egress budget
+    pub global_throttled_packets: AtomicUsize,
+    /// Packets dropped because a QUIC or TCP listener fell behind
+    pub lagged_packets: AtomicUsize,
+    /// Whether the listener is currently suspended as unreachable
+    pub suspended: AtomicBool,
+    pub suspensions: AtomicUsize,
+    /// Retransmission requests received from the listener
+    pub nacks: AtomicUsize,
+    /// Packets the listener asked for again
+    pub nacked_packets: AtomicUsize,
+    /// Datagrams sent again, bundles count once
+    pub resent_datagrams: AtomicUsize,
+}
+
+impl ListenerCtr {
+    fn throttled(&self) -> usize {
+        self.throttled_packets.load(Ordering::Relaxed)
+            + self.global_throttled_packets.load(Ordering::Relaxed)
     }
 }
 
-pub async fn start_packet_counter_print_loop(this: SharedPacketCtr) -> anyhow::Result<()> {
+type ListenerCtrs = Vec<(ListenerAddr, Arc<ListenerCtr>)>;
+type ListenerCtrField = fn(&ListenerCtr) -> &AtomicUsize;
+
+/// The counters of the current listeners, kept up to date by the forwarder
+#[derive(Clone, Default)]
+pub struct SharedListenerCtrs(Arc<RwLock<ListenerCtrs>>);
+
+impl SharedListenerCtrs {
+    pub fn set(&self, ctrs: ListenerCtrs) {
+        *self.0.write().unwrap() = ctrs;
+    }
+
+    fn throttled(&self) -> usize {
+        self.0
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(_, ctr)| ctr.throttled())
+            .sum()
+    }
+}
+
+pub async fn start_packet_counter_print_loop(
+    this: SharedPacketCtr,
+    listener_ctrs: SharedListenerCtrs,
+) -> anyhow::Result<()> {
     let mut sto = io::stdout();
     while Arc::strong_count(&this) > 1 {
         let egress = this.egress.load(Ordering::SeqCst);
         let ingress = this.ingress.load(Ordering::SeqCst);
+        let ipv6 = this.ipv6.load(Ordering::SeqCst);
+        let throttled = listener_ctrs.throttled();
         sto.execute(cursor::MoveToColumn(0))?
             .execute(terminal::Clear(terminal::ClearType::CurrentLine))?;
 
-        print!("Egress Packets: {egress} Ingress Packets: {ingress}");
+        print!(
+            "Egress Packets: {egress} Ingress Packets: {ingress} (IPv6: {ipv6}) Throttled: {throttled}"
+        );
         sto.flush()?;
 
         sleep(Duration::from_millis(300)).await;
@@ -41,3 +109,134 @@ pub async fn start_packet_counter_print_loop(this: SharedPacketCtr) -> anyhow::R
 
     Ok(())
 }
+
+/// Renders the counters in the prometheus text format
+fn render_metrics(
+    packet_counter: &PacketCtr,
+    listener_ctrs: &SharedListenerCtrs,
+    source_metrics: Option<&dyn SourceMetrics>,
+) -> String {
+    let mut out = String::new();
+    _ = writeln!(out, "# TYPE shredcaster_packets_total counter");
+    for (direction, ctr) in [
+        ("ingress", &packet_counter.ingress),
+        ("egress", &packet_counter.egress),
+    ] {
+        _ = writeln!(
+            out,
+            "shredcaster_packets_total{{direction=\"{direction}\"}} {}",
+            ctr.load(Ordering::Relaxed)
+        );
+    }
+    _ = writeln!(
+        out,
+        "# HELP shredcaster_ipv6_packets_total packets captured over IPv6, in either direction"
+    );
+    _ = writeln!(out, "# TYPE shredcaster_ipv6_packets_total counter");
+    _ = writeln!(
+        out,
+        "shredcaster_ipv6_packets_total {}",
+        packet_counter.ipv6.load(Ordering::Relaxed)
+    );
+
+    let listeners = listener_ctrs.0.read().unwrap();
+    let fields: [(&str, &str, ListenerCtrField); 9] = [
+        (
+            "forwarded_packets",
+            "packets forwarded to the listener",
+            |ctr| &ctr.forwarded_packets,
+        ),
+        (
+            "forwarded_bytes",
+            "bytes forwarded to the listener",
+            |ctr| &ctr.forwarded_bytes,
+        ),
+        (
+            "throttled_packets",
+            "packets dropped by the listener rate limit",
+            |ctr| &ctr.throttled_packets,
+        ),
+        (
+            "global_throttled_packets",
+            "packets dropped by the global egress budget",
+            |ctr| &ctr.global_throttled_packets,
+        ),
+        (
+            "lagged_packets",
+            "packets dropped because the listener fell behind",
+            |ctr| &ctr.lagged_packets,
+        ),
+        (
+            "suspensions",
+            "times the listener was suspended as unreachable",
+            |ctr| &ctr.suspensions,
+        ),
+        ("nacks", "retransmission requests received", |ctr| {
+            &ctr.nacks
+        }),
+        (
+            "nacked_packets",
+            "packets the listener asked for again",
+            |ctr| &ctr.nacked_packets,
+        ),
+        (
+            "resent_datagrams",
+            "datagrams sent again on request",
+            |ctr| &ctr.resent_datagrams,
+        ),
+    ];
+    for (name, help, field) in fields {
+        _ = writeln!(out, "# HELP shredcaster_listener_{name}_total {help}");
+        _ = writeln!(out, "# TYPE shredcaster_listener_{name}_total counter");
+        for (addr, ctr) in listeners.iter() {
+            _ = writeln!(
+                out,
+                "shredcaster_listener_{name}_total{{listener=\"{addr}\"}} {}",
+                field(ctr).load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    _ = writeln!(
+        out,
+        "# HELP shredcaster_listener_suspended whether the listener is suspended as unreachable"
+    );
+    _ = writeln!(out, "# TYPE shredcaster_listener_suspended gauge");
+    for (addr, ctr) in listeners.iter() {
+        _ = writeln!(
+            out,
+            "shredcaster_listener_suspended{{listener=\"{addr}\"}} {}",
+            ctr.suspended.load(Ordering::Relaxed) as u8
+        );
+    }
+
+    if let Some(source_metrics) = source_metrics {
+        source_metrics.render(&mut out);
+    }
+    out
+}
+
+/// Serves the packet, per listener and source counters over HTTP at `addr`
+pub async fn serve_metrics(
+    addr: SocketAddr,
+    packet_counter: SharedPacketCtr,
+    listener_ctrs: SharedListenerCtrs,
+    source_metrics: Option<Arc<dyn SourceMetrics>>,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    println!("serving metrics on http://{addr}/metrics");
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let body = render_metrics(&packet_counter, &listener_ctrs, source_metrics.as_deref());
+        tokio::spawn(async move {
+            // the request itself is irrelevant, every path serves the metrics
+            let mut buf = [0; 1024];
+            _ = stream.read(&mut buf).await;
+            let res = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            _ = stream.write_all(res.as_bytes()).await;
+        });
+    }
+}
@@ -0,0 +1,33 @@
+use std::net::IpAddr;
+
+use agave_xdp::netlink::MacAddress;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct MulticastOptions {
+    /// TTL, or hop limit for IPv6, of packets sent to a multicast group,
+    /// which are then always sent through a kernel socket
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub multicast_ttl: Option<u32>,
+    /// Interface packets to a multicast group leave through,
+    /// instead of the one the routing table resolves it to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub multicast_iface: Option<String>,
+}
+
+/// The Ethernet address frames to the IP multicast group `group` are sent to
+pub fn multicast_mac(group: IpAddr) -> MacAddress {
+    match group {
+        // 01:00:5e followed by the low 23 bits of the group
+        IpAddr::V4(group) => {
+            let [_, b, c, d] = group.octets();
+            MacAddress([0x01, 0x00, 0x5e, b & 0x7f, c, d])
+        }
+        // 33:33 followed by the low 32 bits of the group
+        IpAddr::V6(group) => {
+            let [.., a, b, c, d] = group.octets();
+            MacAddress([0x33, 0x33, a, b, c, d])
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex, RwLock, atomic::Ordering},
+    time::{Duration, Instant},
+};
+
+use shredcaster_proto::{
+    nack::Nack,
+    secure::{Key, Opener},
+};
+use tokio::net::UdpSocket;
+
+use crate::{
+    metrics::ListenerCtr,
+    rate_limit::{self, Priority, RateLimiter},
+};
+
+/// Caps the memory of listeners with long windows
+const MAX_BUFFERED: usize = 1 << 16;
+/// How often a single datagram is sent again
+const MAX_RESENDS: u8 = 3;
+/// Bytes resent for a single NACK, which bounds what a spoofed
+/// unsealed NACK can make shredcaster send
+const MAX_RESEND_BYTES_PER_NACK: usize = 64 * 1024;
+
+struct Sent {
+    seq: u64,
+    at: Instant,
+    data: Arc<[u8]>,
+    resends: u8,
+}
+
+/// Recently sent datagrams of a listener, kept for retransmission
+pub struct Retransmits {
+    window: Duration,
+    sent: Mutex<VecDeque<Sent>>,
+    /// Authenticates the NACKs of listeners with a `psk`
+    opener: Option<Mutex<Opener>>,
+    /// Resends take from the listener's and the global budget like parity does
+    limiter: Option<Arc<Mutex<RateLimiter>>>,
+    global_limiter: Option<Arc<Mutex<RateLimiter>>>,
+    ctr: Arc<ListenerCtr>,
+}
+
+impl Retransmits {
+    pub fn new(
+        window: Duration,
+        psk: Option<&Key>,
+        limiter: Option<Arc<Mutex<RateLimiter>>>,
+        global_limiter: Option<Arc<Mutex<RateLimiter>>>,
+        ctr: Arc<ListenerCtr>,
+    ) -> Self {
+        Self {
+            window,
+            sent: Mutex::new(VecDeque::new()),
+            opener: psk.map(|psk| Mutex::new(Opener::new(psk))),
+            limiter,
+            global_limiter,
+            ctr,
+        }
+    }
+
+    /// Keeps `data`, the datagram that carried packet `seq`, until the window passes
+    pub fn record(&self, seq: u64, data: Arc<[u8]>, now: Instant) {
+        let mut sent = self.sent.lock().unwrap();
+        while sent.front().is_some_and(|sent| now - sent.at > self.window)
+            || sent.len() >= MAX_BUFFERED
+        {
+            sent.pop_front();
+        }
+        sent.push_back(Sent {
+            seq,
+            at: now,
+            data,
+            resends: 0,
+        });
+    }
+
+    /// Checks and decodes a NACK received from the listener
+    fn nack(&self, buf: &mut [u8]) -> Option<Nack> {
+        let buf = match &self.opener {
+            Some(opener) => opener.lock().unwrap().open(buf).ok()?,
+            None => buf,
+        };
+        Nack::decode(buf).ok()
+    }
+
+    /// The datagrams still held for `nack` that the budgets allow,
+    /// each sent at most once per request
+    fn resend(&self, nack: &Nack, now: Instant) -> Vec<Arc<[u8]>> {
+        let mut sent = self.sent.lock().unwrap();
+        let mut datagrams: Vec<Arc<[u8]>> = Vec::new();
+        let mut bytes: usize = 0;
+        let mut requested: usize = 0;
+        for range in nack.ranges.iter() {
+            let len = range.end().saturating_sub(*range.start()).saturating_add(1);
+            requested = requested.saturating_add(len as usize);
+            let start = sent.partition_point(|sent| sent.seq < *range.start());
+            for sent in sent.range_mut(start..) {
+                if sent.seq > *range.end() || bytes >= MAX_RESEND_BYTES_PER_NACK {
+                    break;
+                }
+                if now - sent.at > self.window || sent.resends >= MAX_RESENDS {
+                    continue;
+                }
+                // bundles carry several sequence numbers but are sent once
+                if datagrams
+                    .last()
+                    .is_some_and(|last| Arc::ptr_eq(last, &sent.data))
+                    || bytes + sent.data.len() > MAX_RESEND_BYTES_PER_NACK
+                {
+                    continue;
+                }
+                if rate_limit::acquire(
+                    self.limiter.as_deref(),
+                    self.global_limiter.as_deref(),
+                    &self.ctr,
+                    sent.data.len(),
+                    Priority::Low,
+                    now,
+                ) {
+                    sent.resends += 1;
+                    bytes += sent.data.len();
+                    datagrams.push(sent.data.clone());
+                }
+            }
+        }
+        self.ctr.nacks.fetch_add(1, Ordering::Relaxed);
+        self.ctr
+            .nacked_packets
+            .fetch_add(requested, Ordering::Relaxed);
+        self.ctr
+            .resent_datagrams
+            .fetch_add(datagrams.len(), Ordering::Relaxed);
+
+        datagrams
+    }
+}
+
+/// The retransmit buffers of the current listeners, kept up to date by the forwarder
+pub type SharedRetransmits = Arc<RwLock<HashMap<SocketAddr, Arc<Retransmits>>>>;
+
+/// Answers NACKs received on `addr` by resending the requested datagrams
+/// to the listener they came from
+pub async fn serve_nacks(addr: SocketAddr, retransmits: SharedRetransmits) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind(addr).await?;
+    println!("accepting NACKs on {addr}");
+    let mut buf = [0; 2048];
+    loop {
+        let (len, peer) = socket.recv_from(&mut buf).await?;
+        let peer = SocketAddr::new(peer.ip().to_canonical(), peer.port());
+        let Some(listener) = retransmits.read().unwrap().get(&peer).cloned() else {
+            continue;
+        };
+        let Some(nack) = listener.nack(&mut buf[..len]) else {
+            continue;
+        };
+        for datagram in listener.resend(&nack, Instant::now()) {
+            _ = socket.send_to(&datagram, peer).await;
+        }
+    }
+}
@@ -0,0 +1,359 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use shredcaster_proto::{
+    net::{checksum_add, checksum_finish, ipv6_mapped},
+    pcap::{
+        LINKTYPE_ETHERNET, PCAPNG_BYTE_ORDER_MAGIC, PCAPNG_ENHANCED_PACKET,
+        PCAPNG_INTERFACE_DESCRIPTION, PCAPNG_SECTION_HEADER, PcapReader,
+    },
+};
+
+use crate::{
+    CapturedPacket,
+    sink::{BatchWriter, ThreadedSink, delete_oldest},
+    source::{MAX_BATCH, PacketSource, shred_data, unix_now_ns},
+};
+
+/// Packets read from a pcap or pcapng file, e.g. one recorded with tcpdump,
+/// as fast as the sinks take them
+pub struct PcapSource {
+    reader: PcapReader,
+    tvu_ports: Vec<u16>,
+}
+
+impl PcapSource {
+    /// Reads the UDP datagrams to `tvu_ports`, or all of them if empty, from `path`
+    pub fn open(path: impl AsRef<Path>, tvu_ports: &[u16]) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let reader = PcapReader::open(path)?;
+        println!("started reading turbine from {}", path.display());
+        Ok(Self {
+            reader,
+            tvu_ports: tvu_ports.to_vec(),
+        })
+    }
+}
+
+impl PacketSource for PcapSource {
+    fn recv<'a>(
+        &'a mut self,
+        batch: &'a mut Vec<CapturedPacket>,
+    ) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            // reading never waits, give the other tasks a turn between batches
+            tokio::task::yield_now().await;
+            while batch.len() < MAX_BATCH {
+                let Some(datagram) = self.reader.next_datagram()? else {
+                    return Ok(false);
+                };
+                if !self.tvu_ports.is_empty() && !self.tvu_ports.contains(&datagram.dst.port()) {
+                    continue;
+                }
+                if let Some(data) = shred_data(datagram.payload) {
+                    batch.push(CapturedPacket {
+                        ts_ns: datagram.ts_ns,
+                        src: datagram.src,
+                        dst: Some(datagram.dst),
+                        is_egress: datagram.is_egress,
+                        origin: None,
+                        data,
+                    });
+                }
+            }
+            Ok(true)
+        })
+    }
+}
+
+const PCAPNG_OPT_END: u16 = 0;
+const PCAPNG_OPT_COMMENT: u16 = 1;
+const PCAPNG_SHB_USERAPPL: u16 = 4;
+const PCAPNG_IF_TSRESOL: u16 = 9;
+const PCAPNG_EPB_FLAGS: u16 = 2;
+const PCAPNG_EPB_INBOUND: u32 = 1;
+const PCAPNG_EPB_OUTBOUND: u32 = 2;
+
+/// Options of the `pcapng` sink
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PcapngSinkOptions {
+    /// Directory the capture files are written to
+    pub dir: PathBuf,
+    /// Files are named `<prefix>-<unix time in ns>.pcapng`
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// Starts a new file once the current one has this many megabytes
+    pub max_file_mb: Option<u64>,
+    /// Starts a new file once the current one is this many seconds old
+    pub rotate_secs: Option<u64>,
+    /// Deletes the oldest files with the prefix beyond this many
+    pub max_files: Option<usize>,
+}
+
+fn default_prefix() -> String {
+    "shredcaster".to_owned()
+}
+
+impl PcapngSinkOptions {
+    /// Starts the thread writing the files
+    pub fn spawn(self) -> anyhow::Result<ThreadedSink> {
+        std::fs::create_dir_all(&self.dir)?;
+        let mut writer = PcapngWriter {
+            options: self,
+            file: None,
+        };
+        // fail early on an unwritable directory
+        writer.rotate()?;
+        Ok(ThreadedSink::spawn("pcapng", writer))
+    }
+}
+
+/// The file being written
+struct PcapngFile {
+    writer: BufWriter<File>,
+    len: u64,
+    opened: Instant,
+}
+
+/// Writes the captured packets to rotating pcapng files, for Wireshark and tcpdump
+struct PcapngWriter {
+    options: PcapngSinkOptions,
+    file: Option<PcapngFile>,
+}
+
+impl BatchWriter for PcapngWriter {
+    fn write(&mut self, packets: &[CapturedPacket]) -> anyhow::Result<()> {
+        let mut block = Vec::new();
+        for packet in packets {
+            if self.file.as_ref().is_none_or(|file| self.is_full(file)) {
+                self.rotate()?;
+            }
+            let Some(file) = &mut self.file else {
+                unreachable!("rotating opens a file");
+            };
+            block.clear();
+            enhanced_packet_block(packet, &mut block);
+            file.writer.write_all(&block)?;
+            file.len += block.len() as u64;
+        }
+        if let Some(file) = &mut self.file {
+            file.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// When the current file is old enough to be closed, also on a quiet feed
+    fn deadline(&self) -> Option<Instant> {
+        let file = self.file.as_ref()?;
+        Some(file.opened + Duration::from_secs(self.options.rotate_secs?))
+    }
+
+    /// Closes the current file once it is due, the next packet opens another
+    fn tick(&mut self, now: Instant) -> anyhow::Result<()> {
+        if self.deadline().is_some_and(|deadline| deadline <= now)
+            && let Some(mut file) = self.file.take()
+        {
+            file.writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl PcapngWriter {
+    fn is_full(&self, file: &PcapngFile) -> bool {
+        self.options
+            .max_file_mb
+            .is_some_and(|max_mb| file.len >= max_mb << 20)
+            || self
+                .deadline()
+                .is_some_and(|deadline| deadline <= Instant::now())
+    }
+
+    /// Closes the current file, opens the next and deletes the oldest beyond `max_files`
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.writer.flush()?;
+        }
+        let path =
+            self.options
+                .dir
+                .join(format!("{}-{}.pcapng", self.options.prefix, unix_now_ns()));
+        let mut writer = BufWriter::new(File::create_new(&path)?);
+        let mut header = Vec::new();
+        section_header_block(&mut header);
+        interface_description_block(&mut header);
+        writer.write_all(&header)?;
+        self.file = Some(PcapngFile {
+            writer,
+            len: header.len() as u64,
+            opened: Instant::now(),
+        });
+        println!("started writing packets to {}", path.display());
+
+        match self.options.max_files {
+            Some(max_files) => delete_oldest(
+                &self.options.dir,
+                &self.options.prefix,
+                "pcapng",
+                max_files.max(1),
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Appends a block of `block_type` with `body`, padded to 32 bits, and `options`
+fn push_block(out: &mut Vec<u8>, block_type: u32, body: &[u8], options: &[(u16, &[u8])]) {
+    let start = out.len();
+    out.extend_from_slice(&block_type.to_le_bytes());
+    // the length is filled in once known
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(body);
+    out.resize(out.len().next_multiple_of(4), 0);
+    if !options.is_empty() {
+        for (code, value) in options {
+            out.extend_from_slice(&code.to_le_bytes());
+            out.extend_from_slice(&(value.len() as u16).to_le_bytes());
+            out.extend_from_slice(value);
+            out.resize(out.len().next_multiple_of(4), 0);
+        }
+        out.extend_from_slice(&PCAPNG_OPT_END.to_le_bytes());
+        out.extend_from_slice(&[0; 2]);
+    }
+    let len = (out.len() - start + 4) as u32;
+    out.extend_from_slice(&len.to_le_bytes());
+    out[start + 4..start + 8].copy_from_slice(&len.to_le_bytes());
+}
+
+fn section_header_block(out: &mut Vec<u8>) {
+    let mut body = Vec::with_capacity(16);
+    body.extend_from_slice(&PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes());
+    // version 1.0
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // the section length is unknown
+    body.extend_from_slice(&(-1i64).to_le_bytes());
+    push_block(
+        out,
+        PCAPNG_SECTION_HEADER,
+        &body,
+        &[(PCAPNG_SHB_USERAPPL, b"shredcaster")],
+    );
+}
+
+fn interface_description_block(out: &mut Vec<u8>) {
+    let mut body = Vec::with_capacity(8);
+    body.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+    body.extend_from_slice(&[0; 2]);
+    // no snapshot length limit
+    body.extend_from_slice(&0u32.to_le_bytes());
+    // timestamps in nanoseconds
+    push_block(
+        out,
+        PCAPNG_INTERFACE_DESCRIPTION,
+        &body,
+        &[(PCAPNG_IF_TSRESOL, &[9])],
+    );
+}
+
+fn enhanced_packet_block(packet: &CapturedPacket, out: &mut Vec<u8>) {
+    let mut frame = Vec::with_capacity(62 + packet.data.as_ref().len());
+    ethernet_frame(packet, &mut frame);
+    let mut body = Vec::with_capacity(20 + frame.len());
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&((packet.ts_ns >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.ts_ns as u32).to_le_bytes());
+    body.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    body.extend_from_slice(&frame);
+
+    let (flags, direction) = if packet.is_egress {
+        (PCAPNG_EPB_OUTBOUND, "egress")
+    } else {
+        (PCAPNG_EPB_INBOUND, "ingress")
+    };
+    let comment = match &packet.origin {
+        Some(origin) => format!(
+            "{direction} on {} of host {:016x}",
+            origin.iface, origin.host_id
+        ),
+        None => direction.to_owned(),
+    };
+    push_block(
+        out,
+        PCAPNG_ENHANCED_PACKET,
+        &body,
+        &[
+            (PCAPNG_OPT_COMMENT, comment.as_bytes()),
+            (PCAPNG_EPB_FLAGS, &flags.to_le_bytes()),
+        ],
+    );
+}
+
+/// Synthesizes the Ethernet, IP and UDP headers a packet was received with,
+/// unknown destinations are left unspecified
+fn ethernet_frame(packet: &CapturedPacket, out: &mut Vec<u8>) {
+    let payload = packet.data.as_ref();
+    let src = packet.src;
+    let dst = packet.dst.unwrap_or_else(|| match src {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    });
+    let udp_len = (8 + payload.len()) as u16;
+    let mut udp = Vec::with_capacity(usize::from(udp_len));
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&udp_len.to_be_bytes());
+    udp.extend_from_slice(&[0; 2]);
+    udp.extend_from_slice(payload);
+
+    // the locally administered MACs carry no information
+    out.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x02]);
+    out.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x01]);
+    let pseudo_header = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            out.extend_from_slice(&0x0800u16.to_be_bytes());
+            let mut ip = [0; 20];
+            ip[0] = 0x45;
+            ip[2..4].copy_from_slice(&(20 + udp_len).to_be_bytes());
+            // don't fragment
+            ip[6] = 0x40;
+            ip[8] = 64;
+            ip[9] = libc::IPPROTO_UDP as u8;
+            ip[12..16].copy_from_slice(&src_ip.octets());
+            ip[16..20].copy_from_slice(&dst_ip.octets());
+            let ip_checksum = checksum_finish(checksum_add(0, &ip));
+            ip[10..12].copy_from_slice(&ip_checksum.to_be_bytes());
+            out.extend_from_slice(&ip);
+            checksum_add(0, &ip[12..20])
+        }
+        (src_ip, dst_ip) => {
+            let (src_ip, dst_ip) = (ipv6_mapped(src_ip), ipv6_mapped(dst_ip));
+            out.extend_from_slice(&0x86ddu16.to_be_bytes());
+            let mut ip = [0; 40];
+            ip[0] = 0x60;
+            ip[4..6].copy_from_slice(&udp_len.to_be_bytes());
+            ip[6] = libc::IPPROTO_UDP as u8;
+            ip[7] = 64;
+            ip[8..24].copy_from_slice(&src_ip.octets());
+            ip[24..40].copy_from_slice(&dst_ip.octets());
+            out.extend_from_slice(&ip);
+            checksum_add(0, &ip[8..40])
+        }
+    };
+    let sum = pseudo_header + u32::from(libc::IPPROTO_UDP as u8) + u32::from(udp_len);
+    // a zero checksum means none was computed
+    let udp_checksum = match checksum_finish(checksum_add(sum, &udp)) {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    udp[6..8].copy_from_slice(&udp_checksum.to_be_bytes());
+    out.extend_from_slice(&udp);
+}
@@ -0,0 +1,316 @@
+use std::{
+    sync::{Mutex, atomic::Ordering},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    filter::{PacketInfo, ShredKind},
+    metrics::ListenerCtr,
+};
+
+/// How much traffic a bucket absorbs above its rate,
+/// turbine delivers shreds in bursts so this is kept generous
+const BURST: Duration = Duration::from_secs(1);
+/// Coding shreds are dropped first, they are only admitted while
+/// the bucket holds more than this fraction of its burst
+const CODE_SHRED_RESERVE: f64 = 0.5;
+/// Below this fraction of its burst, a bucket admits a sample of the data
+/// shreds that shrinks as it empties, instead of cutting them off at once
+const DATA_SHRED_SAMPLE_RESERVE: f64 = 0.25;
+
+/// Which packets are dropped first when over budget
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    /// Coding shreds, FEC parity and retransmissions
+    Low,
+    /// Data shreds, sampled by this hash once the budget runs low
+    Sampled(u64),
+    /// Packets that aren't shreds, dropped only once the budget is exhausted
+    Normal,
+}
+
+impl Priority {
+    pub fn of(packet: &PacketInfo) -> Self {
+        match packet.kind {
+            Some(ShredKind::Code) => Priority::Low,
+            Some(ShredKind::Data) => Priority::Sampled(packet.sample_hash()),
+            None => Priority::Normal,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct RateLimit {
+    /// Maximum packets per second
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_pps: Option<f64>,
+    /// Maximum megabits per second of UDP payload, envelopes, sealing and FEC parity included
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_mbps: Option<f64>,
+}
+
+impl RateLimit {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_pps.is_none() && self.max_mbps.is_none()
+    }
+}
+
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        let burst = rate * BURST.as_secs_f64();
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+        }
+    }
+
+    fn refill(&mut self, elapsed: Duration) {
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+    }
+
+    fn allows(&self, n: f64, priority: Priority) -> bool {
+        let left = self.tokens - n;
+        match priority {
+            Priority::Low => left >= CODE_SHRED_RESERVE * self.burst,
+            Priority::Sampled(hash) => {
+                let reserve = DATA_SHRED_SAMPLE_RESERVE * self.burst;
+                left >= reserve || left >= 0.0 && (hash as f64 / u64::MAX as f64) < left / reserve
+            }
+            Priority::Normal => left >= 0.0,
+        }
+    }
+}
+
+/// Token buckets limiting packets and bytes per second
+pub struct RateLimiter {
+    packets: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Option<Self> {
+        if limit.is_unlimited() {
+            return None;
+        }
+        Some(Self {
+            packets: limit.max_pps.map(TokenBucket::new),
+            bytes: limit
+                .max_mbps
+                .map(|mbps| TokenBucket::new(mbps * 1_000_000.0 / 8.0)),
+            last: Instant::now(),
+        })
+    }
+
+    /// Refills the buckets and tells whether a packet of `len` bytes fits the budget
+    pub fn allows(&mut self, now: Instant, len: usize, priority: Priority) -> bool {
+        let elapsed = now.saturating_duration_since(self.last);
+        self.last = now;
+        let mut allows = true;
+        for (bucket, n) in [(&mut self.packets, 1.0), (&mut self.bytes, len as f64)] {
+            if let Some(bucket) = bucket {
+                bucket.refill(elapsed);
+                allows &= bucket.allows(n, priority);
+            }
+        }
+        allows
+    }
+
+    /// Takes the tokens of a packet of `len` bytes
+    pub fn take(&mut self, len: usize) {
+        for (bucket, n) in [(&mut self.packets, 1.0), (&mut self.bytes, len as f64)] {
+            if let Some(bucket) = bucket {
+                bucket.tokens -= n;
+            }
+        }
+    }
+}
+
+/// Takes a packet of `len` bytes sent to a listener from its own limiter and the
+/// global one if both allow it, counting it as throttled by the first that doesn't
+pub fn acquire(
+    limiter: Option<&Mutex<RateLimiter>>,
+    global_limiter: Option<&Mutex<RateLimiter>>,
+    ctr: &ListenerCtr,
+    len: usize,
+    priority: Priority,
+    now: Instant,
+) -> bool {
+    let mut limiter = limiter.map(|l| l.lock().unwrap());
+    if let Some(limiter) = &mut limiter
+        && !limiter.allows(now, len, priority)
+    {
+        ctr.throttled_packets.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+    let mut global = global_limiter.map(|l| l.lock().unwrap());
+    if let Some(global) = &mut global
+        && !global.allows(now, len, priority)
+    {
+        ctr.global_throttled_packets.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+    for limiter in limiter.iter_mut().chain(global.iter_mut()) {
+        limiter.take(len);
+    }
+    ctr.forwarded_bytes.fetch_add(len, Ordering::Relaxed);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pps(max_pps: f64) -> RateLimiter {
+        RateLimiter::new(RateLimit {
+            max_pps: Some(max_pps),
+            max_mbps: None,
+        })
+        .unwrap()
+    }
+
+    /// Packets of `priority` taken from a full bucket of 100 before one is refused
+    fn admitted(priority: Priority) -> usize {
+        let mut limiter = pps(100.0);
+        let now = limiter.last;
+        let mut n = 0;
+        while limiter.allows(now, 0, Priority::Normal) {
+            if !limiter.allows(now, 0, priority) {
+                return n;
+            }
+            limiter.take(0);
+            n += 1;
+        }
+        n
+    }
+
+    #[test]
+    fn coding_shreds_are_refused_before_data_shreds() {
+        assert_eq!(admitted(Priority::Low), 50);
+        // the data shred least likely to be sampled goes at the sample reserve,
+        // the most likely one when the bucket is empty
+        assert_eq!(admitted(Priority::Sampled(u64::MAX)), 75);
+        assert_eq!(admitted(Priority::Sampled(0)), 99);
+        assert_eq!(admitted(Priority::Normal), 100);
+    }
+
+    #[test]
+    fn data_shreds_are_sampled_below_the_reserve() {
+        let mut limiter = pps(1000.0);
+        let now = limiter.last;
+        // leaves half of the sample reserve
+        for _ in 0..875 {
+            limiter.take(0);
+        }
+        let sampled = (0..1000u64)
+            .map(|i| i * (u64::MAX / 1000))
+            .filter(|&hash| limiter.allows(now, 0, Priority::Sampled(hash)))
+            .count();
+        assert!((450..550).contains(&sampled), "{sampled}");
+    }
+
+    #[test]
+    fn empty_buckets_refill_over_time() {
+        let mut limiter = pps(10.0);
+        let start = limiter.last;
+        for _ in 0..10 {
+            limiter.take(0);
+        }
+        assert!(!limiter.allows(start, 0, Priority::Normal));
+        assert!(limiter.allows(start + Duration::from_millis(100), 0, Priority::Normal));
+        assert!(!limiter.allows(start + Duration::from_millis(100), 0, Priority::Low));
+    }
+
+    #[test]
+    fn acquire_charges_the_listener_and_the_global_budget() {
+        let limiter = Mutex::new(pps(100.0));
+        let global = Mutex::new(pps(10.0));
+        let now = limiter
+            .lock()
+            .unwrap()
+            .last
+            .max(global.lock().unwrap().last);
+        let ctr = ListenerCtr::default();
+
+        let sent = (0..20)
+            .filter(|_| {
+                acquire(
+                    Some(&limiter),
+                    Some(&global),
+                    &ctr,
+                    100,
+                    Priority::Normal,
+                    now,
+                )
+            })
+            .count();
+        assert_eq!(sent, 10);
+        assert_eq!(ctr.forwarded_bytes.load(Ordering::Relaxed), 1000);
+        assert_eq!(ctr.global_throttled_packets.load(Ordering::Relaxed), 10);
+        assert_eq!(ctr.throttled_packets.load(Ordering::Relaxed), 0);
+        let tokens = |l: &Mutex<RateLimiter>| l.lock().unwrap().packets.as_ref().unwrap().tokens;
+        assert_eq!(tokens(&limiter), 90.0);
+        assert_eq!(tokens(&global), 0.0);
+    }
+
+    #[test]
+    fn acquire_leaves_the_global_budget_alone_when_the_listener_refuses() {
+        let limiter = Mutex::new(
+            RateLimiter::new(RateLimit {
+                max_pps: None,
+                max_mbps: Some(0.008),
+            })
+            .unwrap(),
+        );
+        let global = Mutex::new(pps(100.0));
+        let now = limiter
+            .lock()
+            .unwrap()
+            .last
+            .max(global.lock().unwrap().last);
+        let ctr = ListenerCtr::default();
+
+        // 1000 bytes per second, half of it kept for data shreds
+        assert!(acquire(
+            Some(&limiter),
+            Some(&global),
+            &ctr,
+            400,
+            Priority::Low,
+            now
+        ));
+        assert!(!acquire(
+            Some(&limiter),
+            Some(&global),
+            &ctr,
+            400,
+            Priority::Low,
+            now
+        ));
+        assert!(acquire(
+            Some(&limiter),
+            Some(&global),
+            &ctr,
+            400,
+            Priority::Normal,
+            now
+        ));
+        assert_eq!(ctr.throttled_packets.load(Ordering::Relaxed), 1);
+        assert_eq!(ctr.forwarded_bytes.load(Ordering::Relaxed), 800);
+        let global = global.lock().unwrap();
+        assert_eq!(global.packets.as_ref().unwrap().tokens, 98.0);
+
+        // without limiters everything goes through
+        assert!(acquire(None, None, &ctr, 400, Priority::Low, now));
+    }
+}
@@ -0,0 +1,667 @@
+use std::{
+    collections::{HashMap, VecDeque, hash_map::Entry},
+    error::Error,
+    fmt::{self, Write as _},
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use futures::future::BoxFuture;
+use quinn::rustls::{
+    self,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use shredcaster_proto::{
+    bundle,
+    envelope::{Direction, Envelope},
+    fec::{self, FecDecoder},
+    secure::{Key, Opener},
+};
+use solana_ledger::shred::layout;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::{TcpListener, UdpSocket},
+    sync::mpsc,
+    task::JoinSet,
+};
+
+use crate::{
+    CapturedPacket,
+    capture::{DeliveryMode, Origin},
+    config::{deserialize_psk, serialize_psk},
+    filter::ShredKind,
+    health::HEARTBEAT_MAGIC,
+    source::{MAX_BATCH, PacketSource, SourceMetrics, shred_data, unix_now_ns},
+};
+
+/// Packets received from the feeds but not yet taken by the watcher
+const QUEUE_LEN: usize = 8192;
+/// How long a merged shred is remembered, later copies are relayed again
+const MERGE_WINDOW: Duration = Duration::from_secs(5);
+/// Upper bounds of the lag histogram buckets, in microseconds
+const LAG_BUCKETS_US: [u64; 10] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000,
+];
+const ALPN: &[u8] = b"shredcaster";
+
+/// Where a relay receives an upstream shredcaster's output
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FeedAddr {
+    /// Datagrams, as sent to UDP listeners, the default
+    Udp(SocketAddr),
+    /// Framed shreds on QUIC streams, as sent to QUIC listeners
+    Quic(SocketAddr),
+    /// Framed shreds on TCP connections, as sent to TCP listeners
+    Tcp(SocketAddr),
+}
+
+impl FromStr for FeedAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, addr) = s.split_once("://").unwrap_or(("udp", s));
+        let addr = addr
+            .parse()
+            .map_err(|_| anyhow::anyhow!("invalid feed address {s}"))?;
+        match scheme {
+            "udp" => Ok(FeedAddr::Udp(addr)),
+            "quic" => Ok(FeedAddr::Quic(addr)),
+            "tcp" => Ok(FeedAddr::Tcp(addr)),
+            _ => anyhow::bail!("unsupported feed scheme {scheme}://"),
+        }
+    }
+}
+
+impl fmt::Display for FeedAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FeedAddr::Udp(addr) => write!(f, "{addr}"),
+            FeedAddr::Quic(addr) => write!(f, "quic://{addr}"),
+            FeedAddr::Tcp(addr) => write!(f, "tcp://{addr}"),
+        }
+    }
+}
+
+impl Serialize for FeedAddr {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for FeedAddr {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        String::deserialize(d)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// How an upstream shredcaster sends to this feed, matching the options
+/// of the listener it has configured for it
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct FeedOptions {
+    /// Envelopes carry the capture metadata, which is kept for the listeners
+    /// of the relay, raw shreds are taken as captured from the sending peer
+    pub mode: DeliveryMode,
+    /// Key of an upstream listener with a `psk`
+    #[serde(
+        deserialize_with = "deserialize_psk",
+        serialize_with = "serialize_psk",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub psk: Option<Key>,
+    /// PEM file of the certificate chain a QUIC feed is served with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cert: Option<PathBuf>,
+    /// PEM file of the private key of `cert`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub private_key: Option<PathBuf>,
+}
+
+/// A feed is either a bare address, or a table with an `addr` and options
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FeedEntry {
+    Addr(FeedAddr),
+    Table {
+        addr: FeedAddr,
+        #[serde(flatten)]
+        options: FeedOptions,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(from = "FeedEntry")]
+pub struct FeedConfig {
+    pub addr: FeedAddr,
+    #[serde(flatten)]
+    pub options: FeedOptions,
+}
+
+impl From<FeedEntry> for FeedConfig {
+    fn from(entry: FeedEntry) -> Self {
+        match entry {
+            FeedEntry::Addr(addr) => Self {
+                addr,
+                options: FeedOptions::default(),
+            },
+            FeedEntry::Table { addr, options } => Self { addr, options },
+        }
+    }
+}
+
+impl FromStr for FeedConfig {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(FeedEntry::Addr(s.parse()?).into())
+    }
+}
+
+/// Turns what an upstream shredcaster sent into captured packets
+struct FeedDecoder {
+    addr: FeedAddr,
+    mode: DeliveryMode,
+    opener: Option<Opener>,
+    fec: FecDecoder,
+    /// Set while packets are rejected, so a misconfigured feed is reported once
+    rejecting: bool,
+}
+
+impl FeedDecoder {
+    fn new(addr: FeedAddr, options: &FeedOptions) -> Self {
+        Self {
+            addr,
+            mode: options.mode,
+            opener: options.psk.as_ref().map(Opener::new),
+            fec: FecDecoder::default(),
+            rejecting: false,
+        }
+    }
+
+    /// Recovers lost datagrams with FEC, opens them and splits bundles,
+    /// like a listener of the upstream shredcaster would
+    fn datagram(&mut self, datagram: &[u8], peer: SocketAddr, out: &mut Vec<CapturedPacket>) {
+        let datagrams = if fec::is_fec(datagram) {
+            match self.fec.decode(datagram) {
+                Ok(datagrams) => datagrams,
+                Err(e) => return self.reject(peer, &e),
+            }
+        } else {
+            vec![datagram.to_vec()]
+        };
+        for mut datagram in datagrams {
+            let datagram = match &mut self.opener {
+                Some(opener) => match opener.open(&mut datagram) {
+                    Ok(datagram) => datagram,
+                    Err(e) => {
+                        self.reject(peer, &e);
+                        continue;
+                    }
+                },
+                None => &datagram,
+            };
+            if !bundle::is_bundle(datagram) {
+                self.packet(datagram, peer, out);
+                continue;
+            }
+            match bundle::decode(datagram) {
+                Ok(frames) => {
+                    for packet in frames {
+                        self.packet(packet, peer, out);
+                    }
+                }
+                Err(e) => self.reject(peer, &e),
+            }
+        }
+    }
+
+    /// Opens a frame of a QUIC or TCP feed, which upstream seals for
+    /// listeners with a `psk` like it does datagrams, and appends its shred to `out`
+    fn frame(&mut self, frame: &mut [u8], peer: SocketAddr, out: &mut Vec<CapturedPacket>) {
+        let frame = match &mut self.opener {
+            Some(opener) => match opener.open(frame) {
+                Ok(frame) => frame,
+                Err(e) => return self.reject(peer, &e),
+            },
+            None => frame,
+        };
+        self.packet(frame, peer, out);
+    }
+
+    /// Appends the shred `packet` carries to `out`
+    fn packet(&mut self, packet: &[u8], peer: SocketAddr, out: &mut Vec<CapturedPacket>) {
+        let captured = match self.mode {
+            DeliveryMode::Raw => shred_data(packet).map(|data| CapturedPacket {
+                ts_ns: unix_now_ns(),
+                src: SocketAddr::new(peer.ip().to_canonical(), peer.port()),
+                dst: None,
+                is_egress: false,
+                origin: None,
+                data,
+            }),
+            DeliveryMode::Envelope => {
+                let (envelope, shred) = match Envelope::decode(packet) {
+                    Ok(decoded) => decoded,
+                    Err(e) => return self.reject(peer, &e),
+                };
+                shred_data(shred).map(|data| CapturedPacket {
+                    ts_ns: envelope.capture_ts_ns,
+                    src: envelope.src,
+                    // envelopes don't carry it
+                    dst: None,
+                    is_egress: envelope.direction == Direction::Egress,
+                    origin: Some(Origin {
+                        host_id: envelope.host_id,
+                        iface: envelope.iface.into(),
+                    }),
+                    data,
+                })
+            }
+        };
+        if let Some(captured) = captured {
+            if self.rejecting {
+                println!("feed {} is accepting packets again", self.addr);
+                self.rejecting = false;
+            }
+            out.push(captured);
+        }
+    }
+
+    fn reject(&mut self, peer: SocketAddr, error: &dyn Error) {
+        if !self.rejecting {
+            eprintln!(
+                "feed {} is dropping packets, e.g. from {peer}: {error}",
+                self.addr
+            );
+            self.rejecting = true;
+        }
+    }
+}
+
+fn quic_server_config(options: &FeedOptions) -> anyhow::Result<quinn::ServerConfig> {
+    let (Some(cert), Some(private_key)) = (&options.cert, &options.private_key) else {
+        anyhow::bail!("QUIC feeds need a cert and a private_key");
+    };
+    let certs = CertificateDer::pem_file_iter(cert)
+        .with_context(|| format!("failed to read {}", cert.display()))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(private_key)
+        .with_context(|| format!("failed to read {}", private_key.display()))?;
+    let mut crypto = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()?
+    .with_no_client_auth()
+    .with_single_cert(certs, key)?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(
+        quinn::crypto::rustls::QuicServerConfig::try_from(crypto)?,
+    )))
+}
+
+/// A bound feed, ready to receive
+enum Feed {
+    Udp(UdpSocket),
+    Quic(quinn::Endpoint),
+    Tcp(TcpListener),
+}
+
+impl Feed {
+    async fn bind(config: &FeedConfig) -> anyhow::Result<Self> {
+        Ok(match config.addr {
+            FeedAddr::Udp(addr) => Feed::Udp(UdpSocket::bind(addr).await?),
+            FeedAddr::Quic(addr) => Feed::Quic(quinn::Endpoint::server(
+                quic_server_config(&config.options)?,
+                addr,
+            )?),
+            FeedAddr::Tcp(addr) => Feed::Tcp(TcpListener::bind(addr).await?),
+        })
+    }
+
+    /// Receives until the relay is dropped, every connection of a stream feed on its own task
+    async fn run(self, addr: FeedAddr, options: FeedOptions, tx: FeedTx) -> anyhow::Result<()> {
+        let mut connections = JoinSet::new();
+        match self {
+            Feed::Udp(socket) => {
+                let mut decoder = FeedDecoder::new(addr, &options);
+                // large enough for jumbo bundles
+                let mut buf = vec![0; 65536];
+                let mut packets = Vec::new();
+                loop {
+                    let (len, peer) = socket.recv_from(&mut buf).await?;
+                    // keeps the relay enabled at upstreams checking for heartbeats
+                    if buf[..len].starts_with(HEARTBEAT_MAGIC) {
+                        socket.send_to(&buf[..len], peer).await?;
+                        continue;
+                    }
+                    decoder.datagram(&buf[..len], peer, &mut packets);
+                    if !tx.forward(&mut packets).await {
+                        return Ok(());
+                    }
+                }
+            }
+            Feed::Quic(endpoint) => {
+                while let Some(incoming) = endpoint.accept().await {
+                    let decoder = FeedDecoder::new(addr, &options);
+                    let tx = tx.clone();
+                    connections.spawn(async move {
+                        let connection = incoming.await?;
+                        let peer = connection.remote_address();
+                        println!("feed {addr} connected from {peer}");
+                        // upstreams send everything on a single stream
+                        let stream = connection.accept_uni().await?;
+                        read_frames(stream, peer, decoder, tx).await
+                    });
+                    reap(addr, &mut connections);
+                }
+                Ok(())
+            }
+            Feed::Tcp(listener) => loop {
+                let (stream, peer) = listener.accept().await?;
+                println!("feed {addr} connected from {peer}");
+                let decoder = FeedDecoder::new(addr, &options);
+                connections.spawn(read_frames(stream, peer, decoder, tx.clone()));
+                reap(addr, &mut connections);
+            },
+        }
+    }
+}
+
+/// Reports why the connections of a stream feed that ended did
+fn reap(addr: FeedAddr, connections: &mut JoinSet<anyhow::Result<()>>) {
+    while let Some(ended) = connections.try_join_next() {
+        if let Ok(Err(e)) = ended {
+            eprintln!("feed {addr} connection closed: {e}");
+        }
+    }
+}
+
+/// A packet, the index of the feed it came from and when it arrived
+type Received = (usize, Instant, CapturedPacket);
+
+/// Hands the packets of a feed to the relay
+#[derive(Clone)]
+struct FeedTx {
+    feed: usize,
+    tx: mpsc::Sender<Received>,
+}
+
+impl FeedTx {
+    /// Hands `packets` over as arrived now, returning false once the relay was dropped
+    async fn forward(&self, packets: &mut Vec<CapturedPacket>) -> bool {
+        let arrived = Instant::now();
+        for packet in packets.drain(..) {
+            if self.tx.send((self.feed, arrived, packet)).await.is_err() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Reads frames of `seq: u64 LE | len: u16 LE | payload` until the stream ends
+async fn read_frames(
+    mut stream: impl AsyncRead + Unpin,
+    peer: SocketAddr,
+    mut decoder: FeedDecoder,
+    tx: FeedTx,
+) -> anyhow::Result<()> {
+    let mut header = [0; 10];
+    let mut buf = Vec::new();
+    let mut packets = Vec::new();
+    loop {
+        match stream.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        let len = u16::from_le_bytes([header[8], header[9]]);
+        buf.resize(usize::from(len), 0);
+        stream.read_exact(&mut buf).await?;
+        decoder.frame(&mut buf, peer, &mut packets);
+        if !tx.forward(&mut packets).await {
+            return Ok(());
+        }
+    }
+}
+
+/// Counters of a single feed
+#[derive(Default)]
+struct FeedCtr {
+    packets: AtomicUsize,
+    /// Shreds the feed delivered before any other
+    wins: AtomicUsize,
+    /// Copies of shreds another feed delivered first, by how long after, see `LAG_BUCKETS_US`
+    lagged: [AtomicUsize; LAG_BUCKETS_US.len() + 1],
+    lag_us: AtomicU64,
+}
+
+impl FeedCtr {
+    fn record_lag(&self, lag: Duration) {
+        let lag_us = lag.as_micros() as u64;
+        let bucket = LAG_BUCKETS_US.partition_point(|bound| *bound < lag_us);
+        self.lagged[bucket].fetch_add(1, Ordering::Relaxed);
+        self.lag_us.fetch_add(lag_us, Ordering::Relaxed);
+    }
+}
+
+type FeedCtrField = fn(&FeedCtr) -> &AtomicUsize;
+
+/// Counters of every feed of a relay
+struct FeedStats {
+    merging: bool,
+    feeds: Vec<(FeedAddr, FeedCtr)>,
+}
+
+impl SourceMetrics for FeedStats {
+    fn render(&self, out: &mut String) {
+        let mut fields: Vec<(&str, &str, FeedCtrField)> =
+            vec![("packets", "shreds received from the feed", |ctr| {
+                &ctr.packets
+            })];
+        if self.merging {
+            fields.push((
+                "wins",
+                "shreds the feed delivered before any other",
+                |ctr| &ctr.wins,
+            ));
+        }
+        for (name, help, field) in fields {
+            _ = writeln!(out, "# HELP shredcaster_feed_{name}_total {help}");
+            _ = writeln!(out, "# TYPE shredcaster_feed_{name}_total counter");
+            for (addr, ctr) in self.feeds.iter() {
+                _ = writeln!(
+                    out,
+                    "shredcaster_feed_{name}_total{{feed=\"{addr}\"}} {}",
+                    field(ctr).load(Ordering::Relaxed)
+                );
+            }
+        }
+        if !self.merging {
+            return;
+        }
+
+        _ = writeln!(
+            out,
+            "# HELP shredcaster_feed_lag_seconds how long after the first copy the feed delivered a shred"
+        );
+        _ = writeln!(out, "# TYPE shredcaster_feed_lag_seconds histogram");
+        for (addr, ctr) in self.feeds.iter() {
+            let mut count = 0;
+            for (bucket, lagged) in ctr.lagged.iter().enumerate() {
+                count += lagged.load(Ordering::Relaxed);
+                let le = match LAG_BUCKETS_US.get(bucket) {
+                    Some(bound_us) => (*bound_us as f64 / 1e6).to_string(),
+                    None => "+Inf".to_owned(),
+                };
+                _ = writeln!(
+                    out,
+                    "shredcaster_feed_lag_seconds_bucket{{feed=\"{addr}\",le=\"{le}\"}} {count}"
+                );
+            }
+            _ = writeln!(
+                out,
+                "shredcaster_feed_lag_seconds_sum{{feed=\"{addr}\"}} {}",
+                ctr.lag_us.load(Ordering::Relaxed) as f64 / 1e6
+            );
+            _ = writeln!(
+                out,
+                "shredcaster_feed_lag_seconds_count{{feed=\"{addr}\"}} {count}"
+            );
+        }
+    }
+}
+
+/// Identifies a shred across feeds
+type ShredId = (u64, u32, ShredKind);
+
+fn shred_id(shred: &[u8]) -> Option<ShredId> {
+    Some((
+        layout::get_slot(shred)?,
+        layout::get_index(shred)?,
+        ShredKind::from_shred(shred)?,
+    ))
+}
+
+/// When the shreds relayed in the last `MERGE_WINDOW` first arrived
+#[derive(Default)]
+struct Merger {
+    first_arrivals: HashMap<ShredId, Instant>,
+    expiry: VecDeque<(Instant, ShredId)>,
+}
+
+impl Merger {
+    /// When `id` first arrived, `None` if this is its first copy
+    fn first_arrival(&mut self, id: ShredId, arrived: Instant) -> Option<Instant> {
+        while let Some((first, expired)) = self.expiry.front()
+            && arrived.saturating_duration_since(*first) > MERGE_WINDOW
+        {
+            self.first_arrivals.remove(expired);
+            self.expiry.pop_front();
+        }
+        match self.first_arrivals.entry(id) {
+            Entry::Occupied(first) => Some(*first.get()),
+            Entry::Vacant(entry) => {
+                entry.insert(arrived);
+                self.expiry.push_back((arrived, id));
+                None
+            }
+        }
+    }
+}
+
+/// Packets received from upstream shredcasters, in the order they arrive,
+/// which lets a relay fan a feed out without root or TVU ports
+pub struct RelaySource {
+    rx: mpsc::Receiver<Received>,
+    received: Vec<Received>,
+    /// Set if only the first copy of every shred is relayed
+    merger: Option<Merger>,
+    stats: Arc<FeedStats>,
+    /// Aborted when the relay is dropped
+    _feeds: JoinSet<()>,
+}
+
+impl RelaySource {
+    /// Relays every packet of `feeds`
+    pub async fn bind(feeds: &[FeedConfig]) -> anyhow::Result<Self> {
+        Self::start(feeds, false).await
+    }
+
+    /// Relays the first copy of every shred any of `feeds` delivers, counting
+    /// how often each feed was first and how far behind it was otherwise
+    pub async fn merge(feeds: &[FeedConfig]) -> anyhow::Result<Self> {
+        Self::start(feeds, true).await
+    }
+
+    async fn start(feeds: &[FeedConfig], merging: bool) -> anyhow::Result<Self> {
+        if feeds.is_empty() {
+            return Err(anyhow::anyhow!("must specify a feed to relay"));
+        }
+        let (tx, rx) = mpsc::channel(QUEUE_LEN);
+        let mut tasks = JoinSet::new();
+        for (feed, config) in feeds.iter().enumerate() {
+            let addr = config.addr;
+            let run = Feed::bind(config)
+                .await
+                .with_context(|| format!("failed to bind feed {addr}"))?
+                .run(
+                    addr,
+                    config.options.clone(),
+                    FeedTx {
+                        feed,
+                        tx: tx.clone(),
+                    },
+                );
+            tasks.spawn(async move {
+                if let Err(e) = run.await {
+                    eprintln!("feed {addr} stopped: {e}");
+                }
+            });
+            println!("started relaying feed {addr}");
+        }
+        Ok(Self {
+            rx,
+            received: Vec::new(),
+            merger: merging.then(Merger::default),
+            stats: Arc::new(FeedStats {
+                merging,
+                feeds: feeds
+                    .iter()
+                    .map(|config| (config.addr, FeedCtr::default()))
+                    .collect(),
+            }),
+            _feeds: tasks,
+        })
+    }
+}
+
+impl PacketSource for RelaySource {
+    fn recv<'a>(
+        &'a mut self,
+        batch: &'a mut Vec<CapturedPacket>,
+    ) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            // the channel only closes once every feed stopped
+            if self.rx.recv_many(&mut self.received, MAX_BATCH).await == 0 {
+                return Ok(false);
+            }
+            for (feed, arrived, packet) in self.received.drain(..) {
+                let (_, ctr) = &self.stats.feeds[feed];
+                ctr.packets.fetch_add(1, Ordering::Relaxed);
+                let Some(merger) = &mut self.merger else {
+                    batch.push(packet);
+                    continue;
+                };
+                // what isn't a shred can't be told apart from its copies
+                let Some(id) = shred_id(packet.data.as_ref()) else {
+                    batch.push(packet);
+                    continue;
+                };
+                match merger.first_arrival(id, arrived) {
+                    Some(first) => ctr.record_lag(arrived.saturating_duration_since(first)),
+                    None => {
+                        ctr.wins.fetch_add(1, Ordering::Relaxed);
+                        batch.push(packet);
+                    }
+                }
+            }
+            Ok(true)
+        })
+    }
+
+    fn metrics(&self) -> Option<Arc<dyn SourceMetrics>> {
+        Some(self.stats.clone())
+    }
+}
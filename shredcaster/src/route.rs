@@ -0,0 +1,301 @@
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use agave_xdp::netlink::MacAddress;
+use netlink_packet_core::{
+    NLM_F_ACK, NLM_F_CREATE, NLM_F_DUMP, NLM_F_REPLACE, NLM_F_REQUEST, NetlinkHeader,
+    NetlinkMessage, NetlinkPayload,
+};
+use netlink_packet_route::{
+    AF_INET, AF_INET6, NeighbourMessage, RouteMessage, RtnlMessage,
+    constants::{NUD_FAILED, NUD_INCOMPLETE, NUD_NONE, NUD_PERMANENT},
+    nlas::{neighbour, route},
+};
+use netlink_sys::{Socket, SocketAddr, protocols::NETLINK_ROUTE};
+
+const RTNLGRP_LINK: u32 = 1;
+const RTNLGRP_NEIGH: u32 = 3;
+const RTNLGRP_IPV4_ROUTE: u32 = 7;
+const RTNLGRP_IPV6_ROUTE: u32 = 11;
+
+/// The resolved next hop for a destination
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NextHop {
+    pub if_index: u32,
+    /// The gateway, or the destination itself if it is on-link
+    pub ip_addr: IpAddr,
+    pub mac_addr: Option<MacAddress>,
+}
+
+pub fn if_nametoindex(name: &str) -> io::Result<u32> {
+    let name = CString::new(name).map_err(io::Error::other)?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        if_index => Ok(if_index),
+    }
+}
+
+pub fn if_indextoname(if_index: u32) -> io::Result<String> {
+    let mut buf = [0 as libc::c_char; libc::IF_NAMESIZE];
+    let ptr = unsafe { libc::if_indextoname(if_index, buf.as_mut_ptr()) };
+    if ptr.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { CStr::from_ptr(ptr) }
+        .to_string_lossy()
+        .into_owned())
+}
+
+fn ip_from_bytes(bytes: &[u8]) -> Option<IpAddr> {
+    if let Ok(v4) = <[u8; 4]>::try_from(bytes) {
+        Some(Ipv4Addr::from(v4).into())
+    } else if let Ok(v6) = <[u8; 16]>::try_from(bytes) {
+        Some(Ipv6Addr::from(v6).into())
+    } else {
+        None
+    }
+}
+
+fn ip_to_bytes(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+/// Resolves routes and neighbours by querying the kernel over netlink
+pub struct Router {
+    socket: Socket,
+    seq: u32,
+}
+
+impl Router {
+    pub fn new() -> io::Result<Self> {
+        let mut socket = Socket::new(NETLINK_ROUTE)?;
+        socket.bind_auto()?;
+        socket.connect(&SocketAddr::new(0, 0))?;
+        Ok(Self { socket, seq: 0 })
+    }
+
+    fn request(&mut self, flags: u16, msg: RtnlMessage) -> io::Result<Vec<RtnlMessage>> {
+        self.seq = self.seq.wrapping_add(1);
+        let mut header = NetlinkHeader::default();
+        header.flags = flags;
+        header.sequence_number = self.seq;
+        let mut req = NetlinkMessage::new(header, NetlinkPayload::from(msg));
+        req.finalize();
+        let mut buf = vec![0; req.header.length as usize];
+        req.serialize(&mut buf);
+        self.socket.send(&buf, 0)?;
+
+        let mut replies = Vec::new();
+        loop {
+            let (buf, _) = self.socket.recv_from_full()?;
+            let mut offset = 0;
+            while offset < buf.len() {
+                let msg: NetlinkMessage<RtnlMessage> =
+                    NetlinkMessage::deserialize(&buf[offset..]).map_err(io::Error::other)?;
+                let len = msg.header.length as usize;
+                let seq = msg.header.sequence_number;
+                match msg.payload {
+                    _ if seq != self.seq => {}
+                    NetlinkPayload::Done(_) => return Ok(replies),
+                    NetlinkPayload::Error(err) if err.code.is_some() => return Err(err.to_io()),
+                    // acknowledges a request that has no reply
+                    NetlinkPayload::Error(_) => return Ok(replies),
+                    NetlinkPayload::InnerMessage(msg) => {
+                        replies.push(msg);
+                        if flags & NLM_F_DUMP != NLM_F_DUMP {
+                            return Ok(replies);
+                        }
+                    }
+                    _ => {}
+                }
+                if len == 0 {
+                    break;
+                }
+                offset += len;
+            }
+        }
+    }
+
+    /// Dumps the neighbour table as `(if_index, ip) -> mac`
+    pub fn neighbours(&mut self) -> io::Result<HashMap<(u32, IpAddr), MacAddress>> {
+        let replies = self.request(
+            NLM_F_REQUEST | NLM_F_DUMP,
+            RtnlMessage::GetNeighbour(NeighbourMessage::default()),
+        )?;
+        let mut neighbours = HashMap::new();
+        for msg in replies {
+            let RtnlMessage::NewNeighbour(entry) = msg else {
+                continue;
+            };
+            if matches!(entry.header.state, NUD_NONE | NUD_INCOMPLETE | NUD_FAILED) {
+                continue;
+            }
+            let mut ip = None;
+            let mut mac = None;
+            for nla in entry.nlas {
+                match nla {
+                    neighbour::Nla::Destination(addr) => ip = ip_from_bytes(&addr),
+                    neighbour::Nla::LinkLocalAddress(addr) => {
+                        mac = <[u8; 6]>::try_from(addr.as_slice()).ok().map(MacAddress)
+                    }
+                    _ => {}
+                }
+            }
+            if let (Some(ip), Some(mac)) = (ip, mac) {
+                neighbours.insert((entry.header.ifindex, ip), mac);
+            }
+        }
+        Ok(neighbours)
+    }
+
+    fn neighbour(if_index: u32, ip: IpAddr) -> NeighbourMessage {
+        let mut msg = NeighbourMessage::default();
+        msg.header.family = match ip {
+            IpAddr::V4(_) => AF_INET as u8,
+            IpAddr::V6(_) => AF_INET6 as u8,
+        };
+        msg.header.ifindex = if_index;
+        msg.nlas.push(neighbour::Nla::Destination(ip_to_bytes(ip)));
+        msg
+    }
+
+    /// Adds or replaces a permanent neighbour entry mapping `ip` to `mac` on `if_index`
+    pub fn set_neighbour(&mut self, if_index: u32, ip: IpAddr, mac: MacAddress) -> io::Result<()> {
+        let mut msg = Self::neighbour(if_index, ip);
+        msg.header.state = NUD_PERMANENT;
+        msg.nlas
+            .push(neighbour::Nla::LinkLocalAddress(mac.0.to_vec()));
+        self.request(
+            NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_REPLACE,
+            RtnlMessage::NewNeighbour(msg),
+        )
+        .map(drop)
+    }
+
+    pub fn del_neighbour(&mut self, if_index: u32, ip: IpAddr) -> io::Result<()> {
+        self.request(
+            NLM_F_REQUEST | NLM_F_ACK,
+            RtnlMessage::DelNeighbour(Self::neighbour(if_index, ip)),
+        )
+        .map(drop)
+    }
+
+    /// Asks the kernel which interface and gateway `dst` is routed through,
+    /// optionally restricted to the interface `oif`
+    pub fn route(&mut self, dst: IpAddr, oif: Option<u32>) -> io::Result<(u32, IpAddr)> {
+        let mut msg = RouteMessage::default();
+        let (family, prefix_len) = match dst {
+            IpAddr::V4(_) => (AF_INET, 32),
+            IpAddr::V6(_) => (AF_INET6, 128),
+        };
+        msg.header.address_family = family as u8;
+        msg.header.destination_prefix_length = prefix_len;
+        msg.nlas.push(route::Nla::Destination(ip_to_bytes(dst)));
+        if let Some(oif) = oif {
+            msg.nlas.push(route::Nla::Oif(oif));
+        }
+
+        let replies = self.request(NLM_F_REQUEST, RtnlMessage::GetRoute(msg))?;
+        let Some(RtnlMessage::NewRoute(route)) = replies.into_iter().next() else {
+            return Err(io::Error::other(format!("no route to {dst}")));
+        };
+        let mut if_index = None;
+        let mut gateway = None;
+        for nla in route.nlas {
+            match nla {
+                route::Nla::Oif(idx) => if_index = Some(idx),
+                route::Nla::Gateway(addr) => gateway = ip_from_bytes(&addr),
+                _ => {}
+            }
+        }
+        let if_index =
+            if_index.ok_or_else(|| io::Error::other(format!("no output interface for {dst}")))?;
+
+        Ok((if_index, gateway.unwrap_or(dst)))
+    }
+
+    pub fn next_hop(
+        &mut self,
+        dst: IpAddr,
+        oif: Option<u32>,
+        neighbours: &HashMap<(u32, IpAddr), MacAddress>,
+    ) -> io::Result<NextHop> {
+        let (if_index, ip_addr) = self.route(dst, oif)?;
+        Ok(NextHop {
+            if_index,
+            ip_addr,
+            mac_addr: neighbours.get(&(if_index, ip_addr)).copied(),
+        })
+    }
+}
+
+/// A non-blocking netlink socket subscribed to link, route and neighbour changes
+pub struct RouteEvents {
+    socket: Socket,
+    buf: Vec<u8>,
+}
+
+impl RouteEvents {
+    pub fn subscribe() -> io::Result<Self> {
+        let mut socket = Socket::new(NETLINK_ROUTE)?;
+        socket.bind_auto()?;
+        for group in [
+            RTNLGRP_LINK,
+            RTNLGRP_NEIGH,
+            RTNLGRP_IPV4_ROUTE,
+            RTNLGRP_IPV6_ROUTE,
+        ] {
+            socket.add_membership(group)?;
+        }
+        socket.set_non_blocking(true)?;
+        Ok(Self {
+            socket,
+            buf: Vec::with_capacity(1 << 16),
+        })
+    }
+
+    /// Drains pending events, returning whether any of them could affect routing
+    pub fn poll_changed(&mut self) -> io::Result<bool> {
+        let mut changed = false;
+        loop {
+            self.buf.clear();
+            match self.socket.recv(&mut self.buf, 0) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(changed),
+                // the socket buffer overflowed, we lost events so assume something changed
+                Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => changed = true,
+                Err(e) => return Err(e),
+            }
+            let mut offset = 0;
+            while offset < self.buf.len() {
+                let Ok(msg) = NetlinkMessage::<RtnlMessage>::deserialize(&self.buf[offset..])
+                else {
+                    changed = true;
+                    break;
+                };
+                changed |= matches!(
+                    msg.payload,
+                    NetlinkPayload::InnerMessage(
+                        RtnlMessage::NewLink(_)
+                            | RtnlMessage::DelLink(_)
+                            | RtnlMessage::NewNeighbour(_)
+                            | RtnlMessage::DelNeighbour(_)
+                            | RtnlMessage::NewRoute(_)
+                            | RtnlMessage::DelRoute(_)
+                    )
+                );
+                if msg.header.length == 0 {
+                    break;
+                }
+                offset += msg.header.length as usize;
+            }
+        }
+    }
+}
@@ -1,57 +1,73 @@
 use std::{
     collections::{HashMap, HashSet},
     sync::Arc,
+    time::Instant,
 };
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use solana_ledger::shred::layout;
 use tokio::sync::broadcast;
-use wtransport::{endpoint::IncomingSession, error::StreamWriteError};
+use wtransport::{Identity, endpoint::IncomingSession, error::StreamWriteError};
 
-use crate::SharedPacketData;
-
-pub trait ShredSamplerTx {
-    /// queue a shred for sampling
-    /// Returning None if shred is invalid
-    fn insert_shred(&mut self, shred: &SharedPacketData) -> Option<()>;
-
-    fn flush(&mut self);
-}
+use crate::{CapturedPacket, SharedPacketData, sink::ShredSink};
 
+/// Publishes the signatures of the shreds of the newest slot to WebTransport clients
 pub struct MaxShredSamplerTx {
     tx: crossbeam_channel::Sender<(u64, Vec<SharedPacketData>)>,
     max_slot: u64,
     slot_shreds: Vec<SharedPacketData>,
 }
 
-impl ShredSamplerTx for MaxShredSamplerTx {
-    fn insert_shred(&mut self, shred: &SharedPacketData) -> Option<()> {
-        let slot = layout::get_slot(&shred.0)?;
-        if slot > self.max_slot {
-            self.max_slot = slot;
-            self.slot_shreds.clear();
-            self.slot_shreds.push(shred.clone());
-        } else if slot == self.max_slot {
-            self.slot_shreds.push(shred.clone());
+impl ShredSink for MaxShredSamplerTx {
+    fn send(&mut self, packets: &[CapturedPacket]) {
+        for packet in packets {
+            let Some(slot) = layout::get_slot(&packet.data.0) else {
+                continue;
+            };
+            if slot > self.max_slot {
+                self.max_slot = slot;
+                self.slot_shreds.clear();
+                self.slot_shreds.push(packet.data.clone());
+            } else if slot == self.max_slot {
+                self.slot_shreds.push(packet.data.clone());
+            }
         }
-        Some(())
     }
 
-    fn flush(&mut self) {
-        _ = self
-            .tx
-            .try_send((self.max_slot, std::mem::take(&mut self.slot_shreds)));
+    fn flush(&mut self, _now: Instant) -> Option<Instant> {
+        if !self.slot_shreds.is_empty() {
+            _ = self
+                .tx
+                .try_send((self.max_slot, std::mem::take(&mut self.slot_shreds)));
+        }
+        None
     }
 }
 
-pub struct NoOpShredSamplerTx;
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WebTransportSamplerOptions {
+    /// Path to a PEM encoded TLS certificate
+    pub cert: String,
+    /// Path to a PEM encoded TLS private key
+    pub private_key: String,
+    /// Clients connect to `/shred_sample?token=<auth_token>`
+    pub auth_token: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+}
 
-impl ShredSamplerTx for NoOpShredSamplerTx {
-    fn insert_shred(&mut self, _shred: &SharedPacketData) -> Option<()> {
-        Some(())
-    }
+fn default_port() -> u16 {
+    4433
+}
 
-    fn flush(&mut self) {}
+impl WebTransportSamplerOptions {
+    pub async fn spawn(self) -> anyhow::Result<MaxShredSamplerTx> {
+        let config = wtransport::ServerConfig::builder()
+            .with_bind_default(self.port)
+            .with_identity(Identity::load_pemfiles(&self.cert, &self.private_key).await?)
+            .build();
+        spawn_webtransport_shred_sampler(self.auth_token, config)
+    }
 }
 
 #[derive(Serialize)]
@@ -0,0 +1,312 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+use crossbeam_channel::{RecvTimeoutError, Sender, TrySendError};
+
+use figment::value::{Dict, Value};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tokio::sync::broadcast;
+
+use crate::{
+    CapturedPacket, archive::ArchiveSinkOptions, capture::CaptureContext,
+    export::ExportSinkOptions, pcap::PcapngSinkOptions, shred_sampler::WebTransportSamplerOptions,
+};
+
+/// An output the captured packets are fanned out to
+pub trait ShredSink: Send {
+    /// Handles packets captured together, oldest first
+    fn send(&mut self, packets: &[CapturedPacket]);
+
+    /// Called after every batch and once a deadline it returned passes,
+    /// returns when it has to be called again to send what it buffered
+    fn flush(&mut self, _now: Instant) -> Option<Instant> {
+        None
+    }
+
+    /// Whether the sink stopped for good and drops everything it is sent
+    fn failed(&self) -> bool {
+        false
+    }
+
+    /// Called before the first packet with where local packets are captured
+    fn attach(&mut self, _context: &Arc<CaptureContext>) {}
+}
+
+impl<T: ShredSink + ?Sized> ShredSink for Box<T> {
+    fn send(&mut self, packets: &[CapturedPacket]) {
+        (**self).send(packets)
+    }
+
+    fn flush(&mut self, now: Instant) -> Option<Instant> {
+        (**self).flush(now)
+    }
+
+    fn failed(&self) -> bool {
+        (**self).failed()
+    }
+
+    fn attach(&mut self, context: &Arc<CaptureContext>) {
+        (**self).attach(context)
+    }
+}
+
+/// Publishes every packet to the subscribers of a broadcast channel
+impl ShredSink for broadcast::Sender<CapturedPacket> {
+    fn send(&mut self, packets: &[CapturedPacket]) {
+        for packet in packets {
+            // only fails when nobody is subscribed
+            _ = broadcast::Sender::send(self, packet.clone());
+        }
+    }
+}
+
+/// Batches handed to the thread of a [`ThreadedSink`] but not yet written
+const WRITER_QUEUE_LEN: usize = 1024;
+
+/// Writes the packets of a [`ThreadedSink`] on its thread
+pub trait BatchWriter: Send + 'static {
+    /// Called before the first packet with where local packets are captured
+    fn attach(&mut self, _context: Arc<CaptureContext>) {}
+
+    fn write(&mut self, packets: &[CapturedPacket]) -> anyhow::Result<()>;
+
+    /// When [`BatchWriter::tick`] has to be called even if no packets arrive,
+    /// e.g. to finish a file once it is old enough
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Called once the deadline passed
+    fn tick(&mut self, _now: Instant) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Called once the sink was dropped and everything handed to it written,
+    /// or after a write failed
+    fn finish(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// What the thread of a [`ThreadedSink`] is handed
+enum Job {
+    Attach(Arc<CaptureContext>),
+    Write(Vec<CapturedPacket>),
+}
+
+/// Deletes all but the newest `keep` files named `<prefix>-<unix time in ns>.<extension>` in `dir`
+pub fn delete_oldest(dir: &Path, prefix: &str, extension: &str, keep: usize) -> io::Result<()> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let Some(ts) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(prefix))
+            .and_then(|name| name.strip_prefix('-'))
+            .and_then(|name| name.strip_suffix(extension))
+            .and_then(|name| name.strip_suffix('.'))
+            .and_then(|ts| ts.parse::<u64>().ok())
+        else {
+            continue;
+        };
+        files.push((ts, name));
+    }
+    files.sort_unstable();
+    for (_, name) in files.iter().rev().skip(keep) {
+        if let Err(e) = std::fs::remove_file(dir.join(name)) {
+            eprintln!("failed to delete {}: {e}", name.to_string_lossy());
+        }
+    }
+    Ok(())
+}
+
+/// Hands the packets to a [`BatchWriter`] on a thread of its own, so slow disks
+/// don't hold up capturing, and drops them while the writer falls behind
+pub struct ThreadedSink {
+    name: &'static str,
+    tx: Option<Sender<Job>>,
+    pending: Vec<CapturedPacket>,
+    /// Set while the writer falls behind
+    dropping: bool,
+    /// Set once a write failed and the writer stopped
+    failed: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ThreadedSink {
+    pub fn spawn(name: &'static str, mut writer: impl BatchWriter) -> Self {
+        let (tx, rx) = crossbeam_channel::bounded::<Job>(WRITER_QUEUE_LEN);
+        let failed = Arc::new(AtomicBool::new(false));
+        let writer_failed = failed.clone();
+        let thread = thread::spawn(move || {
+            loop {
+                let batch = match writer.deadline() {
+                    Some(deadline) => rx.recv_deadline(deadline),
+                    None => rx.recv().map_err(RecvTimeoutError::from),
+                };
+                let result = match batch {
+                    Ok(Job::Attach(context)) => {
+                        writer.attach(context);
+                        Ok(())
+                    }
+                    Ok(Job::Write(batch)) => writer.write(&batch),
+                    Err(RecvTimeoutError::Timeout) => writer.tick(Instant::now()),
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+                if let Err(e) = result {
+                    eprintln!("{name} sink stopped: {e}");
+                    writer_failed.store(true, Ordering::Relaxed);
+                    break;
+                }
+            }
+            // also after a failed write, so that what was written stays readable
+            if let Err(e) = writer.finish() {
+                eprintln!("{name} sink failed to finish: {e}");
+            }
+        });
+        Self {
+            name,
+            tx: Some(tx),
+            pending: Vec::new(),
+            dropping: false,
+            failed,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl ShredSink for ThreadedSink {
+    fn send(&mut self, packets: &[CapturedPacket]) {
+        if self.tx.is_some() {
+            self.pending.extend_from_slice(packets);
+        }
+    }
+
+    fn flush(&mut self, _now: Instant) -> Option<Instant> {
+        let (Some(tx), false) = (&self.tx, self.pending.is_empty()) else {
+            return None;
+        };
+        match tx.try_send(Job::Write(std::mem::take(&mut self.pending))) {
+            Ok(()) if self.dropping => {
+                println!("{} sink caught up", self.name);
+                self.dropping = false;
+            }
+            Err(TrySendError::Full(_)) if !self.dropping => {
+                eprintln!("{} sink fell behind, dropping packets", self.name);
+                self.dropping = true;
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                eprintln!("{} sink stopped, dropping all its packets", self.name);
+                self.failed.store(true, Ordering::Relaxed);
+                self.tx = None;
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn failed(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    fn attach(&mut self, context: &Arc<CaptureContext>) {
+        if let Some(tx) = &self.tx {
+            // nothing was queued yet, so this doesn't wait
+            _ = tx.send(Job::Attach(context.clone()));
+        }
+    }
+}
+
+impl Drop for ThreadedSink {
+    /// Waits for the writer to write what it was handed and finish
+    fn drop(&mut self) {
+        self.tx = None;
+        if let Some(thread) = self.thread.take() {
+            _ = thread.join();
+        }
+    }
+}
+
+/// A sink named in config.toml, e.g.
+/// `[[sinks]] sink = "webtransport"` followed by its options
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SinkConfig {
+    /// The name the sink is registered under
+    pub sink: String,
+    #[serde(flatten)]
+    pub options: Dict,
+}
+
+impl SinkConfig {
+    pub fn options<T: DeserializeOwned>(&self) -> anyhow::Result<T> {
+        Value::from(self.options.clone())
+            .deserialize()
+            .map_err(|e| anyhow::anyhow!("invalid options for sink {}: {e}", self.sink))
+    }
+}
+
+pub type SinkFactory =
+    Box<dyn Fn(SinkConfig) -> BoxFuture<'static, anyhow::Result<Box<dyn ShredSink>>> + Send + Sync>;
+
+/// Builds sinks from their config by name
+pub struct SinkRegistry {
+    factories: HashMap<String, SinkFactory>,
+}
+
+impl Default for SinkRegistry {
+    /// Knows the built in sinks
+    fn default() -> Self {
+        let mut registry = Self {
+            factories: HashMap::new(),
+        };
+        registry.register("webtransport", |config| async move {
+            let options: WebTransportSamplerOptions = config.options()?;
+            Ok(Box::new(options.spawn().await?) as Box<dyn ShredSink>)
+        });
+        registry.register("pcapng", |config| async move {
+            let options: PcapngSinkOptions = config.options()?;
+            Ok(Box::new(options.spawn()?) as Box<dyn ShredSink>)
+        });
+        registry.register("archive", |config| async move {
+            let options: ArchiveSinkOptions = config.options()?;
+            Ok(Box::new(options.spawn()?) as Box<dyn ShredSink>)
+        });
+        registry.register("export", |config| async move {
+            let options: ExportSinkOptions = config.options()?;
+            Ok(Box::new(options.spawn()?) as Box<dyn ShredSink>)
+        });
+        registry
+    }
+}
+
+impl SinkRegistry {
+    /// Makes `name` usable in config.toml, replacing any sink registered under it
+    pub fn register<F, Fut>(&mut self, name: impl Into<String>, factory: F)
+    where
+        F: Fn(SinkConfig) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<Box<dyn ShredSink>>> + Send + 'static,
+    {
+        self.factories.insert(
+            name.into(),
+            Box::new(move |config| Box::pin(factory(config))),
+        );
+    }
+
+    pub async fn build(&self, config: &SinkConfig) -> anyhow::Result<Box<dyn ShredSink>> {
+        let factory = self
+            .factories
+            .get(&config.sink)
+            .ok_or_else(|| anyhow::anyhow!("unknown sink {}", config.sink))?;
+        factory(config.clone()).await
+    }
+}
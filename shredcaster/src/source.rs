@@ -0,0 +1,205 @@
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use arrayvec::ArrayVec;
+use futures::future::{BoxFuture, select_all};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio::net::UdpSocket;
+
+use crate::{CapturedPacket, PACKET_DATA_SIZE, SharedPacketData};
+
+/// Most packets a source hands over at once, so sinks see them without much delay
+pub const MAX_BATCH: usize = 1024;
+
+/// Which source the CLI captures from
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum SourceKind {
+    /// The BPF programs, attached to the interface
+    #[default]
+    Xdp,
+    /// An `AF_PACKET` socket on the interface
+    AfPacket,
+    /// UDP sockets bound to the TVU ports
+    Udp,
+    /// A pcap or pcapng file
+    Pcap(PathBuf),
+    /// A directory of archive segments
+    Archive(PathBuf),
+    /// The feeds of upstream shredcasters
+    Relay,
+    /// The first copy of every shred the feeds of upstream shredcasters deliver
+    Merge,
+}
+
+impl FromStr for SourceKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "xdp" => Ok(SourceKind::Xdp),
+            "af_packet" => Ok(SourceKind::AfPacket),
+            "udp" => Ok(SourceKind::Udp),
+            "relay" => Ok(SourceKind::Relay),
+            "merge" => Ok(SourceKind::Merge),
+            _ => match s.split_once(':') {
+                Some(("pcap", path)) => Ok(SourceKind::Pcap(path.into())),
+                Some(("archive", dir)) => Ok(SourceKind::Archive(dir.into())),
+                _ => anyhow::bail!("unsupported packet source {s}"),
+            },
+        }
+    }
+}
+
+impl fmt::Display for SourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceKind::Xdp => write!(f, "xdp"),
+            SourceKind::AfPacket => write!(f, "af_packet"),
+            SourceKind::Udp => write!(f, "udp"),
+            SourceKind::Pcap(path) => write!(f, "pcap:{}", path.display()),
+            SourceKind::Archive(dir) => write!(f, "archive:{}", dir.display()),
+            SourceKind::Relay => write!(f, "relay"),
+            SourceKind::Merge => write!(f, "merge"),
+        }
+    }
+}
+
+impl Serialize for SourceKind {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for SourceKind {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        String::deserialize(d)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Where the watcher gets captured packets from
+pub trait PacketSource: Send {
+    /// Waits for packets and appends them to `batch`, oldest first,
+    /// returns false once the source has no more packets
+    fn recv<'a>(
+        &'a mut self,
+        batch: &'a mut Vec<CapturedPacket>,
+    ) -> BoxFuture<'a, anyhow::Result<bool>>;
+
+    /// Counters of the source to serve alongside the others, if it keeps any
+    fn metrics(&self) -> Option<Arc<dyn SourceMetrics>> {
+        None
+    }
+}
+
+impl<T: PacketSource + ?Sized> PacketSource for Box<T> {
+    fn recv<'a>(
+        &'a mut self,
+        batch: &'a mut Vec<CapturedPacket>,
+    ) -> BoxFuture<'a, anyhow::Result<bool>> {
+        (**self).recv(batch)
+    }
+
+    fn metrics(&self) -> Option<Arc<dyn SourceMetrics>> {
+        (**self).metrics()
+    }
+}
+
+/// Counters a source keeps, rendered in the Prometheus text format
+pub trait SourceMetrics: Send + Sync {
+    fn render(&self, out: &mut String);
+}
+
+/// The current time as nanoseconds since the unix epoch
+pub fn unix_now_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// The payload of a UDP datagram as the BPF programs would capture it,
+/// `None` if it is empty or larger than a shred
+pub fn shred_data(payload: &[u8]) -> Option<SharedPacketData> {
+    if payload.is_empty() {
+        return None;
+    }
+    ArrayVec::try_from(payload).ok().map(SharedPacketData::new)
+}
+
+/// Datagrams received on ordinary UDP sockets, e.g. bound to the TVU ports
+/// of a test setup, which needs neither root nor BPF support
+pub struct UdpSource {
+    sockets: Vec<UdpSocket>,
+    /// The address each socket is bound to
+    local_addrs: Vec<SocketAddr>,
+    buf: Vec<u8>,
+}
+
+impl UdpSource {
+    pub async fn bind(addrs: &[SocketAddr]) -> anyhow::Result<Self> {
+        if addrs.is_empty() {
+            return Err(anyhow::anyhow!("must specify an address to receive on"));
+        }
+        let mut sockets = Vec::with_capacity(addrs.len());
+        let mut local_addrs = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            let socket = UdpSocket::bind(addr).await?;
+            let local = socket.local_addr()?;
+            local_addrs.push(SocketAddr::new(local.ip().to_canonical(), local.port()));
+            sockets.push(socket);
+            println!("started receiving turbine on {addr}");
+        }
+        Ok(Self {
+            sockets,
+            local_addrs,
+            // one more byte than a shred to tell larger datagrams apart
+            buf: vec![0; PACKET_DATA_SIZE + 1],
+        })
+    }
+}
+
+impl PacketSource for UdpSource {
+    fn recv<'a>(
+        &'a mut self,
+        batch: &'a mut Vec<CapturedPacket>,
+    ) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            select_all(
+                self.sockets
+                    .iter()
+                    .map(|socket| Box::pin(socket.readable())),
+            )
+            .await
+            .0?;
+            let ts_ns = unix_now_ns();
+            for (socket, local_addr) in self.sockets.iter().zip(self.local_addrs.iter()) {
+                while batch.len() < MAX_BATCH {
+                    let (len, src) = match socket.try_recv_from(&mut self.buf) {
+                        Ok(recv) => recv,
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => return Err(e.into()),
+                    };
+                    if let Some(data) = shred_data(&self.buf[..len]) {
+                        batch.push(CapturedPacket {
+                            ts_ns,
+                            src: SocketAddr::new(src.ip().to_canonical(), src.port()),
+                            dst: Some(*local_addr),
+                            is_egress: false,
+                            origin: None,
+                            data,
+                        });
+                    }
+                }
+            }
+            Ok(true)
+        })
+    }
+}
@@ -0,0 +1,319 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use quinn::rustls::{
+    self, RootCertStore,
+    pki_types::{CertificateDer, pem::PemObject},
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt, BufWriter},
+    net::{TcpStream, lookup_host},
+    runtime::Handle,
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+    time::{sleep, timeout},
+};
+
+use crate::{
+    SharedPacketData,
+    capture::Capture,
+    config::ListenerAddr,
+    filter::PacketInfo,
+    forwarder::{Admission, ForwardData},
+    metrics::ListenerCtr,
+    rate_limit::RateLimiter,
+};
+
+const DEFAULT_BUFFER: usize = 8192;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const ALPN: &[u8] = b"shredcaster";
+
+/// What happens when a listener falls behind and its buffer is full
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LagPolicy {
+    /// Drop new packets until the buffer drains
+    #[default]
+    Drop,
+    /// Drop the connection and the buffered packets, the listener reconnects from scratch
+    Disconnect,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct StreamOptions {
+    /// Packets buffered for the listener, 8192 if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buffer: Option<usize>,
+    #[serde(skip_serializing_if = "is_default")]
+    pub on_lag: LagPolicy,
+    /// PEM file of the CA that signed the QUIC listener's certificate,
+    /// the webpki roots are trusted if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_cert: Option<PathBuf>,
+    /// TLS server name of the QUIC listener, its host if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<String>,
+}
+
+fn is_default(policy: &LagPolicy) -> bool {
+    *policy == LagPolicy::default()
+}
+
+type Frame = (u64, ForwardData);
+
+/// State shared between the watcher and a stream task
+#[derive(Default)]
+struct StreamShared {
+    /// Set when a packet was dropped under `LagPolicy::Disconnect`
+    lagged: AtomicBool,
+}
+
+/// A QUIC or TCP listener as seen by the watcher
+#[derive(Clone)]
+pub struct StreamTarget {
+    tx: mpsc::Sender<Frame>,
+    shared: Arc<StreamShared>,
+    on_lag: LagPolicy,
+    admission: Admission,
+}
+
+impl StreamTarget {
+    pub fn forward(
+        &self,
+        packet: &PacketInfo,
+        capture: &Capture,
+        shred: &SharedPacketData,
+        global_limiter: Option<&Mutex<RateLimiter>>,
+        now: Instant,
+    ) {
+        if !self
+            .admission
+            .admit(packet, shred.0.len(), global_limiter, now)
+        {
+            return;
+        }
+        // sequence numbers are taken before buffering, so gaps tell listeners what they missed
+        let seq = self.admission.next_seq();
+        let payload = self.admission.payload(capture, seq, shred);
+        if let Err(TrySendError::Full(_)) = self.tx.try_send((seq, payload)) {
+            self.admission
+                .ctr()
+                .lagged_packets
+                .fetch_add(1, Ordering::Relaxed);
+            if self.on_lag == LagPolicy::Disconnect {
+                self.shared.lagged.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// A running stream task, stopped when dropped
+pub struct StreamLane {
+    options: StreamOptions,
+    tx: mpsc::Sender<Frame>,
+    shared: Arc<StreamShared>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for StreamLane {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl StreamLane {
+    pub fn spawn(
+        runtime: &Handle,
+        addr: ListenerAddr,
+        options: StreamOptions,
+        ctr: Arc<ListenerCtr>,
+    ) -> Self {
+        let (tx, rx) = mpsc::channel(options.buffer.unwrap_or(DEFAULT_BUFFER).max(1));
+        let shared = Arc::new(StreamShared::default());
+        let task = runtime.spawn(run_stream(addr, options.clone(), rx, shared.clone(), ctr));
+        Self {
+            options,
+            tx,
+            shared,
+            task,
+        }
+    }
+
+    pub fn options(&self) -> &StreamOptions {
+        &self.options
+    }
+
+    pub fn target(&self, admission: Admission) -> StreamTarget {
+        StreamTarget {
+            tx: self.tx.clone(),
+            shared: self.shared.clone(),
+            on_lag: self.options.on_lag,
+            admission,
+        }
+    }
+}
+
+type Writer = BufWriter<Pin<Box<dyn AsyncWrite + Send>>>;
+
+fn writer(stream: impl AsyncWrite + Send + 'static) -> Writer {
+    BufWriter::new(Box::pin(stream))
+}
+
+/// An open connection to a stream listener
+struct Connection {
+    writer: Writer,
+    /// Keeps the QUIC connection alive while its stream is written to
+    _quic: Option<(quinn::Endpoint, quinn::Connection)>,
+}
+
+fn split_host(host_port: &str) -> &str {
+    let host = host_port
+        .rsplit_once(':')
+        .map_or(host_port, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn quic_client_config(options: &StreamOptions) -> anyhow::Result<quinn::ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    match &options.ca_cert {
+        Some(path) => {
+            for cert in CertificateDer::pem_file_iter(path)
+                .with_context(|| format!("failed to read {}", path.display()))?
+            {
+                roots.add(cert?)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let mut crypto = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+
+    Ok(quinn::ClientConfig::new(Arc::new(
+        quinn::crypto::rustls::QuicClientConfig::try_from(crypto)?,
+    )))
+}
+
+async fn connect(addr: &ListenerAddr, options: &StreamOptions) -> anyhow::Result<Connection> {
+    match addr {
+        ListenerAddr::Tcp(host_port) => {
+            let stream = TcpStream::connect(host_port.as_str()).await?;
+            stream.set_nodelay(true)?;
+            Ok(Connection {
+                writer: writer(stream),
+                _quic: None,
+            })
+        }
+        ListenerAddr::Quic(host_port) => {
+            let remote = lookup_host(host_port.as_str())
+                .await?
+                .next()
+                .with_context(|| format!("{host_port} did not resolve"))?;
+            let bind: SocketAddr = match remote {
+                SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+            };
+            let mut endpoint = quinn::Endpoint::client(bind)?;
+            endpoint.set_default_client_config(quic_client_config(options)?);
+            let server_name = options
+                .server_name
+                .as_deref()
+                .unwrap_or_else(|| split_host(host_port));
+            let connection = endpoint.connect(remote, server_name)?.await?;
+            let stream = connection.open_uni().await?;
+            Ok(Connection {
+                writer: writer(stream),
+                _quic: Some((endpoint, connection)),
+            })
+        }
+        ListenerAddr::Udp(_) | ListenerAddr::Host(_) => {
+            anyhow::bail!("{addr} is not a stream listener")
+        }
+    }
+}
+
+/// Writes one frame: `seq: u64 LE | len: u16 LE | shred`
+async fn write_frame(writer: &mut Writer, (seq, data): &Frame) -> std::io::Result<()> {
+    writer.write_all(&seq.to_le_bytes()).await?;
+    writer
+        .write_all(&(data.as_ref().len() as u16).to_le_bytes())
+        .await?;
+    writer.write_all(data.as_ref()).await
+}
+
+/// Sends frames until the connection fails or has to be dropped for lagging
+async fn pump(
+    connection: &mut Connection,
+    rx: &mut mpsc::Receiver<Frame>,
+    shared: &StreamShared,
+) -> anyhow::Result<()> {
+    while let Some(frame) = rx.recv().await {
+        if shared.lagged.swap(false, Ordering::Relaxed) {
+            while rx.try_recv().is_ok() {}
+            anyhow::bail!("listener fell behind");
+        }
+        write_frame(&mut connection.writer, &frame).await?;
+        while let Ok(frame) = rx.try_recv() {
+            write_frame(&mut connection.writer, &frame).await?;
+        }
+        connection.writer.flush().await?;
+    }
+
+    Ok(())
+}
+
+async fn run_stream(
+    addr: ListenerAddr,
+    options: StreamOptions,
+    mut rx: mpsc::Receiver<Frame>,
+    shared: Arc<StreamShared>,
+    ctr: Arc<ListenerCtr>,
+) {
+    let mut backoff = MIN_BACKOFF;
+    while !rx.is_closed() {
+        let error = match timeout(CONNECT_TIMEOUT, connect(&addr, &options)).await {
+            Ok(Ok(mut connection)) => {
+                println!("connected to listener {addr}");
+                ctr.suspended.store(false, Ordering::Relaxed);
+                if options.on_lag == LagPolicy::Disconnect {
+                    // start from scratch instead of replaying what piled up while disconnected
+                    shared.lagged.store(false, Ordering::Relaxed);
+                    while rx.try_recv().is_ok() {}
+                }
+                backoff = MIN_BACKOFF;
+                match pump(&mut connection, &mut rx, &shared).await {
+                    Ok(()) => return,
+                    Err(e) => e,
+                }
+            }
+            Ok(Err(e)) => e,
+            Err(_) => anyhow::anyhow!("connection timed out"),
+        };
+        eprintln!(
+            "listener {addr} is unreachable ({error}), reconnecting in {}s",
+            backoff.as_secs()
+        );
+        if !ctr.suspended.swap(true, Ordering::Relaxed) {
+            ctr.suspensions.fetch_add(1, Ordering::Relaxed);
+        }
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
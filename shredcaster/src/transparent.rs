@@ -0,0 +1,77 @@
+use std::{
+    net::{SocketAddr, SocketAddrV4},
+    thread::{self, JoinHandle},
+};
+
+use crossbeam_channel::Receiver;
+use shredcaster_proto::net::{checksum_add, checksum_finish};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+use crate::forwarder::{ForwardData, ForwardPacket};
+
+const IPV4_HEADER_LEN: usize = 20;
+const UDP_HEADER_LEN: usize = 8;
+const TTL: u8 = 64;
+
+/// Writes an IPv4 datagram from `src` to `dst` carrying `payload`, the kernel
+/// fills in the IP header checksum and packet id
+fn write_udp_ipv4(buf: &mut Vec<u8>, src: &SocketAddrV4, dst: &SocketAddrV4, payload: &[u8]) {
+    let udp_len = (UDP_HEADER_LEN + payload.len()) as u16;
+    let total_len = IPV4_HEADER_LEN as u16 + udp_len;
+    buf.clear();
+    buf.extend_from_slice(&[0x45, 0]);
+    buf.extend_from_slice(&total_len.to_be_bytes());
+    // id, don't fragment
+    buf.extend_from_slice(&[0, 0, 0x40, 0]);
+    buf.extend_from_slice(&[TTL, libc::IPPROTO_UDP as u8, 0, 0]);
+    buf.extend_from_slice(&src.ip().octets());
+    buf.extend_from_slice(&dst.ip().octets());
+
+    buf.extend_from_slice(&src.port().to_be_bytes());
+    buf.extend_from_slice(&dst.port().to_be_bytes());
+    buf.extend_from_slice(&udp_len.to_be_bytes());
+    buf.extend_from_slice(&[0, 0]);
+    buf.extend_from_slice(payload);
+
+    // pseudo header, then the UDP header and payload
+    let mut sum = checksum_add(0, &buf[12..20]);
+    sum += u32::from(libc::IPPROTO_UDP as u8) + u32::from(udp_len);
+    let udp_checksum = match checksum_finish(checksum_add(sum, &buf[IPV4_HEADER_LEN..])) {
+        // zero means no checksum
+        0 => 0xffff,
+        udp_checksum => udp_checksum,
+    };
+    buf[IPV4_HEADER_LEN + 6..IPV4_HEADER_LEN + 8].copy_from_slice(&udp_checksum.to_be_bytes());
+}
+
+/// Sends packets from the address they were originally sent from, through a
+/// raw socket bound to `if_name`
+///
+/// Packets captured from IPv6 sources can't be sent to IPv4 listeners and are dropped
+pub fn spawn_transparent_tx_loop(
+    if_name: String,
+    rx: Receiver<ForwardPacket>,
+) -> anyhow::Result<JoinHandle<()>> {
+    // IPPROTO_RAW implies IP_HDRINCL
+    let socket = Socket::new(
+        Domain::IPV4,
+        Type::RAW,
+        Some(Protocol::from(libc::IPPROTO_RAW)),
+    )?;
+    socket.bind_device(Some(if_name.as_bytes()))?;
+    Ok(thread::spawn(move || {
+        let mut buf = Vec::with_capacity(IPV4_HEADER_LEN + UDP_HEADER_LEN + 1500);
+        while let Ok((addrs, data)) = rx.recv() {
+            let ForwardData::Transparent(SocketAddr::V4(src), data) = &data else {
+                continue;
+            };
+            for addr in addrs.iter() {
+                let SocketAddr::V4(dst) = addr else {
+                    continue;
+                };
+                write_udp_ipv4(&mut buf, src, dst, (**data).as_ref());
+                _ = socket.send_to(&buf, &SockAddr::from(*addr));
+            }
+        }
+    }))
+}
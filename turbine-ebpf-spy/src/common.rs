@@ -5,8 +5,31 @@ use aya_ebpf::{macros::map, maps::RingBuf};
 
 pub const PACKET_DATA_SIZE: usize = 1232;
 
-// Packet, is_egress
-pub type PacketBuf = (ArrayVec<u8, PACKET_DATA_SIZE>, bool);
+/// Addresses are stored as IPv6, IPv4 addresses are mapped into `::ffff:0:0/96`
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct PacketMeta {
+    /// `bpf_ktime_get_ns` at capture
+    pub ts_ns: u64,
+    pub src_addr: [u8; 16],
+    pub src_port: u16,
+    pub is_egress: bool,
+    pub dst_addr: [u8; 16],
+    pub dst_port: u16,
+}
+
+#[inline(always)]
+pub fn ipv4_mapped(addr: [u8; 4]) -> [u8; 16] {
+    [
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, addr[0], addr[1], addr[2], addr[3],
+    ]
+}
+
+#[repr(C)]
+pub struct PacketBuf {
+    pub meta: PacketMeta,
+    pub data: ArrayVec<u8, PACKET_DATA_SIZE>,
+}
 
 pub const PACKET_BUF_SIZE: usize = mem::size_of::<PacketBuf>();
 
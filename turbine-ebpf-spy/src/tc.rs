@@ -1,18 +1,18 @@
 use arrayvec::ArrayVec;
 use aya_ebpf::{
     bindings::TC_ACT_PIPE,
-    helpers::generated::bpf_skb_load_bytes,
+    helpers::generated::{bpf_ktime_get_ns, bpf_skb_load_bytes},
     macros::{classifier, map},
     maps::Array,
     programs::TcContext,
 };
 use network_types::{
     eth::{EthHdr, EtherType},
-    ip::{IpProto, Ipv4Hdr},
+    ip::{IpProto, Ipv4Hdr, Ipv6Hdr},
     udp::UdpHdr,
 };
 
-use crate::common::{PACKET_BUF, PACKET_DATA_SIZE, PacketBuf};
+use crate::common::{PACKET_BUF, PACKET_DATA_SIZE, PacketBuf, PacketMeta, ipv4_mapped};
 
 #[map]
 static SHRED_EGRESS_PORT: Array<u16> = Array::with_max_entries(1, 0);
@@ -27,17 +27,33 @@ pub fn tc_egress_probe(ctx: TcContext) -> i32 {
 
 fn try_tc_egress_probe(ctx: TcContext) -> Result<i32, ()> {
     let ethhdr: EthHdr = ctx.load(0).map_err(|_| ())?;
-    match ethhdr.ether_type() {
-        Ok(EtherType::Ipv4) => {}
-        _ => return Ok(TC_ACT_PIPE),
-    }
     let mut offset = EthHdr::LEN;
+    let mut meta = PacketMeta {
+        ts_ns: unsafe { bpf_ktime_get_ns() },
+        is_egress: true,
+        ..Default::default()
+    };
 
-    let ipv4hdr: Ipv4Hdr = ctx.load(offset).map_err(|_| ())?;
-    offset += Ipv4Hdr::LEN;
-
-    if ipv4hdr.proto != IpProto::Udp {
-        return Ok(TC_ACT_PIPE);
+    match ethhdr.ether_type() {
+        Ok(EtherType::Ipv4) => {
+            let ipv4hdr: Ipv4Hdr = ctx.load(offset).map_err(|_| ())?;
+            if ipv4hdr.proto != IpProto::Udp {
+                return Ok(TC_ACT_PIPE);
+            }
+            meta.src_addr = ipv4_mapped(ipv4hdr.src_addr);
+            meta.dst_addr = ipv4_mapped(ipv4hdr.dst_addr);
+            offset += Ipv4Hdr::LEN;
+        }
+        Ok(EtherType::Ipv6) => {
+            let ipv6hdr: Ipv6Hdr = ctx.load(offset).map_err(|_| ())?;
+            if ipv6hdr.next_hdr != IpProto::Udp {
+                return Ok(TC_ACT_PIPE);
+            }
+            meta.src_addr = ipv6hdr.src_addr;
+            meta.dst_addr = ipv6hdr.dst_addr;
+            offset += Ipv6Hdr::LEN;
+        }
+        _ => return Ok(TC_ACT_PIPE),
     }
 
     let udphdr: UdpHdr = ctx.load(offset).map_err(|_| ())?;
@@ -48,6 +64,8 @@ fn try_tc_egress_probe(ctx: TcContext) -> Result<i32, ()> {
     {
         return Ok(TC_ACT_PIPE);
     }
+    meta.src_port = udphdr.src_port();
+    meta.dst_port = udphdr.dst_port();
     let packet_data_len = udphdr.len() as usize - UdpHdr::LEN;
     if packet_data_len > PACKET_DATA_SIZE {
         return Ok(TC_ACT_PIPE);
@@ -74,8 +92,11 @@ fn try_tc_egress_probe(ctx: TcContext) -> Result<i32, ()> {
         return Ok(TC_ACT_PIPE);
     };
     unsafe {
-        event.write((ArrayVec::new(), true));
-        let (packet_buf, _) = event.assume_init_mut();
+        event.write(PacketBuf {
+            meta,
+            data: ArrayVec::new(),
+        });
+        let packet_buf = &mut event.assume_init_mut().data;
         if offset > packet_data_len {
             event.discard(0);
             return Ok(TC_ACT_PIPE);
@@ -3,7 +3,7 @@ use core::mem;
 use arrayvec::ArrayVec;
 use aya_ebpf::{
     bindings::xdp_action::XDP_PASS,
-    helpers::generated::bpf_xdp_load_bytes,
+    helpers::generated::{bpf_ktime_get_ns, bpf_xdp_load_bytes},
     macros::{map, xdp},
     maps::PerCpuHashMap,
     programs::XdpContext,
@@ -14,7 +14,7 @@ use network_types::{
     udp::UdpHdr,
 };
 
-use crate::common::{PACKET_BUF, PACKET_DATA_SIZE, PacketBuf};
+use crate::common::{PACKET_BUF, PACKET_DATA_SIZE, PacketBuf, PacketMeta, ipv4_mapped};
 
 #[map]
 static TURBINE_PORTS: PerCpuHashMap<u16, u8> = PerCpuHashMap::with_max_entries(100, 0);
@@ -44,6 +44,10 @@ unsafe fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Result<*const T, ()> {
 fn try_xdp_turbine_probe(ctx: XdpContext) -> Result<u32, ()> {
     let eth_hdr: *const EthHdr = unsafe { ptr_at(&ctx, 0)? };
     let mut offset = mem::size_of::<EthHdr>();
+    let mut meta = PacketMeta {
+        ts_ns: unsafe { bpf_ktime_get_ns() },
+        ..Default::default()
+    };
 
     match unsafe { (*eth_hdr).ether_type() } {
         Ok(EtherType::Ipv4) => {
@@ -51,6 +55,8 @@ fn try_xdp_turbine_probe(ctx: XdpContext) -> Result<u32, ()> {
             if unsafe { (*hdr).proto } != IpProto::Udp {
                 return Ok(XDP_PASS);
             }
+            meta.src_addr = ipv4_mapped(unsafe { (*hdr).src_addr });
+            meta.dst_addr = ipv4_mapped(unsafe { (*hdr).dst_addr });
             offset += mem::size_of::<Ipv4Hdr>();
         }
         Ok(EtherType::Ipv6) => {
@@ -58,6 +64,8 @@ fn try_xdp_turbine_probe(ctx: XdpContext) -> Result<u32, ()> {
             if unsafe { (*hdr).next_hdr } != IpProto::Udp {
                 return Ok(XDP_PASS);
             }
+            meta.src_addr = unsafe { (*hdr).src_addr };
+            meta.dst_addr = unsafe { (*hdr).dst_addr };
             offset += mem::size_of::<Ipv6Hdr>();
         }
         _ => return Ok(XDP_PASS),
@@ -69,6 +77,8 @@ fn try_xdp_turbine_probe(ctx: XdpContext) -> Result<u32, ()> {
     if unsafe { TURBINE_PORTS.get(&dst_port) }.is_none() {
         return Ok(XDP_PASS);
     }
+    meta.src_port = unsafe { (*udp_hdr).src_port() };
+    meta.dst_port = dst_port;
 
     let packet_data_len = unsafe { (*udp_hdr).len() } as usize - mem::size_of::<UdpHdr>();
     if packet_data_len > PACKET_DATA_SIZE {
@@ -80,8 +90,11 @@ fn try_xdp_turbine_probe(ctx: XdpContext) -> Result<u32, ()> {
         return Ok(XDP_PASS);
     };
     unsafe {
-        event.write((ArrayVec::new(), false));
-        let (packet_buf, _) = event.assume_init_mut();
+        event.write(PacketBuf {
+            meta,
+            data: ArrayVec::new(),
+        });
+        let packet_buf = &mut event.assume_init_mut().data;
         if offset > packet_data_len || packet_data_len == 0 {
             event.discard(0);
             return Ok(XDP_PASS);
@@ -1,15 +1,155 @@
 use anyhow::Result;
 use clap::Parser;
+use shredcaster_proto::{
+    bundle,
+    envelope::Envelope,
+    fec::{self, FecDecoder},
+    nack::Nack,
+    secure::{Key, Opener, Sealer},
+};
+use std::collections::BTreeSet;
+use std::error::Error;
 use std::net::SocketAddr;
+use std::ops::RangeInclusive;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::net::UdpSocket;
 use tokio::time::timeout;
 
+/// Prefix of shredcaster's listener heartbeats
+const HEARTBEAT_MAGIC: &[u8] = b"SCHBEAT1";
+/// Missed sequence numbers remembered for retransmit stats
+const MAX_MISSING: u64 = 1 << 16;
+
 #[derive(Parser, Debug)]
 struct Args {
     /// Bind address
     #[arg(short, long, default_value = "0.0.0.0:8000")]
     addr: SocketAddr,
+    /// Echo shredcaster heartbeats back so it keeps this listener enabled
+    #[arg(long, default_value_t = false)]
+    echo_heartbeats: bool,
+    /// Hex encoded key of a listener configured with `psk`,
+    /// packets are decrypted and checked for replays before validation
+    #[arg(long, verbatim_doc_comment)]
+    psk: Option<Key>,
+    /// shredcaster's `nack_addr`, gaps in the envelope sequence numbers
+    /// are reported there to have the missed packets resent
+    #[arg(long, verbatim_doc_comment)]
+    nack_to: Option<SocketAddr>,
+}
+
+/// Envelope sequence numbers seen so far
+#[derive(Default)]
+struct Sequence {
+    highest: Option<u64>,
+    missing: BTreeSet<u64>,
+    retransmitted: u64,
+    duplicates: u64,
+}
+
+impl Sequence {
+    /// Records `seq`, returning the sequence numbers it shows were missed
+    fn record(&mut self, seq: u64) -> Option<RangeInclusive<u64>> {
+        match self.highest {
+            Some(highest) if seq <= highest => {
+                if self.missing.remove(&seq) {
+                    self.retransmitted += 1;
+                } else {
+                    self.duplicates += 1;
+                }
+                None
+            }
+            highest => {
+                self.highest = Some(seq);
+                let first = highest? + 1;
+                (first < seq).then(|| {
+                    self.missing
+                        .extend(first.max(seq.saturating_sub(MAX_MISSING))..seq);
+                    while self.missing.len() as u64 > MAX_MISSING {
+                        self.missing.pop_first();
+                    }
+                    first..=seq - 1
+                })
+            }
+        }
+    }
+
+    /// Strips the envelopes of `packets`, returning the gaps they reveal
+    fn strip(&mut self, packets: &mut [Vec<u8>]) -> Vec<RangeInclusive<u64>> {
+        let mut missed = Vec::new();
+        for packet in packets.iter_mut() {
+            let Ok((envelope, payload)) = Envelope::decode(packet) else {
+                continue;
+            };
+            missed.extend(self.record(envelope.seq));
+            *packet = payload.to_vec();
+        }
+        missed
+    }
+}
+
+/// Asks shredcaster to resend packets, from the socket they are received on
+struct NackSender {
+    to: SocketAddr,
+    sealer: Option<Sealer>,
+    seq: u64,
+}
+
+impl NackSender {
+    async fn send(&mut self, socket: &UdpSocket, ranges: Vec<RangeInclusive<u64>>) -> Result<()> {
+        let mut nack = Vec::new();
+        Nack { ranges }.encode(&mut nack);
+        if let Some(sealer) = &self.sealer {
+            let mut sealed = Vec::new();
+            sealer.seal(self.seq, &nack, &mut sealed);
+            self.seq += 1;
+            nack = sealed;
+        }
+        socket.send_to(&nack, self.to).await?;
+        Ok(())
+    }
+}
+
+/// Recovers lost datagrams with FEC, opens them if a key was given
+/// and splits bundles into their packets, skipping the datagrams that don't open
+fn decode(
+    fec: &mut FecDecoder,
+    opener: &mut Option<Opener>,
+    datagram: &[u8],
+    peer: SocketAddr,
+) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+    let datagrams = if fec::is_fec(datagram) {
+        fec.decode(datagram)?
+    } else {
+        vec![datagram.to_vec()]
+    };
+    let mut packets = Vec::new();
+    for mut datagram in datagrams {
+        let datagram = match opener {
+            Some(opener) => match opener.open(&mut datagram) {
+                Ok(datagram) => datagram,
+                Err(e) => {
+                    println!("Ignoring datagram from {peer}: {e}");
+                    continue;
+                }
+            },
+            None => &datagram,
+        };
+        if !bundle::is_bundle(datagram) {
+            packets.push(datagram.to_vec());
+            continue;
+        }
+        match bundle::decode(datagram) {
+            Ok(frames) => packets.extend(frames.map(<[u8]>::to_vec)),
+            Err(e) => println!("Ignoring bundle from {peer}: {e}"),
+        }
+    }
+    Ok(packets)
+}
+
+/// Packets must be exactly 1232 bytes of null bytes
+fn is_valid(packet: &[u8]) -> bool {
+    packet.len() == 1232 && packet.iter().all(|&b| b == 0)
 }
 
 #[tokio::main]
@@ -21,44 +161,96 @@ async fn main() -> Result<()> {
     let socket = UdpSocket::bind(&args.addr).await?;
     println!("Listening for packets...");
 
-    let mut buf = [0u8; 1232];
+    // large enough for jumbo bundles
+    let mut buf = vec![0u8; 65536];
     let mut packet_count = 0u64;
     let mut last_packet_time: Duration;
     let timeout_duration = Duration::from_secs(10);
 
-    let expected_packet = [0u8; 1232];
+    let mut opener = args.psk.as_ref().map(Opener::new);
+    let mut fec = FecDecoder::default();
+    let mut sequence = Sequence::default();
+    let mut nacks = match args.nack_to {
+        Some(to) => Some(NackSender {
+            to,
+            sealer: args.psk.as_ref().map(Sealer::new).transpose()?,
+            seq: 0,
+        }),
+        None => None,
+    };
 
     loop {
         let (len, peer) = socket.recv_from(&mut buf).await?;
+        if args.echo_heartbeats && buf[..len].starts_with(HEARTBEAT_MAGIC) {
+            socket.send_to(&buf[..len], peer).await?;
+            continue;
+        }
 
-        if len == 1232 && buf == expected_packet {
-            packet_count += 1;
+        let mut packets = match decode(&mut fec, &mut opener, &buf[..len], peer) {
+            Ok(packets) => packets,
+            Err(e) => {
+                println!("Ignoring packet from {peer}: {e}");
+                continue;
+            }
+        };
+        let missed = sequence.strip(&mut packets);
+        if let Some(nacks) = &mut nacks
+            && !missed.is_empty()
+        {
+            nacks.send(&socket, missed).await?;
+        }
+        for packet in packets.iter() {
+            if !is_valid(packet) {
+                println!(
+                    "Ignoring invalid packet from {} ({} bytes, expected 1232 null bytes)",
+                    peer,
+                    packet.len()
+                );
+            }
+        }
+        let valid = packets.iter().filter(|packet| is_valid(packet)).count() as u64;
+        if valid > 0 {
+            packet_count += valid;
             last_packet_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
             println!(
                 "First packet received from {peer}, at {} EPOCH_MS",
                 last_packet_time.as_millis()
             );
             break;
-        } else {
-            println!(
-                "Ignoring invalid packet from {} ({} bytes, expected 1232 null bytes)",
-                peer, len
-            );
         }
     }
 
     loop {
         match timeout(timeout_duration, socket.recv_from(&mut buf)).await {
             Ok(Ok((len, peer))) => {
-                // Validate packet: must be exactly 1232 bytes of null bytes
-                if len == 1232 && buf[..len].iter().all(|&b| b == 0) {
-                    packet_count += 1;
-                    last_packet_time = SystemTime::now().duration_since(UNIX_EPOCH)?;
-                } else {
-                    println!(
-                        "Ignoring invalid packet from {} ({} bytes, expected 1232 null bytes)",
-                        peer, len
-                    );
+                if args.echo_heartbeats && buf[..len].starts_with(HEARTBEAT_MAGIC) {
+                    socket.send_to(&buf[..len], peer).await?;
+                    continue;
+                }
+                let mut packets = match decode(&mut fec, &mut opener, &buf[..len], peer) {
+                    Ok(packets) => packets,
+                    Err(e) => {
+                        println!("Ignoring packet from {peer}: {e}");
+                        continue;
+                    }
+                };
+                let missed = sequence.strip(&mut packets);
+                if let Some(nacks) = &mut nacks
+                    && !missed.is_empty()
+                {
+                    nacks.send(&socket, missed).await?;
+                }
+                for packet in packets {
+                    if is_valid(&packet) {
+                        packet_count += 1;
+                        last_packet_time = SystemTime::now().duration_since(UNIX_EPOCH)?;
+                    } else {
+                        println!(
+                            "Ignoring invalid packet from {} ({} bytes, expected 1232 null bytes)",
+                            peer,
+                            packet.len()
+                        );
+                    }
                 }
             }
             Ok(Err(e)) => {
@@ -78,6 +270,16 @@ async fn main() -> Result<()> {
         last_packet_time.as_millis()
     );
     println!("Total packets received: {}", packet_count);
+    if fec.recovered > 0 {
+        println!("Datagrams recovered by FEC: {}", fec.recovered);
+    }
+    if sequence.retransmitted > 0 || !sequence.missing.is_empty() {
+        println!("Packets retransmitted: {}", sequence.retransmitted);
+        println!("Packets still missing: {}", sequence.missing.len());
+    }
+    if sequence.duplicates > 0 {
+        println!("Duplicate packets: {}", sequence.duplicates);
+    }
 
     Ok(())
 }
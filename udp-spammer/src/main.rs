@@ -1,24 +1,60 @@
 use std::{
+    io,
     net::SocketAddr,
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
     sync::Arc,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::anyhow;
 use clap::Parser;
-use tokio::{net::UdpSocket, task::JoinSet};
+use shredcaster_proto::{
+    archive::{Archive, ShredKey},
+    hash::SplitMix64,
+    pcap::PcapReader,
+};
+use tokio::{
+    net::UdpSocket,
+    task::JoinSet,
+    time::{Instant, sleep_until},
+};
 
 #[derive(Parser)]
 struct UdpSpammerArgs {
+    /// Where packets are sent, can be given several times
+    #[arg(short, long, required = true)]
+    target: Vec<SocketAddr>,
+    /// Packets per second, 5000 unless replaying, where it replaces the recorded timing
     #[arg(short, long)]
-    target: SocketAddr,
-    /// Packets per second
-    #[arg(short, long, default_value_t = 5000)]
-    pps: u32,
+    pps: Option<u32>,
+    /// Replays the shreds of a pcap or pcapng file, or of a shredcaster archive
+    /// directory, instead of sending zeroes
+    #[arg(long)]
+    replay: Option<PathBuf>,
+    /// Replays the recorded timing this many times faster
+    #[arg(long, default_value_t = 1.0)]
+    speed: f64,
+    /// Only replays shreds of this slot and later ones
+    #[arg(long)]
+    from_slot: Option<u64>,
+    /// Only replays shreds of this slot and earlier ones
+    #[arg(long)]
+    to_slot: Option<u64>,
+    /// Share of replayed packets dropped, between 0 and 1
+    #[arg(long, default_value_t = 0.0)]
+    loss: f64,
+    /// Share of replayed packets sent after the one following them, between 0 and 1
+    #[arg(long, default_value_t = 0.0)]
+    reorder: f64,
+    /// Makes loss and reordering reproducible
+    #[arg(long)]
+    seed: Option<u64>,
 }
 
 const SPAM_DURATION_SECS: u32 = 10; // 10 seconds
 const PACKET_BATCH_SIZE: u32 = 100; // Number of packets to send in one go
+const DEFAULT_PPS: u32 = 5000;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -26,10 +62,19 @@ async fn main() -> anyhow::Result<()> {
 
     let socket = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
 
+    match &args.replay {
+        Some(path) => replay(&args, &socket, path).await,
+        None => spam(&args, socket).await,
+    }
+}
+
+async fn spam(args: &UdpSpammerArgs, socket: Arc<UdpSocket>) -> anyhow::Result<()> {
+    let pps = args.pps.unwrap_or(DEFAULT_PPS);
     let packet = [0u8; 1232];
-    let total_packets = args.pps * SPAM_DURATION_SECS;
+    let total_packets = pps * SPAM_DURATION_SECS;
     let batch_cnt = total_packets
         .checked_div(PACKET_BATCH_SIZE)
+        .filter(|cnt| *cnt > 0)
         .ok_or_else(|| anyhow!("PPS must be at least {}", PACKET_BATCH_SIZE))?;
     let remaining = total_packets % PACKET_BATCH_SIZE;
 
@@ -47,16 +92,18 @@ async fn main() -> anyhow::Result<()> {
 
         let mut spawner = JoinSet::new();
         for _ in 0..packet_cnt {
-            let socket = socket.clone();
-            spawner.spawn(async move {
-                let _ = socket.send_to(&packet, args.target).await;
-            });
+            for target in args.target.iter().copied() {
+                let socket = socket.clone();
+                spawner.spawn(async move {
+                    let _ = socket.send_to(&packet, target).await;
+                });
+            }
         }
 
         spawner.join_all().await;
         println!(
             "Spawned {} packets at {} EPOCH MS",
-            args.pps,
+            pps,
             SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .unwrap()
@@ -71,3 +118,136 @@ async fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// A recorded packet and when it was captured, in unix nanoseconds
+type Recorded = (u64, Vec<u8>);
+
+async fn replay(args: &UdpSpammerArgs, socket: &UdpSocket, path: &Path) -> anyhow::Result<()> {
+    if args.speed.is_nan() || args.speed <= 0.0 {
+        return Err(anyhow!("speed must be positive"));
+    }
+    if args.pps == Some(0) {
+        return Err(anyhow!("PPS must be positive"));
+    }
+    for (name, share) in [("loss", args.loss), ("reorder", args.reorder)] {
+        if !(0.0..=1.0).contains(&share) {
+            return Err(anyhow!("{name} must be between 0 and 1"));
+        }
+    }
+    let slots = (
+        args.from_slot.map_or(Bound::Unbounded, Bound::Included),
+        args.to_slot.map_or(Bound::Unbounded, Bound::Included),
+    );
+
+    if path.is_dir() {
+        let archive = Archive::open(path)?;
+        println!(
+            "replaying {} archive segments from {}",
+            archive.segments.len(),
+            path.display()
+        );
+        let records = archive
+            .records(slots)
+            .map(|record| record.map(|record| (record.capture_ts_ns, record.shred)));
+        send_recorded(args, socket, records).await
+    } else {
+        let mut reader = PcapReader::open(path)?;
+        println!("replaying {}", path.display());
+        let filtered = slots != (Bound::Unbounded, Bound::Unbounded);
+        let datagrams = std::iter::from_fn(move || {
+            loop {
+                let datagram = match reader.next_datagram() {
+                    Ok(Some(datagram)) => datagram,
+                    Ok(None) => return None,
+                    Err(e) => return Some(Err(e)),
+                };
+                // without a slot range, whatever the capture holds is replayed
+                if filtered
+                    && !ShredKey::parse(datagram.payload)
+                        .is_some_and(|key| slots.contains(&key.slot))
+                {
+                    continue;
+                }
+                return Some(Ok((datagram.ts_ns, datagram.payload.to_vec())));
+            }
+        });
+        send_recorded(args, socket, datagrams).await
+    }
+}
+
+/// Sends `packets` to the targets, spaced as recorded or at the fixed PPS
+async fn send_recorded(
+    args: &UdpSpammerArgs,
+    socket: &UdpSocket,
+    packets: impl Iterator<Item = io::Result<Recorded>>,
+) -> anyhow::Result<()> {
+    let seed = args.seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64
+    });
+    if args.loss > 0.0 || args.reorder > 0.0 {
+        println!("injecting loss and reordering with seed {seed}");
+    }
+    let mut chance = Chance(SplitMix64(seed));
+    let start = Instant::now();
+    let mut first_ts_ns = None;
+    // a packet sent after the next one
+    let mut held: Option<Vec<u8>> = None;
+    let (mut sent, mut lost, mut reordered) = (0u64, 0u64, 0u64);
+
+    for (i, packet) in packets.enumerate() {
+        let (ts_ns, payload) = packet?;
+        let due = match args.pps {
+            Some(pps) => start + Duration::from_secs_f64(i as f64 / f64::from(pps)),
+            None => {
+                let first_ts_ns = *first_ts_ns.get_or_insert(ts_ns);
+                start + Duration::from_nanos(ts_ns.saturating_sub(first_ts_ns)).div_f64(args.speed)
+            }
+        };
+        if due > Instant::now() {
+            sleep_until(due).await;
+        }
+
+        if chance.hit(args.loss) {
+            lost += 1;
+            continue;
+        }
+        if held.is_none() && chance.hit(args.reorder) {
+            held = Some(payload);
+            reordered += 1;
+            continue;
+        }
+        for packet in std::iter::once(payload).chain(held.take()) {
+            for target in args.target.iter() {
+                let _ = socket.send_to(&packet, target).await;
+            }
+            sent += 1;
+        }
+    }
+    if let Some(packet) = held {
+        for target in args.target.iter() {
+            let _ = socket.send_to(&packet, target).await;
+        }
+        sent += 1;
+    }
+
+    println!(
+        "replayed {sent} packets in {:.3}s, dropped {lost}, reordered {reordered}",
+        start.elapsed().as_secs_f64()
+    );
+    Ok(())
+}
+
+/// Picks the packets to drop or reorder, the same ones for the same seed
+struct Chance(SplitMix64);
+
+impl Chance {
+    fn hit(&mut self, probability: f64) -> bool {
+        if probability <= 0.0 {
+            return false;
+        }
+        ((self.0.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}